@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::commit::Commit;
+use crate::config::{Role, ServerConfig, UserAccess};
+
+/// File in the server's data directory holding the issued tokens.
+pub const TOKENS_FILE: &str = "tokens.toml";
+
+/// Prefix of every issued token, so they are easy to spot in logs and
+/// config files.
+const TOKEN_PREFIX: &str = "hit_";
+
+/// The user a request was authenticated as, added to the request
+/// extensions by the server's auth middleware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthUser(pub String);
+
+/// Who may do what on the server, from its `default_role` and `users`
+/// settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessRules {
+    default_role: Role,
+    users: BTreeMap<String, UserAccess>,
+}
+
+impl From<&ServerConfig> for AccessRules {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            default_role: config.default_role,
+            users: config.users.clone(),
+        }
+    }
+}
+
+impl AccessRules {
+    pub fn permissions(&self, user: &str) -> Permissions {
+        match self.users.get(user) {
+            Some(access) => Permissions {
+                role: access.role.unwrap_or(self.default_role),
+                paths: access.paths.clone(),
+            },
+            None => Permissions {
+                role: self.default_role,
+                paths: Vec::new(),
+            },
+        }
+    }
+}
+
+/// What the user of a request may do, added to the request extensions by
+/// the server's auth middleware.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Permissions {
+    pub role: Role,
+    /// Path prefixes the user is limited to; empty for the whole repository.
+    pub paths: Vec<String>,
+}
+
+impl Permissions {
+    /// Everything is allowed, as when the server runs without
+    /// authentication.
+    pub fn unrestricted() -> Self {
+        Self {
+            role: Role::Admin,
+            paths: Vec::new(),
+        }
+    }
+
+    /// Whether the whole repository may be seen.
+    pub fn can_read_all(&self) -> bool {
+        self.role == Role::Admin || self.paths.is_empty()
+    }
+
+    /// Whether changes to `path` may be seen.
+    pub fn can_read(&self, path: &str) -> bool {
+        if self.can_read_all() {
+            return true;
+        }
+        let path = Path::new(path);
+        // `docs/../secret` starts with `docs` but is not inside it.
+        if path.components().any(|c| matches!(c, Component::ParentDir)) {
+            return false;
+        }
+        self.paths
+            .iter()
+            .any(|prefix| path.starts_with(prefix.trim_end_matches('/')))
+    }
+
+    /// Whether objects may be uploaded.
+    pub fn can_upload(&self) -> bool {
+        self.role >= Role::Writer
+    }
+
+    /// Whether `path` may be changed.
+    pub fn can_write(&self, path: &str) -> bool {
+        self.can_upload() && self.can_read(path)
+    }
+
+    /// `commit` with only the changes that may be seen, or `None` if there
+    /// are none.
+    pub fn visible(&self, mut commit: Commit) -> Option<Commit> {
+        commit.changes.retain(|c| self.can_read(&c.path));
+        (!commit.changes.is_empty()).then_some(commit)
+    }
+}
+
+/// An issued token. Only the SHA-256 of the token is kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenEntry {
+    pub user: String,
+    pub hash: String,
+    pub created: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TokenFile {
+    token: Vec<TokenEntry>,
+}
+
+/// Bearer tokens accepted by `hit serve`, stored hashed in
+/// [`TOKENS_FILE`].
+///
+/// Tokens are issued and revoked with `hit serve token`, usually while the
+/// server is running; the server rereads the file whenever it changes.
+#[derive(Debug)]
+pub struct TokenStore {
+    path: PathBuf,
+    cache: RwLock<Cached>,
+}
+
+#[derive(Debug, Default)]
+struct Cached {
+    /// Modification time and length of the file when it was read.
+    stamp: Option<(SystemTime, u64)>,
+    tokens: Vec<TokenEntry>,
+}
+
+impl TokenStore {
+    /// The tokens of the server with data directory `data_dir`.
+    pub fn in_dir(data_dir: impl AsRef<Path>) -> Self {
+        Self {
+            path: data_dir.as_ref().join(TOKENS_FILE),
+            cache: RwLock::new(Cached::default()),
+        }
+    }
+
+    /// Every issued token, oldest first.
+    pub fn list(&self) -> io::Result<Vec<TokenEntry>> {
+        Ok(self.read()?.token)
+    }
+
+    /// Issue a new token for `user` and return it. This is the only time the
+    /// token itself is available.
+    pub fn issue(&self, user: &str) -> io::Result<String> {
+        if user.is_empty() || user.chars().any(|c| c.is_control() || c.is_whitespace()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid user name {:?}", user),
+            ));
+        }
+        let mut bytes = [0u8; 32];
+        getrandom::getrandom(&mut bytes).map_err(|e| io::Error::other(e.to_string()))?;
+        let token = format!("{}{}", TOKEN_PREFIX, to_hex(&bytes));
+        let mut file = self.read()?;
+        file.token.push(TokenEntry {
+            user: user.into(),
+            hash: hash_token(&token),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(io::Error::other)?
+                .as_secs(),
+        });
+        self.write(&file)?;
+        Ok(token)
+    }
+
+    /// Revoke every token of `user`. Returns how many were revoked.
+    pub fn revoke(&self, user: &str) -> io::Result<usize> {
+        let mut file = self.read()?;
+        let before = file.token.len();
+        file.token.retain(|t| t.user != user);
+        let revoked = before - file.token.len();
+        if revoked > 0 {
+            self.write(&file)?;
+        }
+        Ok(revoked)
+    }
+
+    /// The user `token` was issued to, or `None` if it is not valid.
+    pub fn authenticate(&self, token: &str) -> io::Result<Option<String>> {
+        let stamp = match fs::metadata(&self.path) {
+            Ok(meta) => Some((meta.modified()?, meta.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let hash = hash_token(token);
+        {
+            let cache = self.cache.read().map_err(|_| poisoned())?;
+            if cache.stamp == stamp {
+                return Ok(find_user(&cache.tokens, &hash));
+            }
+        }
+        let tokens = self.read()?.token;
+        let user = find_user(&tokens, &hash);
+        *self.cache.write().map_err(|_| poisoned())? = Cached { stamp, tokens };
+        Ok(user)
+    }
+
+    fn read(&self) -> io::Result<TokenFile> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(TokenFile::default()),
+            Err(e) => return Err(e),
+        };
+        toml::from_str(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid token file {}: {}", self.path.display(), e),
+            )
+        })
+    }
+
+    fn write(&self, file: &TokenFile) -> io::Result<()> {
+        let text = toml::to_string(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = self.path.with_extension("toml.tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(&mut options.open(&tmp)?, text.as_bytes())?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+fn find_user(tokens: &[TokenEntry], hash: &str) -> Option<String> {
+    tokens.iter().find(|t| t.hash == hash).map(|t| t.user.clone())
+}
+
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn poisoned() -> io::Error {
+    io::Error::other("token cache lock poisoned")
+}
+
+/// Default headers for requests to a server, carrying `token` if there is
+/// one.
+pub fn auth_headers(token: Option<&str>) -> io::Result<reqwest::header::HeaderMap> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(token) = token {
+        let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        value.set_sensitive(true);
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_roles_and_paths() {
+        let mut config = ServerConfig {
+            default_role: Role::Reader,
+            ..ServerConfig::default()
+        };
+        config.users.insert(
+            "ada".into(),
+            UserAccess {
+                role: Some(Role::Writer),
+                paths: vec!["docs/".into()],
+            },
+        );
+        config.users.insert(
+            "root".into(),
+            UserAccess {
+                role: Some(Role::Admin),
+                paths: vec!["docs".into()],
+            },
+        );
+        let rules = AccessRules::from(&config);
+
+        let ada = rules.permissions("ada");
+        assert!(ada.can_upload());
+        assert!(ada.can_write("docs/guide.md"));
+        assert!(!ada.can_write("docs2/guide.md"));
+        assert!(!ada.can_write("docs/../src/main.rs"));
+        assert!(!ada.can_read("src/main.rs"));
+
+        let eve = rules.permissions("eve");
+        assert!(eve.can_read("src/main.rs"));
+        assert!(!eve.can_upload());
+        assert!(!eve.can_write("src/main.rs"));
+
+        assert!(rules.permissions("root").can_write("src/main.rs"));
+        assert!(Permissions::unrestricted().can_write("anything"));
+    }
+
+    #[test]
+    fn issues_and_revokes_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = TokenStore::in_dir(dir.path());
+        assert_eq!(store.authenticate("hit_nope").unwrap(), None);
+
+        let ada = store.issue("ada").unwrap();
+        let bob = store.issue("bob").unwrap();
+        assert!(ada.starts_with(TOKEN_PREFIX));
+        assert_ne!(ada, bob);
+        assert_eq!(store.authenticate(&ada).unwrap().as_deref(), Some("ada"));
+        assert_eq!(store.authenticate(&bob).unwrap().as_deref(), Some("bob"));
+
+        let text = fs::read_to_string(dir.path().join(TOKENS_FILE)).unwrap();
+        assert!(!text.contains(&ada));
+        assert_eq!(store.list().unwrap().len(), 2);
+        assert!(store.issue("two words").is_err());
+
+        // Another store, like `hit serve token` next to a running server.
+        assert_eq!(TokenStore::in_dir(dir.path()).revoke("ada").unwrap(), 1);
+        assert_eq!(store.authenticate(&ada).unwrap(), None);
+        assert_eq!(store.authenticate(&bob).unwrap().as_deref(), Some("bob"));
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{self, Read};
+
+/// Chunks are never cut before this many bytes.
+pub const MIN_CHUNK: usize = 256 * 1024;
+/// Chunks are always cut after this many bytes.
+pub const MAX_CHUNK: usize = 4 * 1024 * 1024;
+/// A boundary is declared when the low 20 bits of the rolling hash are zero,
+/// giving an average of about 1 MiB past [`MIN_CHUNK`].
+const BOUNDARY_MASK: u64 = (1 << 20) - 1;
+
+/// Per-byte values for the gear rolling hash, generated with splitmix64 so
+/// chunk boundaries are identical on every client.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Splits a byte stream into content-defined chunks.
+///
+/// Boundaries depend only on nearby content, so an edit near the start of a
+/// large file leaves the chunks after it unchanged and they deduplicate
+/// against the previous version. At most [`MAX_CHUNK`] bytes are buffered.
+pub struct Chunker<R> {
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::with_capacity(MAX_CHUNK),
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        while !self.eof && self.buf.len() < MAX_CHUNK {
+            let start = self.buf.len();
+            self.buf.resize(MAX_CHUNK, 0);
+            match self.reader.read(&mut self.buf[start..]) {
+                Ok(0) => {
+                    self.buf.truncate(start);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(start + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.buf.truncate(start),
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            return Some(Err(e));
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        let cut = find_boundary(&self.buf);
+        let rest = self.buf.split_off(cut);
+        Some(Ok(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+/// Length of the first chunk in `data`.
+fn find_boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK);
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+        Chunker::new(data).collect::<io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn small_input_is_a_single_chunk() {
+        assert_eq!(chunks(b"hello"), vec![b"hello".to_vec()]);
+        assert!(chunks(b"").is_empty());
+    }
+
+    #[test]
+    fn chunks_respect_bounds_and_reassemble() {
+        let data = pseudo_random(12 * 1024 * 1024, 1);
+        let parts = chunks(&data);
+        assert!(parts.len() > 1);
+        for part in &parts[..parts.len() - 1] {
+            assert!(part.len() >= MIN_CHUNK && part.len() <= MAX_CHUNK);
+        }
+        assert_eq!(parts.concat(), data);
+    }
+
+    #[test]
+    fn prefix_edit_preserves_later_chunks() {
+        let data = pseudo_random(12 * 1024 * 1024, 2);
+        let mut edited = b"inserted".to_vec();
+        edited.extend_from_slice(&data);
+        let before = chunks(&data);
+        let after = chunks(&edited);
+        let shared = after.iter().filter(|c| before.contains(c)).count();
+        assert!(shared >= before.len() - 2, "only {} of {} chunks shared", shared, before.len());
+    }
+}
@@ -1,10 +1,17 @@
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::io;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::commit_log::CommitLog;
+use arc_swap::ArcSwap;
+use futures_util::{Stream, StreamExt, stream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::commit_log::{COMMIT_LOG_FILE, CommitLog};
+use crate::commit_sqlite::{COMMIT_DB_FILE, SqliteBackend};
+use crate::config::CommitBackendKind;
 
 use crate::server::Change;
 
@@ -14,20 +21,211 @@ pub struct Commit {
     pub id: u64,
     pub changes: Vec<Change>,
     pub timestamp: u64,
+    /// User who made the commit, if known.
+    #[serde(default)]
+    pub author: Option<String>,
+}
+
+/// Filters for [`CommitStore::query`]. Unset fields match every commit.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct CommitQuery {
+    /// Only commits with ids greater than this.
+    #[serde(default)]
+    pub after: u64,
+    /// Only commits changing this path.
+    pub path: Option<String>,
+    pub author: Option<String>,
+    /// Only commits made at or after this time, in seconds since the epoch.
+    pub since: Option<u64>,
+    /// Only commits made before this time.
+    pub until: Option<u64>,
+    /// Return at most this many commits.
+    pub limit: Option<usize>,
+}
+
+impl CommitQuery {
+    /// Whether `commit` passes every filter except `limit`.
+    pub fn matches(&self, commit: &Commit) -> bool {
+        commit.id > self.after
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|p| commit.changes.iter().any(|c| &c.path == p))
+            && self
+                .author
+                .as_ref()
+                .is_none_or(|a| commit.author.as_ref() == Some(a))
+            && self.since.is_none_or(|t| commit.timestamp >= t)
+            && self.until.is_none_or(|t| commit.timestamp < t)
+    }
+}
+
+/// Storage for the commit history behind a [`CommitStore`].
+///
+/// The store hands the backend to a dedicated writer thread, which is the
+/// only caller of these methods. Reads go through [`CommitBackend::reader`].
+pub trait CommitBackend: Send {
+    /// Persist a batch of commits with consecutive ids following
+    /// [`last_id`](Self::last_id), flushing once for the whole batch. On
+    /// error none of the commits may be visible to readers.
+    fn append(&mut self, commits: &[Commit]) -> io::Result<()>;
+
+    /// Id of the newest commit, 0 when there are none.
+    fn last_id(&self) -> u64;
+
+    fn latest(&self) -> Option<Commit>;
+
+    /// A handle for reading the persisted history concurrently with appends.
+    fn reader(&self) -> Arc<dyn CommitReader>;
+}
+
+/// Read access to the history of a [`CommitBackend`]. Readers never wait
+/// for a batch that is being written.
+pub trait CommitReader: Send + Sync {
+    fn get(&self, id: u64) -> io::Result<Option<Commit>>;
+
+    /// Iterate over the commits with ids greater than `after`, in order.
+    fn iter_after(&self, after: u64) -> io::Result<CommitIterator>;
+
+    /// Commits matching `query`, in order. The default scans the history
+    /// from `query.after`; backends with indexes should override it.
+    fn query(&self, query: &CommitQuery) -> io::Result<Vec<Commit>> {
+        let mut found = Vec::new();
+        if query.limit == Some(0) {
+            return Ok(found);
+        }
+        for commit in self.iter_after(query.after)? {
+            let commit = commit?;
+            if query.matches(&commit) {
+                found.push(commit);
+                if query.limit == Some(found.len()) {
+                    break;
+                }
+            }
+        }
+        Ok(found)
+    }
 }
 
+pub type CommitIterator = Box<dyn Iterator<Item = io::Result<Commit>> + Send>;
+
+/// Commits [`CommitStore::stream_after`] reads at a time.
+pub const STREAM_BATCH: usize = 64;
+
+/// Keeps the history in memory only; used by tests and throwaway servers.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    commits: Arc<RwLock<Vec<Commit>>>,
+}
+
+impl CommitBackend for MemoryBackend {
+    fn append(&mut self, commits: &[Commit]) -> io::Result<()> {
+        write_lock(&self.commits)?.extend_from_slice(commits);
+        Ok(())
+    }
+
+    fn last_id(&self) -> u64 {
+        self.latest().map(|c| c.id).unwrap_or(0)
+    }
+
+    fn latest(&self) -> Option<Commit> {
+        read_lock(&self.commits).ok()?.last().cloned()
+    }
+
+    fn reader(&self) -> Arc<dyn CommitReader> {
+        Arc::new(MemoryReader(self.commits.clone()))
+    }
+}
+
+/// The lock is only held to copy commits in or out, never during I/O.
+struct MemoryReader(Arc<RwLock<Vec<Commit>>>);
+
+impl CommitReader for MemoryReader {
+    fn get(&self, id: u64) -> io::Result<Option<Commit>> {
+        let commits = read_lock(&self.0)?;
+        Ok(commits
+            .binary_search_by_key(&id, |c| c.id)
+            .ok()
+            .map(|i| commits[i].clone()))
+    }
+
+    fn iter_after(&self, after: u64) -> io::Result<CommitIterator> {
+        let commits = read_lock(&self.0)?;
+        let start = commits.partition_point(|c| c.id <= after);
+        let tail: Vec<Commit> = commits[start..].to_vec();
+        Ok(Box::new(tail.into_iter().map(Ok)))
+    }
+}
+
+fn read_lock<T>(lock: &RwLock<T>) -> io::Result<RwLockReadGuard<'_, T>> {
+    lock.read().map_err(|_| io::Error::other("Lock poisoned"))
+}
+
+fn write_lock<T>(lock: &RwLock<T>) -> io::Result<RwLockWriteGuard<'_, T>> {
+    lock.write().map_err(|_| io::Error::other("Lock poisoned"))
+}
+
+/// Rejects every append; for testing how write failures are reported.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct FailingBackend(MemoryBackend);
+
+#[cfg(test)]
+impl CommitBackend for FailingBackend {
+    fn append(&mut self, _: &[Commit]) -> io::Result<()> {
+        Err(io::Error::other("disk full"))
+    }
+
+    fn last_id(&self) -> u64 {
+        0
+    }
+
+    fn latest(&self) -> Option<Commit> {
+        None
+    }
+
+    fn reader(&self) -> Arc<dyn CommitReader> {
+        self.0.reader()
+    }
+}
+
+/// Most commits written with a single flush.
+const MAX_BATCH: usize = 256;
+
+/// Pending appends beyond this make `add_commit` wait for the writer.
+const WRITE_QUEUE: usize = 1024;
+
+/// Newest state of the history. The writer replaces it as a whole after
+/// every batch, so reading it never blocks.
+#[derive(Debug, Default)]
+struct Snapshot {
+    latest: Option<Commit>,
+}
+
+struct WriteRequest {
+    change: Change,
+    author: Option<String>,
+    timestamp: u64,
+    reply: oneshot::Sender<io::Result<Commit>>,
+}
+
+/// The commit history of a server.
+///
+/// Commits are appended by a dedicated writer thread. Changes that arrive
+/// while it is flushing are written together in the next batch, so a burst
+/// of changes costs a few flushes rather than one each. Reads use the
+/// backend's [`CommitReader`] and a snapshot of the newest commit and never
+/// wait for the writer.
 #[derive(Clone)]
 pub struct CommitStore {
-    pub commits: Arc<Mutex<Vec<Commit>>>,
-    log: Option<Arc<Mutex<CommitLog>>>,
+    writer: mpsc::Sender<WriteRequest>,
+    snapshot: Arc<ArcSwap<Snapshot>>,
+    reader: Arc<dyn CommitReader>,
 }
 
 impl Default for CommitStore {
     fn default() -> Self {
-        Self {
-            commits: Arc::new(Mutex::new(Vec::new())),
-            log: None,
-        }
+        Self::with_backend(MemoryBackend::default())
     }
 }
 
@@ -36,139 +234,413 @@ impl CommitStore {
         Self::default()
     }
 
+    /// Start a writer thread for `backend`. It stops once every clone of
+    /// the store has been dropped.
+    pub fn with_backend(backend: impl CommitBackend + 'static) -> Self {
+        let snapshot = Arc::new(ArcSwap::from_pointee(Snapshot {
+            latest: backend.latest(),
+        }));
+        let reader = backend.reader();
+        let (writer, requests) = mpsc::channel(WRITE_QUEUE);
+        let published = snapshot.clone();
+        std::thread::Builder::new()
+            .name("commit-writer".into())
+            .spawn(move || run_writer(backend, requests, published))
+            .expect("failed to spawn commit writer");
+        Self {
+            writer,
+            snapshot,
+            reader,
+        }
+    }
+
     /// Create a commit store backed by a commit log at the given path.
+    ///
+    /// Only the log's newest checkpoint and the commits after it are read;
+    /// older history stays on disk until it is requested.
     pub fn with_log(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
-        let commits = CommitLog::load(&path).map_err(|e| {
-            tracing::error!("failed to load commit log: {}", e);
+        let log = CommitLog::open(&path).map_err(|e| {
+            tracing::error!("failed to open commit log: {}", e);
             e
         })?;
-        tracing::info!(path = %path.as_ref().display(), count = commits.len(), "loaded commits from log");
-        let log = CommitLog::open(path).map_err(|e| {
-            tracing::error!("failed to open commit log: {}", e);
+        tracing::info!(path = %path.as_ref().display(), count = log.state().count, "opened commit log");
+        Ok(Self::with_backend(log))
+    }
+
+    /// Create a commit store backed by an SQLite database at the given path.
+    pub fn with_sqlite(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let db = SqliteBackend::open(&path).map_err(|e| {
+            tracing::error!("failed to open commit database: {}", e);
             e
         })?;
-        Ok(Self {
-            commits: Arc::new(Mutex::new(commits)),
-            log: Some(Arc::new(Mutex::new(log))),
-        })
+        tracing::info!(path = %path.as_ref().display(), last_id = db.last_id(), "opened commit database");
+        Ok(Self::with_backend(db))
+    }
+
+    /// Open the backend selected in the server configuration, with its
+    /// files under `dir`.
+    pub fn open(kind: CommitBackendKind, dir: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let dir = dir.as_ref();
+        match kind {
+            CommitBackendKind::Memory => Ok(Self::default()),
+            CommitBackendKind::Log => Self::with_log(dir.join(COMMIT_LOG_FILE)),
+            CommitBackendKind::Sqlite => Self::with_sqlite(dir.join(COMMIT_DB_FILE)),
+        }
     }
 
-    pub fn add_commit(&self, change: Change) -> Result<Commit, Box<dyn Error>> {
-        let mut commits = self.commits.lock().map_err(|_| "Lock poisoned")?;
-        let id = commits.last().map(|c| c.id + 1).unwrap_or(1);
+    /// Record `change` as a new commit once it has been persisted.
+    pub async fn add_commit(&self, change: Change) -> Result<Commit, Box<dyn Error>> {
+        self.add_commit_by(change, None).await
+    }
+
+    /// Like [`add_commit`](Self::add_commit), recording `author` as the user
+    /// who made the change.
+    pub async fn add_commit_by(
+        &self,
+        change: Change,
+        author: Option<String>,
+    ) -> Result<Commit, Box<dyn Error>> {
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-        let commit = Commit {
-            id,
-            changes: vec![change],
+        let (reply, committed) = oneshot::channel();
+        let request = WriteRequest {
+            change,
+            author,
             timestamp,
+            reply,
         };
-        if let Some(log) = &self.log {
-            let mut log = log.lock().map_err(|_| "Lock poisoned")?;
-            if let Err(e) = log.append(&commit) {
-                tracing::error!("failed to append commit to log: {}", e);
-                return Err(Box::new(e));
-            }
+        if self.writer.send(request).await.is_err() {
+            return Err("commit writer has stopped".into());
+        }
+        match committed.await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err("commit writer has stopped".into()),
         }
-        commits.push(commit.clone());
-        Ok(commit)
     }
 
     pub fn all(&self) -> Result<Vec<Commit>, Box<dyn Error>> {
-        Ok(self.commits.lock().map_err(|_| "Lock poisoned")?.clone())
+        Ok(self.commits_after(0)?.collect::<io::Result<_>>()?)
+    }
+
+    /// Iterate over the commits with ids greater than `after`.
+    ///
+    /// Disk-backed stores read the commits lazily; the in-memory store
+    /// copies only the commits after `after`.
+    pub fn commits_after(&self, after: u64) -> Result<CommitIterator, Box<dyn Error>> {
+        Ok(self.reader.iter_after(after)?)
+    }
+
+    /// Commits matching `query`, in order.
+    pub fn query(&self, query: &CommitQuery) -> Result<Vec<Commit>, Box<dyn Error>> {
+        Ok(self.reader.query(query)?)
+    }
+
+    /// Stream the commits with ids greater than `after`.
+    ///
+    /// Commits are read [`STREAM_BATCH`] at a time on a blocking thread, each
+    /// batch only once the previous one has been consumed, so a slow
+    /// consumer holds no thread while it is not polling. The stream ends
+    /// after the first error. Must be polled within a Tokio runtime.
+    pub fn stream_after(&self, after: u64) -> impl Stream<Item = io::Result<Commit>> + Send + 'static {
+        let reader = self.reader.clone();
+        let batches = stream::unfold(Some(after), move |after| {
+            let reader = reader.clone();
+            async move {
+                let after = after?;
+                let batch = tokio::task::spawn_blocking(move || {
+                    reader.iter_after(after)?.take(STREAM_BATCH).collect::<io::Result<Vec<_>>>()
+                })
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+                match batch {
+                    Ok(batch) => {
+                        // A short batch reached the end of the history
+                        let next = batch.last().filter(|_| batch.len() == STREAM_BATCH).map(|c| c.id);
+                        (!batch.is_empty()).then(|| (batch.into_iter().map(Ok).collect(), next))
+                    }
+                    Err(e) => Some((vec![Err(e)], None)),
+                }
+            }
+        });
+        batches.flat_map(stream::iter)
+    }
+
+    /// Look up a single commit by id.
+    pub fn get(&self, id: u64) -> Result<Option<Commit>, Box<dyn Error>> {
+        Ok(self.reader.get(id)?)
     }
 
     pub fn latest(&self) -> Result<Option<Commit>, Box<dyn Error>> {
-        Ok(self
-            .commits
-            .lock()
-            .map_err(|_| "Lock poisoned")?
-            .last()
-            .cloned())
+        Ok(self.snapshot.load().latest.clone())
+    }
+}
+
+/// Every commit the backend `kind` keeps under `dir`, in order, for offline
+/// tools such as `hit gc`.
+///
+/// The history is only read, never opened for appending, so this is safe
+/// while `hit serve` is running. The memory backend keeps nothing on disk
+/// and is refused.
+pub fn read_history(kind: CommitBackendKind, dir: impl AsRef<Path>) -> io::Result<Vec<Commit>> {
+    let dir = dir.as_ref();
+    match kind {
+        CommitBackendKind::Memory => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the memory commit backend keeps no history on disk",
+        )),
+        CommitBackendKind::Log => CommitLog::load(dir.join(COMMIT_LOG_FILE)),
+        CommitBackendKind::Sqlite => {
+            let path = dir.join(COMMIT_DB_FILE);
+            if !path.exists() {
+                return Ok(Vec::new());
+            }
+            crate::commit_sqlite::reader(path).iter_after(0)?.collect()
+        }
+    }
+}
+
+/// Body of the writer thread: take every queued request, append them as
+/// one batch and answer each with its commit.
+fn run_writer(
+    mut backend: impl CommitBackend,
+    mut requests: mpsc::Receiver<WriteRequest>,
+    snapshot: Arc<ArcSwap<Snapshot>>,
+) {
+    while let Some(first) = requests.blocking_recv() {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match requests.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
+        }
+        let first_id = backend.last_id() + 1;
+        let (commits, replies): (Vec<Commit>, Vec<_>) = batch
+            .into_iter()
+            .zip(first_id..)
+            .map(|(request, id)| {
+                let commit = Commit {
+                    id,
+                    changes: vec![request.change],
+                    timestamp: request.timestamp,
+                    author: request.author,
+                };
+                (commit, request.reply)
+            })
+            .unzip();
+        match backend.append(&commits) {
+            Ok(()) => {
+                tracing::debug!(first_id, count = commits.len(), "committed batch");
+                snapshot.store(Arc::new(Snapshot {
+                    latest: commits.last().cloned(),
+                }));
+                for (commit, reply) in commits.into_iter().zip(replies) {
+                    let _ = reply.send(Ok(commit));
+                }
+            }
+            Err(e) => {
+                tracing::error!(count = commits.len(), "failed to persist commits: {}", e);
+                for reply in replies {
+                    let _ = reply.send(Err(io::Error::new(e.kind(), e.to_string())));
+                }
+            }
+        }
     }
+    tracing::debug!("commit writer stopped");
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::server::Change;
+    use crate::object::FileMode;
+    use crate::object_id::ObjectId;
+    use crate::commit_sqlite::COMMIT_DB_FILE;
 
-    #[test]
-    fn error_on_poisoned_lock() {
-        let store = CommitStore::default();
-        // Poison the mutex
-        {
-            let store2 = store.clone();
-            std::thread::spawn(move || {
-                let _guard = store2.commits.lock().unwrap();
-                panic!("boom");
-            })
-            .join()
-            .ok();
-        }
-        let change = Change {
-            hash: "h".into(),
+    fn change(n: u8) -> Change {
+        Change {
+            hash: ObjectId::from([n; 32]),
             path: "p".into(),
             timestamp: 0,
+            client: None,
+            mode: FileMode::Regular,
+        }
+    }
+
+    #[tokio::test]
+    async fn error_when_backend_fails() {
+        let store = CommitStore::with_backend(FailingBackend::default());
+        let err = store.add_commit(change(1)).await.unwrap_err();
+        assert!(err.to_string().contains("disk full"));
+        assert_eq!(store.latest().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn streams_commits_in_batches() {
+        let store = CommitStore::default();
+        let total = STREAM_BATCH * 2 + 3;
+        for n in 0..total {
+            store.add_commit(change(n as u8)).await.unwrap();
+        }
+        let ids = |after: u64| {
+            store.stream_after(after).map(|c| c.unwrap().id).collect::<Vec<_>>()
         };
-        let res = store.add_commit(change);
-        assert!(res.is_err());
-    }
-
-    #[test]
-    fn no_memory_update_on_log_failure() {
-        use std::fs;
-        
-        let mut store = CommitStore::default();
-        
-        // Create a temporary file that will cause write failures
-        let temp_dir = std::env::temp_dir();
-        let log_path = temp_dir.join("readonly_commit_test.log");
-        
-        // Create the file first
-        fs::write(&log_path, b"").unwrap();
-        
-        // Make it read-only
-        let mut perms = fs::metadata(&log_path).unwrap().permissions();
-        perms.set_readonly(true);
-        fs::set_permissions(&log_path, perms).unwrap();
-        
-        // The CommitLog::open should succeed (it opens for append)
-        // but the actual write will fail due to read-only permissions
-        if let Ok(log) = CommitLog::open(&log_path) {
-            store.log = Some(Arc::new(Mutex::new(log)));
-            
-            let change = Change {
-                hash: "h".into(),
-                path: "p".into(),
-                timestamp: 0,
-            };
-            let res = store.add_commit(change);
-            assert!(res.is_err());
-            assert_eq!(store.commits.lock().unwrap().len(), 0);
+        assert_eq!(ids(0).await, (1..=total as u64).collect::<Vec<_>>());
+        assert_eq!(ids(STREAM_BATCH as u64).await, (STREAM_BATCH as u64 + 1..=total as u64).collect::<Vec<_>>());
+        assert!(ids(total as u64).await.is_empty());
+    }
+
+    /// Counts appends and makes each one slow, so requests pile up.
+    struct SlowBackend {
+        inner: MemoryBackend,
+        appends: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl CommitBackend for SlowBackend {
+        fn append(&mut self, commits: &[Commit]) -> io::Result<()> {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            self.appends.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.inner.append(commits)
+        }
+
+        fn last_id(&self) -> u64 {
+            self.inner.last_id()
+        }
+
+        fn latest(&self) -> Option<Commit> {
+            self.inner.latest()
+        }
+
+        fn reader(&self) -> Arc<dyn CommitReader> {
+            self.inner.reader()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batches_concurrent_appends() {
+        let appends = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let store = CommitStore::with_backend(SlowBackend {
+            inner: MemoryBackend::default(),
+            appends: appends.clone(),
+        });
+        let tasks: Vec<_> = (0..50)
+            .map(|n| {
+                let store = store.clone();
+                tokio::spawn(async move { store.add_commit(change(n)).await.unwrap() })
+            })
+            .collect();
+        // Reads are answered while the writer is busy.
+        let started = std::time::Instant::now();
+        store.latest().unwrap();
+        store.all().unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_millis(50));
+
+        let mut ids = Vec::new();
+        for task in tasks {
+            ids.push(task.await.unwrap().id);
+        }
+        ids.sort();
+        assert_eq!(ids, (1..=50).collect::<Vec<_>>());
+        assert!(appends.load(std::sync::atomic::Ordering::SeqCst) < 50);
+        assert_eq!(store.latest().unwrap().map(|c| c.id), Some(50));
+        assert_eq!(store.all().unwrap().len(), 50);
+    }
+
+    /// Appends to memory until `failing` is set, then rejects appends.
+    struct FlakyBackend {
+        inner: MemoryBackend,
+        failing: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl CommitBackend for FlakyBackend {
+        fn append(&mut self, commits: &[Commit]) -> io::Result<()> {
+            if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(io::Error::other("disk full"));
+            }
+            self.inner.append(commits)
         }
-        
-        // Clean up - restore write permissions and delete
-        let mut perms = fs::metadata(&log_path).unwrap().permissions();
-        perms.set_readonly(false);
-        let _ = fs::set_permissions(&log_path, perms);
-        let _ = fs::remove_file(&log_path);
+
+        fn last_id(&self) -> u64 {
+            self.inner.last_id()
+        }
+
+        fn latest(&self) -> Option<Commit> {
+            self.inner.latest()
+        }
+
+        fn reader(&self) -> Arc<dyn CommitReader> {
+            self.inner.reader()
+        }
+    }
+
+    #[tokio::test]
+    async fn no_memory_update_on_log_failure() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let store = CommitStore::with_backend(FlakyBackend {
+            inner: MemoryBackend::default(),
+            failing: failing.clone(),
+        });
+        let first = store.add_commit(change(1)).await.unwrap();
+
+        failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(store.add_commit(change(2)).await.is_err());
+        assert_eq!(store.latest().unwrap(), Some(first.clone()));
+        assert_eq!(store.all().unwrap(), vec![first]);
+        assert_eq!(store.get(2).unwrap(), None);
+
+        // The failed commit's id is not used up
+        failing.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(store.add_commit(change(3)).await.unwrap().id, 2);
     }
 
-    #[test]
-    fn loads_commits_from_existing_log() {
+    #[tokio::test]
+    async fn loads_commits_from_existing_log() {
         fn path() -> &'static str { "store_test.log" }
         let _ = std::fs::remove_file(path());
+        let _ = std::fs::remove_file(crate::commit_log::index_path(Path::new(path())));
         {
             let mut log = CommitLog::open(path()).unwrap();
             let commit = Commit {
                 id: 1,
-                changes: vec![Change { hash: "h".into(), path: "p".into(), timestamp: 1 }],
+                changes: vec![Change { hash: ObjectId::from([1; 32]), path: "p".into(), timestamp: 1, client: None, mode: FileMode::Regular }],
                 timestamp: 1,
+                author: None,
             };
             log.append(&commit).unwrap();
         }
         let store = CommitStore::with_log(path()).unwrap();
         assert_eq!(store.all().unwrap().len(), 1);
+        assert_eq!(store.latest().unwrap().map(|c| c.id), Some(1));
+        assert_eq!(store.get(1).unwrap().map(|c| c.id), Some(1));
+        assert_eq!(store.add_commit(store.get(1).unwrap().unwrap().changes[0].clone()).await.unwrap().id, 2);
+        assert_eq!(store.get(2).unwrap().map(|c| c.id), Some(2));
         std::fs::remove_file(path()).ok();
+        std::fs::remove_file(crate::commit_log::index_path(Path::new(path()))).ok();
+    }
+
+    #[tokio::test]
+    async fn opens_the_configured_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let change = Change {
+            hash: ObjectId::from([1; 32]),
+            path: "p".into(),
+            timestamp: 0,
+            client: None,
+            mode: FileMode::Regular,
+        };
+        {
+            let store = CommitStore::open(CommitBackendKind::Sqlite, dir.path()).unwrap();
+            store.add_commit(change.clone()).await.unwrap();
+            store.add_commit(change.clone()).await.unwrap();
+        }
+        assert!(dir.path().join(COMMIT_DB_FILE).exists());
+        let store = CommitStore::open(CommitBackendKind::Sqlite, dir.path()).unwrap();
+        assert_eq!(store.latest().unwrap().map(|c| c.id), Some(2));
+        assert_eq!(store.add_commit(change).await.unwrap().id, 3);
+        let query = CommitQuery {
+            path: Some("p".into()),
+            after: 1,
+            ..Default::default()
+        };
+        assert_eq!(store.query(&query).unwrap().len(), 2);
     }
 }
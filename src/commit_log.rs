@@ -1,130 +1,1084 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-use crate::commit::Commit;
+use serde::{Deserialize, Serialize};
+
+use crate::commit::{Commit, CommitBackend, CommitIterator, CommitReader};
+use crate::object::FileMode;
+use crate::object_id::ObjectId;
+use crate::server::Change;
+
+/// Default location of the commit log, relative to the repository root.
+pub const COMMIT_LOG_PATH: &str = ".hit/commits.log";
+/// File name of the commit log inside the server's data directory.
+pub const COMMIT_LOG_FILE: &str = "commits.log";
+
+/// Current on-disk format version, stored after [`MAGIC`] at the start of the
+/// log. Logs in older formats are upgraded when opened.
+///
+/// * 0: no header; records are `u32` length and zstd payload.
+/// * 1: header; records carry a kind byte and commit id before the payload.
+/// * 2: every record starts with [`RECORD_MAGIC`] and a CRC32 of its contents.
+/// * 3: payloads are JSON instead of bincode, so fields can be added to
+///   commits without another format change.
+pub const LOG_VERSION: u16 = 3;
+
+/// A checkpoint record is appended after this many commits.
+pub const CHECKPOINT_INTERVAL: u64 = 1024;
+
+const MAGIC: &[u8; 6] = b"HITLOG";
+const HEADER_LEN: u64 = 8;
+
+/// Marks the start of a record, so scanning can resynchronise after damage.
+const RECORD_MAGIC: [u8; 2] = *b"hr";
+/// Record magic, `u32` length and CRC32 over the length and body.
+const RECORD_HEADER_LEN: usize = 10;
+
+/// Every record body starts with a kind byte and a commit id, both
+/// uncompressed so the index can be rebuilt without decoding payloads.
+const RECORD_PREFIX_LEN: usize = 9;
+const KIND_COMMIT: u8 = 0;
+const KIND_CHECKPOINT: u8 = 1;
+
+/// Index entries are a kind byte, a commit id and the record's log offset.
+const INDEX_ENTRY_LEN: u64 = 17;
+
+const APPEND_LEVEL: i32 = 0;
+const COMPACT_LEVEL: i32 = 19;
+
+/// Summary of the history in a commit log.
+///
+/// Checkpoint records store this state, so opening a log only replays the
+/// commits written after the newest checkpoint.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LogState {
+    /// Id of the newest commit, 0 for an empty log.
+    pub last_id: u64,
+    /// Number of commits in the log.
+    pub count: u64,
+    /// Newest change recorded for every path.
+    pub heads: BTreeMap<String, Change>,
+    pub latest: Option<Commit>,
+}
+
+impl LogState {
+    fn apply(&mut self, commit: &Commit) {
+        self.last_id = commit.id;
+        self.count += 1;
+        for change in &commit.changes {
+            self.heads.insert(change.path.clone(), change.clone());
+        }
+        self.latest = Some(commit.clone());
+    }
+}
 
 /// Append-only commit log stored on disk.
+///
+/// An offset index is kept next to the log (`commits.idx` for `commits.log`)
+/// for random access by commit id. The index is derived data: it is checked
+/// against the log on open and rebuilt if it is missing or stale.
+///
+/// Opening a log verifies every record written since the index was last
+/// updated. A damaged record at the end is a torn write and is truncated;
+/// damage followed by intact records is reported as an error.
 pub struct CommitLog {
     path: PathBuf,
     file: File,
+    index: File,
+    /// Cleared when an index write fails so later entries are not written
+    /// out of order; the index is repaired on the next open.
+    index_ok: bool,
+    /// Length of the log up to the end of the last record.
+    end: u64,
+    durable: Arc<Durable>,
+    state: LogState,
+    since_checkpoint: u64,
+}
+
+/// The part of a log that readers may see, published by the writer after
+/// every flush.
+#[derive(Debug)]
+struct Durable {
+    end: AtomicU64,
+    index_ok: AtomicBool,
 }
 
 impl CommitLog {
     /// Open the commit log for appending. The file is created if it doesn't exist.
     pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
         let path = path.as_ref().to_owned();
-        let file = OpenOptions::new()
+        if read_version(&path)?.is_some_and(|v| v < LOG_VERSION) {
+            upgrade(&path)?;
+        }
+        let mut file = OpenOptions::new()
             .create(true)
+            .read(true)
             .append(true)
             .open(&path)?;
-        Ok(Self { path, file })
+        if file.metadata()?.len() == 0 {
+            file.write_all(&header())?;
+            file.sync_data()?;
+        }
+        let mut index = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(index_path(&path))?;
+        sync_index(&path, &mut file, &mut index)?;
+        let (state, since_checkpoint) = recover(&path, &mut file, &mut index)?;
+        tracing::debug!(path = %path.display(), last_id = state.last_id, replayed = since_checkpoint, "opened commit log");
+        let end = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            index,
+            index_ok: true,
+            end,
+            durable: Arc::new(Durable {
+                end: AtomicU64::new(end),
+                index_ok: AtomicBool::new(true),
+            }),
+            state,
+            since_checkpoint,
+        })
     }
 
     /// Append a commit to the log and flush to disk.
     pub fn append(&mut self, commit: &Commit) -> io::Result<()> {
-        let data = bincode::serialize(commit).map_err(to_io_err)?;
-        let compressed = zstd::stream::encode_all(&data[..], 0)?;
-        let len = compressed.len() as u32;
-        self
-            .file
-            .write_all(&len.to_le_bytes())
-            .map_err(|e| {
-                tracing::error!("failed to write commit length: {}", e);
-                e
-            })?;
-        self.file
-            .write_all(&compressed)
-            .map_err(|e| {
-                tracing::error!("failed to write commit data: {}", e);
-                e
-            })?;
-        self.file.sync_data().map_err(|e| {
-            tracing::error!("failed to sync commit log: {}", e);
-            e
-        })?;
+        self.append_batch(std::slice::from_ref(commit))
+    }
+
+    /// Append several commits to the log with a single flush to disk.
+    ///
+    /// If the write fails none of the commits are in the log afterwards.
+    pub fn append_batch(&mut self, commits: &[Commit]) -> io::Result<()> {
+        let start = self.end;
+        let mut records = Vec::new();
+        let mut offsets = Vec::with_capacity(commits.len());
+        for commit in commits {
+            let data = serde_json::to_vec(commit).map_err(to_io_err)?;
+            offsets.push(start + records.len() as u64);
+            records.extend_from_slice(&encode_record(KIND_COMMIT, commit.id, &data, APPEND_LEVEL)?);
+        }
+        if let Err(e) = self.file.write_all(&records).and_then(|_| self.file.sync_data()) {
+            tracing::error!("failed to write commits to log: {}", e);
+            // Drop any part of the batch that reached the file, so the next
+            // append does not land after a partial record.
+            if let Err(e) = self.file.set_len(start) {
+                tracing::error!("failed to truncate commit log after a failed write: {}", e);
+            }
+            return Err(e);
+        }
+        self.end += records.len() as u64;
+        for (commit, offset) in commits.iter().zip(offsets) {
+            self.add_index_entry(KIND_COMMIT, commit.id, offset);
+            self.state.apply(commit);
+            self.since_checkpoint += 1;
+        }
+        if self.since_checkpoint >= CHECKPOINT_INTERVAL {
+            // The commits themselves are durable; a missing checkpoint only
+            // means the next open replays a little more.
+            if let Err(e) = self.checkpoint() {
+                tracing::warn!("failed to write checkpoint: {}", e);
+            }
+        }
+        self.durable.end.store(self.end, Ordering::Release);
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> io::Result<()> {
+        let data = serde_json::to_vec(&self.state).map_err(to_io_err)?;
+        let offset = self.end;
+        let record = encode_record(KIND_CHECKPOINT, self.state.last_id, &data, APPEND_LEVEL)?;
+        if let Err(e) = self.file.write_all(&record).and_then(|_| self.file.sync_data()) {
+            let _ = self.file.set_len(offset);
+            return Err(e);
+        }
+        self.end += record.len() as u64;
+        self.add_index_entry(KIND_CHECKPOINT, self.state.last_id, offset);
+        self.since_checkpoint = 0;
         Ok(())
     }
 
+    fn add_index_entry(&mut self, kind: u8, id: u64, offset: u64) {
+        if !self.index_ok {
+            return;
+        }
+        if let Err(e) = self.index.write_all(&encode_index_entry(kind, id, offset)) {
+            tracing::warn!("failed to update commit log index: {}", e);
+            self.index_ok = false;
+            self.durable.index_ok.store(false, Ordering::Release);
+        }
+    }
+
+    /// A handle for reading the commits flushed so far while appends go on.
+    pub fn reader(&self) -> LogReader {
+        LogReader {
+            path: self.path.clone(),
+            durable: self.durable.clone(),
+        }
+    }
+
+    /// Read the commit with the given id, using the index to seek to it.
+    pub fn get(&self, id: u64) -> io::Result<Option<Commit>> {
+        self.reader().get(id)
+    }
+
+    /// Iterate over the commits with ids greater than `after`, reading them
+    /// lazily from disk.
+    ///
+    /// The iterator has its own file handle and stops at the end of the log
+    /// as it is now; commits appended later are not returned.
+    pub fn iter_after(&self, after: u64) -> io::Result<CommitIter> {
+        self.reader().iter_after(after)
+    }
+
+    /// State of the log as of the last append.
+    pub fn state(&self) -> &LogState {
+        &self.state
+    }
+
     /// Load all commits from the given path.
+    ///
+    /// A torn record at the end is ignored, but any other damaged record is
+    /// an error; use [`CommitLog::scan`] to find out which ones.
     pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Commit>> {
+        Self::iter(path)?.collect()
+    }
+
+    /// Iterate over every commit in the log at `path` without loading the
+    /// whole history into memory. Errors are the same as for [`CommitLog::load`].
+    pub fn iter(path: impl AsRef<Path>) -> io::Result<CommitIter> {
         let path = path.as_ref();
-        let mut file = match File::open(path) {
-            Ok(f) => f,
-            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
-            Err(e) => {
-                tracing::error!("failed to open commit log at {}: {}", path.display(), e);
-                return Err(e);
+        match read_version(path)? {
+            Some(version) => {
+                let start = if version == 0 { 0 } else { HEADER_LEN };
+                CommitIter::open(path, version, start, fs::metadata(path)?.len())
             }
+            None => Ok(CommitIter::empty(path)),
+        }
+    }
+
+    /// Read every record in the log, reporting the ones that cannot be decoded
+    /// instead of silently dropping them.
+    pub fn scan(path: impl AsRef<Path>) -> io::Result<LogScan> {
+        let path = path.as_ref();
+        let version = match read_version(path)? {
+            Some(v) => v,
+            None => return Ok(LogScan::default()),
         };
-        let mut commits = Vec::new();
-        loop {
-            let mut len_buf = [0u8; 4];
-            if let Err(e) = file.read_exact(&mut len_buf) {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    break;
-                }
-                tracing::error!("failed to read commit length: {}", e);
-                return Err(e);
+        let file = File::open(path).map_err(|e| {
+            tracing::error!("failed to open commit log at {}: {}", path.display(), e);
+            e
+        })?;
+        let start = if version == 0 { 0 } else { HEADER_LEN };
+        let mut reader = RecordReader::new(file, version, start)?;
+        let mut scan = LogScan::default();
+        while let Some(frame) = reader.next_frame()? {
+            match frame {
+                Frame::Record { offset, len, body } => match decode_body(version, &body) {
+                    Ok(Record::Commit(commit)) => scan.commits.push(commit),
+                    Ok(Record::Checkpoint(_)) => {}
+                    Err(reason) => scan.corrupt.push(CorruptRecord { offset, len, reason }),
+                },
+                Frame::Corrupt(bad) => scan.corrupt.push(bad),
+                Frame::Torn(offset) => scan.torn_tail = Some(offset),
             }
-            let len = u32::from_le_bytes(len_buf) as usize;
-            let mut data = vec![0u8; len];
-            if let Err(e) = file.read_exact(&mut data) {
-                if e.kind() == io::ErrorKind::UnexpectedEof {
-                    tracing::error!("unexpected EOF when reading commit data: {}", e);
-                    break;
-                }
-                tracing::error!("failed to read commit data: {}", e);
-                return Err(e);
+        }
+        Ok(scan)
+    }
+
+    /// Replace the log at `path` with one containing exactly `commits`.
+    ///
+    /// The new log is written next to the old one and renamed into place, so a
+    /// crash leaves either the old or the new file intact.
+    pub fn rewrite(path: impl AsRef<Path>, commits: &[Commit]) -> io::Result<()> {
+        write_log(path.as_ref(), commits, APPEND_LEVEL)
+    }
+
+    /// Rewrite the log with every commit recompressed at a higher level and a
+    /// single checkpoint at the end. History is preserved exactly, so a log
+    /// with damaged records is refused; repair it with `hit fsck --repair`.
+    ///
+    /// The log must not be open for appending (e.g. by `hit serve`) while it
+    /// is compacted.
+    pub fn compact(path: impl AsRef<Path>) -> io::Result<CompactReport> {
+        let path = path.as_ref();
+        let bytes_before = match fs::metadata(path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CompactReport::default()),
+            Err(e) => return Err(e),
+        };
+        let scan = Self::scan(path)?;
+        if !scan.corrupt.is_empty() || scan.torn_tail.is_some() {
+            return Err(invalid_data(
+                "commit log has damaged records; run `hit fsck --repair` first".into(),
+            ));
+        }
+        write_log(path, &scan.commits, COMPACT_LEVEL)?;
+        let report = CompactReport {
+            commits: scan.commits.len(),
+            bytes_before,
+            bytes_after: fs::metadata(path)?.len(),
+        };
+        tracing::info!(path = %path.display(), ?report, "compacted commit log");
+        Ok(report)
+    }
+
+    /// Path backing the commit log.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl CommitBackend for CommitLog {
+    fn append(&mut self, commits: &[Commit]) -> io::Result<()> {
+        self.append_batch(commits)
+    }
+
+    fn last_id(&self) -> u64 {
+        self.state.last_id
+    }
+
+    fn latest(&self) -> Option<Commit> {
+        self.state.latest.clone()
+    }
+
+    fn reader(&self) -> Arc<dyn CommitReader> {
+        Arc::new(CommitLog::reader(self))
+    }
+}
+
+/// Reads a commit log while it is appended to, see [`CommitLog::reader`].
+///
+/// Every call opens its own file handles and sees the commits that were
+/// flushed when it started.
+#[derive(Clone)]
+pub struct LogReader {
+    path: PathBuf,
+    durable: Arc<Durable>,
+}
+
+impl LogReader {
+    /// Read the commit with the given id, using the index to seek to it.
+    pub fn get(&self, id: u64) -> io::Result<Option<Commit>> {
+        if id == 0 {
+            return Ok(None);
+        }
+        match self.iter_after(id - 1)?.next() {
+            Some(Ok(commit)) if commit.id == id => Ok(Some(commit)),
+            Some(Err(e)) => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    /// Iterate over the commits with ids greater than `after`.
+    pub fn iter_after(&self, after: u64) -> io::Result<CommitIter> {
+        let end = self.durable.end.load(Ordering::Acquire);
+        let start = if self.durable.index_ok.load(Ordering::Acquire) {
+            let mut index = File::open(index_path(&self.path))?;
+            let entries = index.metadata()?.len() / INDEX_ENTRY_LEN;
+            match seek_index(&mut index, entries, after.saturating_add(1))? {
+                Some(entry) if entry.offset < end => entry.offset,
+                _ => end,
             }
-            let decompressed = match zstd::stream::decode_all(&data[..]) {
-                Ok(d) => d,
+        } else {
+            // Without a complete index, scan from the start.
+            HEADER_LEN
+        };
+        let mut iter = CommitIter::open(&self.path, LOG_VERSION, start, end)?;
+        iter.after = after;
+        Ok(iter)
+    }
+}
+
+impl CommitReader for LogReader {
+    fn get(&self, id: u64) -> io::Result<Option<Commit>> {
+        LogReader::get(self, id)
+    }
+
+    fn iter_after(&self, after: u64) -> io::Result<CommitIterator> {
+        Ok(Box::new(LogReader::iter_after(self, after)?))
+    }
+}
+
+/// Result of reading a commit log record by record.
+#[derive(Debug, Default)]
+pub struct LogScan {
+    /// Commits that decoded successfully, in log order.
+    pub commits: Vec<Commit>,
+    /// Damaged records followed by intact ones.
+    pub corrupt: Vec<CorruptRecord>,
+    /// Offset of damage at the end of the log left by a torn write, if any.
+    pub torn_tail: Option<u64>,
+}
+
+/// A damaged region of the log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptRecord {
+    /// Byte offset where the damage starts.
+    pub offset: u64,
+    /// Bytes up to the next intact record.
+    pub len: u64,
+    pub reason: String,
+}
+
+/// Outcome of [`CommitLog::compact`].
+#[derive(Debug, Default, PartialEq)]
+pub struct CompactReport {
+    pub commits: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// Lazy iterator over the commits in a log, see [`CommitLog::iter`].
+///
+/// Yields an error and stops at a damaged record; a torn record at the end
+/// of the log ends the iteration with a warning.
+pub struct CommitIter {
+    path: PathBuf,
+    version: u16,
+    /// Commits with this id or lower are skipped.
+    after: u64,
+    reader: Option<RecordReader>,
+}
+
+impl CommitIter {
+    fn open(path: &Path, version: u16, start: u64, end: u64) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mut reader = RecordReader::new(file, version, start)?;
+        reader.file_len = reader.file_len.min(end);
+        Ok(Self {
+            path: path.to_owned(),
+            version,
+            after: 0,
+            reader: Some(reader),
+        })
+    }
+
+    fn empty(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            version: LOG_VERSION,
+            after: 0,
+            reader: None,
+        }
+    }
+}
+
+impl Iterator for CommitIter {
+    type Item = io::Result<Commit>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = match self.reader.as_mut()?.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    self.reader = None;
+                    return None;
+                }
                 Err(e) => {
-                    tracing::error!("failed to decompress commit: {}", e);
-                    continue;
+                    self.reader = None;
+                    return Some(Err(e));
                 }
             };
-            let commit: Commit = match bincode::deserialize(&decompressed) {
-                Ok(c) => c,
-                Err(e) => {
-                    let err = to_io_err(e);
-                    tracing::error!("failed to deserialize commit: {}", err);
-                    continue;
+            let bad = match frame {
+                Frame::Record { offset, len, body } => match decode_body(self.version, &body) {
+                    Ok(Record::Commit(commit)) if commit.id <= self.after => continue,
+                    Ok(Record::Commit(commit)) => return Some(Ok(commit)),
+                    Ok(Record::Checkpoint(_)) => continue,
+                    Err(reason) => CorruptRecord { offset, len, reason },
+                },
+                Frame::Corrupt(bad) => bad,
+                Frame::Torn(offset) => {
+                    tracing::warn!(offset, "ignoring torn record at end of commit log");
+                    self.reader = None;
+                    return None;
                 }
             };
-            commits.push(commit);
+            self.reader = None;
+            return Some(Err(corrupt_error(&self.path, &bad)));
         }
-        Ok(commits)
     }
+}
 
-    /// Path backing the commit log.
-    pub fn path(&self) -> &Path {
-        &self.path
+enum Record {
+    Commit(Commit),
+    Checkpoint(LogState),
+}
+
+struct IndexEntry {
+    kind: u8,
+    id: u64,
+    offset: u64,
+}
+
+enum Frame {
+    /// A record whose framing (and checksum, from version 2) is intact.
+    Record { offset: u64, len: u64, body: Vec<u8> },
+    Corrupt(CorruptRecord),
+    Torn(u64),
+}
+
+/// Reads records one after another, telling torn tails apart from damage in
+/// the middle of the log by looking for an intact record after the damage.
+struct RecordReader {
+    file: BufReader<File>,
+    version: u16,
+    offset: u64,
+    file_len: u64,
+}
+
+impl RecordReader {
+    fn new(file: File, version: u16, offset: u64) -> io::Result<Self> {
+        let file_len = file.metadata()?.len();
+        let mut file = BufReader::new(file);
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Self {
+            file,
+            version,
+            offset,
+            file_len,
+        })
     }
+
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        if self.offset >= self.file_len {
+            return Ok(None);
+        }
+        let offset = self.offset;
+        let remaining = self.file_len - offset;
+        match read_frame(&mut self.file, self.version, remaining)? {
+            Ok((len, body)) => {
+                self.offset += len;
+                Ok(Some(Frame::Record { offset, len, body }))
+            }
+            // Older formats cannot be resynchronised: without a checksum a
+            // bad length makes everything after it unreadable.
+            Err(_) if self.version < 2 => {
+                self.offset = self.file_len;
+                Ok(Some(Frame::Torn(offset)))
+            }
+            Err(reason) => match self.find_next_record(offset + 1)? {
+                Some(next) => {
+                    self.offset = next;
+                    self.file.seek(SeekFrom::Start(next))?;
+                    Ok(Some(Frame::Corrupt(CorruptRecord {
+                        offset,
+                        len: next - offset,
+                        reason,
+                    })))
+                }
+                None => {
+                    self.offset = self.file_len;
+                    Ok(Some(Frame::Torn(offset)))
+                }
+            },
+        }
+    }
+
+    /// Offset of the first intact record at or after `from`.
+    fn find_next_record(&mut self, from: u64) -> io::Result<Option<u64>> {
+        self.file.seek(SeekFrom::Start(from))?;
+        let mut rest = Vec::new();
+        self.file.read_to_end(&mut rest)?;
+        for i in 0..(rest.len() + 1).saturating_sub(RECORD_HEADER_LEN) {
+            if rest[i..].starts_with(&RECORD_MAGIC) {
+                let mut candidate = &rest[i..];
+                let remaining = candidate.len() as u64;
+                if read_frame(&mut candidate, self.version, remaining)?.is_ok() {
+                    return Ok(Some(from + i as u64));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Read one record from `reader`, which has `remaining` bytes left. Returns
+/// the record's total size and its body, or why it is damaged.
+fn read_frame(
+    reader: &mut impl Read,
+    version: u16,
+    remaining: u64,
+) -> io::Result<Result<(u64, Vec<u8>), String>> {
+    let header_len = if version >= 2 { RECORD_HEADER_LEN } else { 4 };
+    if remaining < header_len as u64 {
+        return Ok(Err("incomplete record header".into()));
+    }
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    reader.read_exact(&mut header[..header_len])?;
+    let (len_bytes, crc) = if version >= 2 {
+        if header[..2] != RECORD_MAGIC {
+            return Ok(Err("missing record marker".into()));
+        }
+        let crc = u32::from_le_bytes(header[6..10].try_into().unwrap());
+        (&header[2..6], Some(crc))
+    } else {
+        (&header[..4], None)
+    };
+    let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as u64;
+    if len > remaining - header_len as u64 {
+        return Ok(Err(format!("record length {} runs past the end of the log", len)));
+    }
+    let mut body = vec![0u8; len as usize];
+    reader.read_exact(&mut body)?;
+    if let Some(expected) = crc {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(len_bytes);
+        hasher.update(&body);
+        if hasher.finalize() != expected {
+            return Ok(Err("checksum mismatch".into()));
+        }
+    }
+    Ok(Ok((header_len as u64 + len, body)))
+}
+
+/// Location of the offset index belonging to the log at `path`.
+pub fn index_path(path: &Path) -> PathBuf {
+    path.with_extension("idx")
+}
+
+fn header() -> [u8; HEADER_LEN as usize] {
+    let mut header = [0u8; HEADER_LEN as usize];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&LOG_VERSION.to_le_bytes());
+    header
+}
+
+/// Format version of the log at `path`: `None` if it is missing or empty and
+/// `Some(0)` for a log written before the header was introduced.
+fn read_version(path: &Path) -> io::Result<Option<u16>> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut buf = [0u8; HEADER_LEN as usize];
+    let n = read_full(&mut file, &mut buf)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n < buf.len() || &buf[..MAGIC.len()] != MAGIC {
+        return Ok(Some(0));
+    }
+    let version = u16::from_le_bytes([buf[6], buf[7]]);
+    if version > LOG_VERSION {
+        return Err(invalid_data(format!(
+            "{} has unsupported commit log version {}",
+            path.display(),
+            version
+        )));
+    }
+    Ok(Some(version))
+}
+
+/// Rewrite a log in an older format in the current one, keeping the
+/// original next to it in case it held records that could not be decoded.
+fn upgrade(path: &Path) -> io::Result<()> {
+    let scan = CommitLog::scan(path)?;
+    let backup = path.with_extension("log.legacy");
+    fs::copy(path, &backup)?;
+    if !scan.corrupt.is_empty() || scan.torn_tail.is_some() {
+        tracing::warn!(
+            corrupt = scan.corrupt.len(),
+            torn = scan.torn_tail.is_some(),
+            backup = %backup.display(),
+            "dropping damaged records while upgrading commit log"
+        );
+    }
+    write_log(path, &scan.commits, APPEND_LEVEL)?;
+    tracing::info!(
+        path = %path.display(),
+        backup = %backup.display(),
+        commits = scan.commits.len(),
+        "upgraded commit log to version {}",
+        LOG_VERSION
+    );
+    Ok(())
+}
+
+fn write_log(path: &Path, commits: &[Commit], level: i32) -> io::Result<()> {
+    let tmp = path.with_extension("log.tmp");
+    let _ = fs::remove_file(&tmp);
+    let file = File::create(&tmp)?;
+    let mut out = BufWriter::new(file);
+    out.write_all(&header())?;
+    let mut state = LogState::default();
+    for commit in commits {
+        let data = serde_json::to_vec(commit).map_err(to_io_err)?;
+        out.write_all(&encode_record(KIND_COMMIT, commit.id, &data, level)?)?;
+        state.apply(commit);
+    }
+    if !commits.is_empty() {
+        let data = serde_json::to_vec(&state).map_err(to_io_err)?;
+        out.write_all(&encode_record(KIND_CHECKPOINT, state.last_id, &data, level)?)?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    // Drop the old index first: a crash before the rename then leaves the
+    // old log with no index, which is rebuilt on open.
+    match fs::remove_file(index_path(path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::rename(&tmp, path)
+}
+
+fn encode_record(kind: u8, id: u64, data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let compressed = zstd::stream::encode_all(data, level)?;
+    let len = ((RECORD_PREFIX_LEN + compressed.len()) as u32).to_le_bytes();
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + RECORD_PREFIX_LEN + compressed.len());
+    record.extend_from_slice(&RECORD_MAGIC);
+    record.extend_from_slice(&len);
+    record.extend_from_slice(&[0; 4]);
+    record.push(kind);
+    record.extend_from_slice(&id.to_le_bytes());
+    record.extend_from_slice(&compressed);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len);
+    hasher.update(&record[RECORD_HEADER_LEN..]);
+    record[6..RECORD_HEADER_LEN].copy_from_slice(&hasher.finalize().to_le_bytes());
+    Ok(record)
+}
+
+fn decode_body(version: u16, body: &[u8]) -> Result<Record, String> {
+    if version == 0 {
+        return decode_legacy_record(body).map(Record::Commit);
+    }
+    decode_record(version, body)
+}
+
+fn decode_record(version: u16, data: &[u8]) -> Result<Record, String> {
+    if data.len() < RECORD_PREFIX_LEN {
+        return Err(format!("record of {} bytes is too short", data.len()));
+    }
+    let kind = data[0];
+    let id = u64::from_le_bytes(data[1..RECORD_PREFIX_LEN].try_into().unwrap());
+    let decompressed = zstd::stream::decode_all(&data[RECORD_PREFIX_LEN..])
+        .map_err(|e| format!("failed to decompress record: {}", e))?;
+    match kind {
+        KIND_COMMIT => {
+            let commit = decode_payload::<Commit, LegacyCommit>(version, &decompressed)
+                .map_err(|e| format!("failed to deserialize commit: {}", e))?;
+            if commit.id != id {
+                return Err(format!("record for commit {} holds commit {}", id, commit.id));
+            }
+            Ok(Record::Commit(commit))
+        }
+        KIND_CHECKPOINT => decode_payload::<LogState, LegacyLogState>(version, &decompressed)
+            .map(Record::Checkpoint)
+            .map_err(|e| format!("failed to deserialize checkpoint: {}", e)),
+        other => Err(format!("unknown record kind {}", other)),
+    }
+}
+
+fn decode_legacy_record(data: &[u8]) -> Result<Commit, String> {
+    let decompressed =
+        zstd::stream::decode_all(data).map_err(|e| format!("failed to decompress commit: {}", e))?;
+    bincode::deserialize::<LegacyCommit>(&decompressed)
+        .map(Commit::from)
+        .map_err(|e| format!("failed to deserialize commit: {}", e))
+}
+
+/// Decode a record payload: JSON from version 3, bincode in the layout
+/// `L` of the same type before that.
+fn decode_payload<T, L>(version: u16, data: &[u8]) -> Result<T, String>
+where
+    T: serde::de::DeserializeOwned,
+    L: serde::de::DeserializeOwned + Into<T>,
+{
+    if version < 3 {
+        return bincode::deserialize::<L>(data).map(Into::into).map_err(|e| e.to_string());
+    }
+    serde_json::from_slice(data).map_err(|e| e.to_string())
+}
+
+/// Layouts of the log versions that stored payloads as bincode. Bincode
+/// encodes fields by position, so these must never change.
+#[derive(Serialize, Deserialize)]
+struct LegacyChange {
+    hash: ObjectId,
+    path: String,
+    timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyCommit {
+    id: u64,
+    changes: Vec<LegacyChange>,
+    timestamp: u64,
+}
+
+#[derive(Deserialize)]
+struct LegacyLogState {
+    last_id: u64,
+    count: u64,
+    heads: BTreeMap<String, LegacyChange>,
+    latest: Option<LegacyCommit>,
+}
+
+impl From<LegacyChange> for Change {
+    fn from(c: LegacyChange) -> Self {
+        Change {
+            hash: c.hash,
+            path: c.path,
+            timestamp: c.timestamp,
+            client: None,
+            mode: FileMode::Regular,
+        }
+    }
+}
+
+impl From<LegacyCommit> for Commit {
+    fn from(c: LegacyCommit) -> Self {
+        Commit {
+            id: c.id,
+            changes: c.changes.into_iter().map(Change::from).collect(),
+            timestamp: c.timestamp,
+            author: None,
+        }
+    }
+}
+
+impl From<LegacyLogState> for LogState {
+    fn from(s: LegacyLogState) -> Self {
+        LogState {
+            last_id: s.last_id,
+            count: s.count,
+            heads: s.heads.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            latest: s.latest.map(Commit::from),
+        }
+    }
+}
+
+/// Read and verify the record at `offset` of a current-format log.
+fn read_record_at(path: &Path, file: &mut File, offset: u64) -> io::Result<Record> {
+    let remaining = file.metadata()?.len().saturating_sub(offset);
+    file.seek(SeekFrom::Start(offset))?;
+    let body = match read_frame(file, LOG_VERSION, remaining)? {
+        Ok((_, body)) => body,
+        Err(reason) => return Err(corrupt_error(path, &CorruptRecord { offset, len: 0, reason })),
+    };
+    decode_record(LOG_VERSION, &body).map_err(|reason| corrupt_error(path, &CorruptRecord { offset, len: 0, reason }))
+}
+
+/// Read the kind and id of the record at `offset` without verifying or
+/// decoding its body. Returns the offset just past the record, or `None` if
+/// there is no plausible record there.
+fn read_record_prefix(file: &mut File, offset: u64, file_len: u64) -> io::Result<Option<(u8, u64, u64)>> {
+    let prefix_end = offset + (RECORD_HEADER_LEN + RECORD_PREFIX_LEN) as u64;
+    if prefix_end > file_len {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = [0u8; RECORD_HEADER_LEN + RECORD_PREFIX_LEN];
+    file.read_exact(&mut buf)?;
+    if buf[..2] != RECORD_MAGIC {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(buf[2..6].try_into().unwrap()) as u64;
+    let end = offset + RECORD_HEADER_LEN as u64 + len;
+    if end > file_len || (len as usize) < RECORD_PREFIX_LEN {
+        return Ok(None);
+    }
+    let id = u64::from_le_bytes(buf[RECORD_HEADER_LEN + 1..].try_into().unwrap());
+    Ok(Some((buf[RECORD_HEADER_LEN], id, end)))
+}
+
+/// Index entry of the first commit with an id of at least `id`, searching
+/// the first `entries` entries.
+fn seek_index(index: &mut File, entries: u64, id: u64) -> io::Result<Option<IndexEntry>> {
+    // Entries are ordered by id, with a checkpoint after the commit it
+    // follows, so the first entry not below (id, commit) is the match.
+    let (mut lo, mut hi) = (0, entries);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let entry = read_index_entry(index, mid)?;
+        if (entry.id, entry.kind) < (id, KIND_COMMIT) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    if lo == entries {
+        return Ok(None);
+    }
+    read_index_entry(index, lo).map(Some)
+}
+
+fn encode_index_entry(kind: u8, id: u64, offset: u64) -> [u8; INDEX_ENTRY_LEN as usize] {
+    let mut entry = [0u8; INDEX_ENTRY_LEN as usize];
+    entry[0] = kind;
+    entry[1..9].copy_from_slice(&id.to_le_bytes());
+    entry[9..].copy_from_slice(&offset.to_le_bytes());
+    entry
+}
+
+fn read_index_entry(index: &mut File, n: u64) -> io::Result<IndexEntry> {
+    index.seek(SeekFrom::Start(n * INDEX_ENTRY_LEN))?;
+    let mut entry = [0u8; INDEX_ENTRY_LEN as usize];
+    index.read_exact(&mut entry)?;
+    Ok(IndexEntry {
+        kind: entry[0],
+        id: u64::from_le_bytes(entry[1..9].try_into().unwrap()),
+        offset: u64::from_le_bytes(entry[9..].try_into().unwrap()),
+    })
+}
+
+/// Bring the index up to date with the log, verifying and indexing the
+/// records written after its last entry. An index that disagrees with the
+/// log is rebuilt. A torn record at the end of the log is truncated so the
+/// next append does not land after garbage.
+fn sync_index(path: &Path, file: &mut File, index: &mut File) -> io::Result<()> {
+    let file_len = file.metadata()?.len();
+    let index_len = index.metadata()?.len();
+    let mut entries = index_len / INDEX_ENTRY_LEN;
+    let mut offset = HEADER_LEN;
+    if entries > 0 {
+        let last = read_index_entry(index, entries - 1)?;
+        match read_record_prefix(file, last.offset, file_len)? {
+            Some((kind, id, end)) if kind == last.kind && id == last.id => offset = end,
+            _ => {
+                tracing::warn!("commit log index does not match the log, rebuilding");
+                entries = 0;
+            }
+        }
+    }
+    if entries * INDEX_ENTRY_LEN != index_len {
+        index.set_len(entries * INDEX_ENTRY_LEN)?;
+    }
+    let mut reader = RecordReader::new(file.try_clone()?, LOG_VERSION, offset)?;
+    let mut added = Vec::new();
+    while let Some(frame) = reader.next_frame()? {
+        match frame {
+            Frame::Record { offset, len, body } => {
+                if body.len() < RECORD_PREFIX_LEN {
+                    let reason = format!("record of {} bytes is too short", body.len());
+                    return Err(corrupt_error(path, &CorruptRecord { offset, len, reason }));
+                }
+                let id = u64::from_le_bytes(body[1..RECORD_PREFIX_LEN].try_into().unwrap());
+                added.extend_from_slice(&encode_index_entry(body[0], id, offset));
+            }
+            Frame::Corrupt(bad) => return Err(corrupt_error(path, &bad)),
+            Frame::Torn(offset) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    offset,
+                    bytes = file_len - offset,
+                    "truncating torn record at end of commit log"
+                );
+                file.set_len(offset)?;
+                file.sync_data()?;
+            }
+        }
+    }
+    index.write_all(&added)
+}
+
+/// Restore the log state from the newest checkpoint and the commits after it.
+/// Also returns how many commits were replayed.
+fn recover(path: &Path, file: &mut File, index: &mut File) -> io::Result<(LogState, u64)> {
+    let entries = index.metadata()?.len() / INDEX_ENTRY_LEN;
+    let mut state = LogState::default();
+    let mut start = 0;
+    for n in (0..entries).rev() {
+        let entry = read_index_entry(index, n)?;
+        if entry.kind != KIND_CHECKPOINT {
+            continue;
+        }
+        // Checkpoints are redundant, so a damaged one only costs a longer replay.
+        match read_record_at(path, file, entry.offset) {
+            Ok(Record::Checkpoint(checkpoint)) => {
+                state = checkpoint;
+                start = n + 1;
+                break;
+            }
+            Ok(Record::Commit(_)) => {}
+            Err(e) => tracing::warn!(offset = entry.offset, "skipping unreadable checkpoint: {}", e),
+        }
+    }
+    let mut replayed = 0;
+    for n in start..entries {
+        let entry = read_index_entry(index, n)?;
+        if entry.kind != KIND_COMMIT {
+            continue;
+        }
+        if let Record::Commit(commit) = read_record_at(path, file, entry.offset)? {
+            state.apply(&commit);
+            replayed += 1;
+        }
+    }
+    Ok((state, replayed))
+}
+
+fn corrupt_error(path: &Path, bad: &CorruptRecord) -> io::Error {
+    invalid_data(format!(
+        "{} is corrupt at offset {}: {}; run `hit fsck --repair`",
+        path.display(),
+        bad.offset,
+        bad.reason
+    ))
+}
+
+/// Like `read_exact`, but reports how many bytes were read before EOF.
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
 }
 
 fn to_io_err<E: std::fmt::Display>(e: E) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, format!("{}", e))
+    io::Error::other(format!("{}", e))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::server::Change;
+    use crate::object_id::ObjectId;
     use crate::commit::Commit;
     use serial_test::serial;
 
     fn log_path() -> &'static str { "test_commits.log" }
 
-    fn clean() { let _ = std::fs::remove_file(log_path()); }
+    fn clean() {
+        let _ = fs::remove_file(log_path());
+        let _ = fs::remove_file(index_path(Path::new(log_path())));
+        let _ = fs::remove_file(Path::new(log_path()).with_extension("log.legacy"));
+    }
+
+    fn commit(id: u64, path: &str) -> Commit {
+        Commit { id, changes: vec![Change { hash: ObjectId::from([id as u8; 32]), path: path.into(), timestamp: id, client: None, mode: FileMode::Regular }], timestamp: id, author: None }
+    }
+
+    /// `commit(id, "p")` as bincode, the way logs before version 3 stored it.
+    fn legacy_payload(id: u64) -> Vec<u8> {
+        let change = LegacyChange { hash: ObjectId::from([id as u8; 32]), path: "p".into(), timestamp: id };
+        bincode::serialize(&LegacyCommit { id, changes: vec![change], timestamp: id }).unwrap()
+    }
 
     #[test]
     #[serial]
     fn write_and_reload() {
         clean();
         let mut log = CommitLog::open(log_path()).unwrap();
-        let commit1 = Commit { id: 1, changes: vec![Change { hash: "h1".into(), path: "p".into(), timestamp: 1 }], timestamp: 1 };
-        let commit2 = Commit { id: 2, changes: vec![Change { hash: "h2".into(), path: "p".into(), timestamp: 2 }], timestamp: 2 };
+        let commit1 = Commit { id: 1, changes: vec![Change { hash: ObjectId::from([1; 32]), path: "p".into(), timestamp: 1, client: None, mode: FileMode::Regular }], timestamp: 1, author: None };
+        let commit2 = Commit { id: 2, changes: vec![Change { hash: ObjectId::from([2; 32]), path: "p".into(), timestamp: 2, client: None, mode: FileMode::Regular }], timestamp: 2, author: None };
         log.append(&commit1).unwrap();
         log.append(&commit2).unwrap();
         drop(log);
@@ -133,4 +1087,258 @@ mod tests {
         assert_eq!(loaded, vec![commit1, commit2]);
         clean();
     }
+
+    /// Log offsets of the records for commits `1..=n`, read from the index.
+    fn commit_offsets(n: u64) -> Vec<u64> {
+        let mut index = File::open(index_path(Path::new(log_path()))).unwrap();
+        (0..n).map(|i| read_index_entry(&mut index, i).unwrap().offset).collect()
+    }
+
+    #[test]
+    #[serial]
+    fn scan_reports_corrupt_and_torn_records() {
+        clean();
+        let mut log = CommitLog::open(log_path()).unwrap();
+        for id in 1..=3 {
+            log.append(&commit(id, "p")).unwrap();
+        }
+        drop(log);
+        let offsets = commit_offsets(3);
+        let mut bytes = fs::read(log_path()).unwrap();
+        bytes[offsets[1] as usize + RECORD_HEADER_LEN + RECORD_PREFIX_LEN + 2] ^= 0xff;
+        bytes.extend_from_slice(&RECORD_MAGIC);
+        bytes.extend_from_slice(&100u32.to_le_bytes());
+        bytes.extend_from_slice(b"torn");
+        fs::write(log_path(), bytes).unwrap();
+
+        let scan = CommitLog::scan(log_path()).unwrap();
+        assert_eq!(scan.commits, vec![commit(1, "p"), commit(3, "p")]);
+        assert_eq!(scan.corrupt.len(), 1);
+        assert_eq!(scan.corrupt[0].offset, offsets[1]);
+        assert_eq!(scan.corrupt[0].len, offsets[2] - offsets[1]);
+        assert_eq!(scan.corrupt[0].reason, "checksum mismatch");
+        assert!(scan.torn_tail.is_some());
+
+        CommitLog::rewrite(log_path(), &scan.commits).unwrap();
+        let scan = CommitLog::scan(log_path()).unwrap();
+        assert_eq!(scan.commits, vec![commit(1, "p"), commit(3, "p")]);
+        assert!(scan.corrupt.is_empty());
+        assert!(scan.torn_tail.is_none());
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn open_truncates_a_torn_tail() {
+        clean();
+        let mut log = CommitLog::open(log_path()).unwrap();
+        log.append(&commit(1, "p")).unwrap();
+        log.append(&commit(2, "p")).unwrap();
+        drop(log);
+        let intact = fs::metadata(log_path()).unwrap().len();
+        let record = encode_record(KIND_COMMIT, 3, &serde_json::to_vec(&commit(3, "p")).unwrap(), 0).unwrap();
+        let mut file = OpenOptions::new().append(true).open(log_path()).unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+        drop(file);
+
+        let mut log = CommitLog::open(log_path()).unwrap();
+        assert_eq!(fs::metadata(log_path()).unwrap().len(), intact);
+        assert_eq!(log.state().last_id, 2);
+        log.append(&commit(3, "p")).unwrap();
+        drop(log);
+        assert_eq!(CommitLog::load(log_path()).unwrap().len(), 3);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn corruption_before_intact_records_is_an_error() {
+        clean();
+        let mut log = CommitLog::open(log_path()).unwrap();
+        for id in 1..=3 {
+            log.append(&commit(id, "p")).unwrap();
+        }
+        drop(log);
+        let offsets = commit_offsets(3);
+        let mut bytes = fs::read(log_path()).unwrap();
+        bytes[offsets[1] as usize + RECORD_HEADER_LEN + 4] ^= 0xff;
+        fs::write(log_path(), bytes).unwrap();
+
+        let err = CommitLog::load(log_path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&format!("offset {}", offsets[1])), "{}", err);
+        assert!(CommitLog::open(log_path()).is_err());
+        // Also when the index has to be rebuilt from the log.
+        fs::remove_file(index_path(Path::new(log_path()))).unwrap();
+        let err = CommitLog::open(log_path()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn index_gives_random_access_and_is_rebuilt() {
+        clean();
+        let mut log = CommitLog::open(log_path()).unwrap();
+        for id in 1..=5 {
+            log.append(&commit(id, "p")).unwrap();
+        }
+        assert_eq!(log.get(3).unwrap(), Some(commit(3, "p")));
+        assert_eq!(log.get(6).unwrap(), None);
+        drop(log);
+
+        // A truncated index is completed from the log, a missing one rebuilt.
+        let index = index_path(Path::new(log_path()));
+        let len = fs::metadata(&index).unwrap().len();
+        OpenOptions::new().write(true).open(&index).unwrap().set_len(len - 20).unwrap();
+        let log = CommitLog::open(log_path()).unwrap();
+        assert_eq!(log.get(5).unwrap(), Some(commit(5, "p")));
+        drop(log);
+        fs::remove_file(&index).unwrap();
+        let log = CommitLog::open(log_path()).unwrap();
+        assert_eq!(log.get(1).unwrap(), Some(commit(1, "p")));
+        assert_eq!(log.state().last_id, 5);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn iterates_lazily_from_an_id() {
+        clean();
+        let mut log = CommitLog::open(log_path()).unwrap();
+        for id in 1..=5 {
+            log.append(&commit(id, "p")).unwrap();
+        }
+        let ids = |iter: CommitIter| iter.map(|c| c.unwrap().id).collect::<Vec<_>>();
+        assert_eq!(ids(log.iter_after(2).unwrap()), vec![3, 4, 5]);
+        assert_eq!(ids(log.iter_after(5).unwrap()), Vec::<u64>::new());
+        let snapshot = log.iter_after(0).unwrap();
+        log.append(&commit(6, "p")).unwrap();
+        assert_eq!(ids(snapshot), vec![1, 2, 3, 4, 5]);
+        drop(log);
+
+        let offsets = commit_offsets(2);
+        let mut bytes = fs::read(log_path()).unwrap();
+        bytes[offsets[1] as usize + RECORD_HEADER_LEN + 4] ^= 0xff;
+        fs::write(log_path(), bytes).unwrap();
+        let mut iter = CommitLog::iter(log_path()).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), commit(1, "p"));
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn readers_see_flushed_batches() {
+        clean();
+        let mut log = CommitLog::open(log_path()).unwrap();
+        let reader = log.reader();
+        assert_eq!(reader.iter_after(0).unwrap().count(), 0);
+        log.append_batch(&[commit(1, "a"), commit(2, "b"), commit(3, "a")]).unwrap();
+        assert_eq!(reader.get(2).unwrap(), Some(commit(2, "b")));
+        assert_eq!(reader.get(4).unwrap(), None);
+        let ids: Vec<u64> = reader.iter_after(1).unwrap().map(|c| c.unwrap().id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(log.state().count, 3);
+        drop(log);
+        assert_eq!(CommitLog::load(log_path()).unwrap().len(), 3);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn open_resumes_from_the_last_checkpoint() {
+        clean();
+        CommitLog::rewrite(log_path(), &[commit(1, "a"), commit(2, "b")]).unwrap();
+        let mut log = CommitLog::open(log_path()).unwrap();
+        log.append(&commit(3, "a")).unwrap();
+        drop(log);
+
+        // Damage the first commit's payload: only the checkpoint after it
+        // still knows about it, so a full replay would lose it.
+        let mut bytes = fs::read(log_path()).unwrap();
+        let payload = HEADER_LEN as usize + RECORD_HEADER_LEN + RECORD_PREFIX_LEN;
+        bytes[payload + 4] ^= 0xff;
+        fs::write(log_path(), bytes).unwrap();
+
+        let log = CommitLog::open(log_path()).unwrap();
+        let state = log.state();
+        assert_eq!(state.count, 3);
+        assert_eq!(state.last_id, 3);
+        assert_eq!(state.latest, Some(commit(3, "a")));
+        assert_eq!(state.heads["a"], commit(3, "a").changes[0]);
+        assert_eq!(state.heads["b"], commit(2, "b").changes[0]);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn upgrades_headerless_logs() {
+        clean();
+        let mut legacy = Vec::new();
+        for id in 1..=2 {
+            let data = zstd::stream::encode_all(&legacy_payload(id)[..], 0).unwrap();
+            legacy.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            legacy.extend_from_slice(&data);
+        }
+        fs::write(log_path(), &legacy).unwrap();
+        assert_eq!(CommitLog::load(log_path()).unwrap(), vec![commit(1, "p"), commit(2, "p")]);
+
+        let mut log = CommitLog::open(log_path()).unwrap();
+        log.append(&commit(3, "p")).unwrap();
+        drop(log);
+        assert!(fs::read(log_path()).unwrap().starts_with(MAGIC));
+        assert_eq!(fs::read(Path::new(log_path()).with_extension("log.legacy")).unwrap(), legacy);
+        assert_eq!(CommitLog::load(log_path()).unwrap().len(), 3);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn upgrades_bincode_logs() {
+        clean();
+        let mut legacy = Vec::from(&MAGIC[..]);
+        legacy.extend_from_slice(&2u16.to_le_bytes());
+        for id in 1..=2 {
+            legacy.extend_from_slice(&encode_record(KIND_COMMIT, id, &legacy_payload(id), 0).unwrap());
+        }
+        fs::write(log_path(), &legacy).unwrap();
+        assert_eq!(CommitLog::load(log_path()).unwrap(), vec![commit(1, "p"), commit(2, "p")]);
+
+        let mut log = CommitLog::open(log_path()).unwrap();
+        assert_eq!(log.state().last_id, 2);
+        log.append(&commit(3, "p")).unwrap();
+        drop(log);
+        assert_eq!(read_version(Path::new(log_path())).unwrap(), Some(LOG_VERSION));
+        assert_eq!(CommitLog::load(log_path()).unwrap().len(), 3);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn compact_keeps_history() {
+        clean();
+        let mut log = CommitLog::open(log_path()).unwrap();
+        for id in 1..=20 {
+            log.append(&commit(id, &format!("file-{}", id % 3))).unwrap();
+        }
+        drop(log);
+        let before = CommitLog::load(log_path()).unwrap();
+
+        let report = CommitLog::compact(log_path()).unwrap();
+        assert_eq!(report.commits, 20);
+        assert_eq!(CommitLog::load(log_path()).unwrap(), before);
+        let log = CommitLog::open(log_path()).unwrap();
+        assert_eq!(log.get(7).unwrap(), Some(commit(7, "file-1")));
+        assert_eq!(log.state().count, 20);
+        drop(log);
+
+        let mut file = OpenOptions::new().append(true).open(log_path()).unwrap();
+        file.write_all(&RECORD_MAGIC).unwrap();
+        drop(file);
+        let err = CommitLog::compact(log_path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        clean();
+    }
 }
@@ -0,0 +1,378 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params, params_from_iter};
+
+use crate::commit::{Commit, CommitBackend, CommitIterator, CommitQuery, CommitReader};
+
+/// File name of the commit database inside the server's data directory.
+pub const COMMIT_DB_FILE: &str = "commits.db";
+
+/// Schema version stored in `PRAGMA user_version`.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Commits fetched per query while iterating.
+const BATCH_SIZE: i64 = 256;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS commits (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        author TEXT,
+        body TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS commits_by_timestamp ON commits (timestamp);
+    CREATE INDEX IF NOT EXISTS commits_by_author ON commits (author, id);
+    CREATE TABLE IF NOT EXISTS commit_paths (
+        path TEXT NOT NULL,
+        commit_id INTEGER NOT NULL REFERENCES commits (id),
+        PRIMARY KEY (path, commit_id)
+    ) WITHOUT ROWID;
+";
+
+/// Commit history in an SQLite database.
+///
+/// Every commit is stored as JSON together with indexed columns for its
+/// timestamp and author and one row per changed path, so [`CommitQuery`]
+/// filters are answered without scanning the history.
+pub struct SqliteBackend {
+    path: PathBuf,
+    conn: Connection,
+    last_id: u64,
+    latest: Option<Commit>,
+}
+
+impl SqliteBackend {
+    /// Open the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let conn = Connection::open(&path).map_err(to_io_err)?;
+        conn.pragma_update(None, "journal_mode", "WAL").map_err(to_io_err)?;
+        // Commits must survive a power loss once acknowledged, as with the log.
+        conn.pragma_update(None, "synchronous", "FULL").map_err(to_io_err)?;
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(to_io_err)?;
+        if version > SCHEMA_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has unsupported schema version {}", path.display(), version),
+            ));
+        }
+        conn.execute_batch(SCHEMA).map_err(to_io_err)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION).map_err(to_io_err)?;
+        let latest: Option<String> = conn
+            .query_row("SELECT body FROM commits ORDER BY id DESC LIMIT 1", [], |row| row.get(0))
+            .optional()
+            .map_err(to_io_err)?;
+        let latest = latest.map(|b| decode(&b)).transpose()?;
+        Ok(Self {
+            path,
+            conn,
+            last_id: latest.as_ref().map(|c| c.id).unwrap_or(0),
+            latest,
+        })
+    }
+}
+
+impl CommitBackend for SqliteBackend {
+    /// Writes the whole batch in one transaction.
+    fn append(&mut self, commits: &[Commit]) -> io::Result<()> {
+        let tx = self.conn.transaction().map_err(to_io_err)?;
+        for commit in commits {
+            let body = serde_json::to_string(commit).map_err(to_io_err)?;
+            tx.execute(
+                "INSERT INTO commits (id, timestamp, author, body) VALUES (?1, ?2, ?3, ?4)",
+                params![to_sql_int(commit.id), to_sql_int(commit.timestamp), commit.author, body],
+            )
+            .map_err(to_io_err)?;
+            for change in &commit.changes {
+                tx.execute(
+                    "INSERT OR IGNORE INTO commit_paths (path, commit_id) VALUES (?1, ?2)",
+                    params![change.path, to_sql_int(commit.id)],
+                )
+                .map_err(to_io_err)?;
+            }
+        }
+        tx.commit().map_err(to_io_err)?;
+        if let Some(last) = commits.last() {
+            self.last_id = last.id;
+            self.latest = Some(last.clone());
+        }
+        Ok(())
+    }
+
+    fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
+    fn latest(&self) -> Option<Commit> {
+        self.latest.clone()
+    }
+
+    fn reader(&self) -> Arc<dyn CommitReader> {
+        Arc::new(SqliteReader {
+            path: self.path.clone(),
+        })
+    }
+}
+
+/// A reader of the database at `path` that never writes to it, so it can
+/// be used while a server is appending.
+pub fn reader(path: impl AsRef<Path>) -> impl CommitReader {
+    SqliteReader {
+        path: path.as_ref().to_owned(),
+    }
+}
+
+/// Reads the database over connections of its own, which see the
+/// transactions committed when each call starts.
+struct SqliteReader {
+    path: PathBuf,
+}
+
+impl SqliteReader {
+    fn connect(&self) -> io::Result<Connection> {
+        Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+            .map_err(to_io_err)
+    }
+}
+
+impl CommitReader for SqliteReader {
+    fn get(&self, id: u64) -> io::Result<Option<Commit>> {
+        let body: Option<String> = self
+            .connect()?
+            .query_row("SELECT body FROM commits WHERE id = ?1", [to_sql_int(id)], |row| row.get(0))
+            .optional()
+            .map_err(to_io_err)?;
+        body.map(|b| decode(&b)).transpose()
+    }
+
+    /// Reads the commits in batches, up to the newest commit at the time of
+    /// the call.
+    fn iter_after(&self, after: u64) -> io::Result<CommitIterator> {
+        let conn = self.connect()?;
+        let end: Option<i64> = conn
+            .query_row("SELECT max(id) FROM commits", [], |row| row.get(0))
+            .map_err(to_io_err)?;
+        let end = end.unwrap_or(0) as u64;
+        Ok(Box::new(SqliteIter {
+            conn,
+            next_after: after,
+            end,
+            batch: Vec::new().into_iter(),
+            done: after >= end,
+        }))
+    }
+
+    fn query(&self, query: &CommitQuery) -> io::Result<Vec<Commit>> {
+        let mut sql = String::from("SELECT body FROM commits WHERE id > ?");
+        let mut args = vec![to_sql_int(query.after)];
+        if let Some(path) = &query.path {
+            sql.push_str(" AND id IN (SELECT commit_id FROM commit_paths WHERE path = ?)");
+            args.push(Value::Text(path.clone()));
+        }
+        if let Some(author) = &query.author {
+            sql.push_str(" AND author = ?");
+            args.push(Value::Text(author.clone()));
+        }
+        if let Some(since) = query.since {
+            sql.push_str(" AND timestamp >= ?");
+            args.push(to_sql_int(since));
+        }
+        if let Some(until) = query.until {
+            sql.push_str(" AND timestamp < ?");
+            args.push(to_sql_int(until));
+        }
+        sql.push_str(" ORDER BY id");
+        if let Some(limit) = query.limit {
+            sql.push_str(" LIMIT ?");
+            args.push(to_sql_int(limit as u64));
+        }
+        let conn = self.connect()?;
+        let mut stmt = conn.prepare(&sql).map_err(to_io_err)?;
+        let bodies = stmt
+            .query_map(params_from_iter(args), |row| row.get::<_, String>(0))
+            .map_err(to_io_err)?;
+        bodies.map(|b| decode(&b.map_err(to_io_err)?)).collect()
+    }
+}
+
+/// Iterator returned by [`SqliteReader::iter_after`].
+struct SqliteIter {
+    conn: Connection,
+    next_after: u64,
+    end: u64,
+    batch: std::vec::IntoIter<Commit>,
+    done: bool,
+}
+
+impl SqliteIter {
+    fn fetch(&mut self) -> io::Result<Vec<Commit>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT body FROM commits WHERE id > ?1 AND id <= ?2 ORDER BY id LIMIT ?3")
+            .map_err(to_io_err)?;
+        let bodies = stmt
+            .query_map(params![to_sql_int(self.next_after), to_sql_int(self.end), BATCH_SIZE], |row| row.get::<_, String>(0))
+            .map_err(to_io_err)?;
+        bodies.map(|b| decode(&b.map_err(to_io_err)?)).collect()
+    }
+}
+
+impl Iterator for SqliteIter {
+    type Item = io::Result<Commit>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(commit) = self.batch.next() {
+                self.next_after = commit.id;
+                return Some(Ok(commit));
+            }
+            if self.done {
+                return None;
+            }
+            match self.fetch() {
+                Ok(batch) => {
+                    self.done = (batch.len() as i64) < BATCH_SIZE;
+                    self.batch = batch.into_iter();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn decode(body: &str) -> io::Result<Commit> {
+    serde_json::from_str(body).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("failed to decode commit: {}", e),
+        )
+    })
+}
+
+/// SQLite integers are signed; larger values cannot be stored, so clamping
+/// them keeps comparisons correct.
+fn to_sql_int(n: u64) -> Value {
+    Value::Integer(i64::try_from(n).unwrap_or(i64::MAX))
+}
+
+fn to_io_err<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::other(format!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::FileMode;
+    use crate::object_id::ObjectId;
+    use crate::server::Change;
+
+    fn commit(id: u64, path: &str, author: Option<&str>) -> Commit {
+        Commit {
+            id,
+            changes: vec![Change {
+                hash: ObjectId::from([id as u8; 32]),
+                path: path.into(),
+                timestamp: id * 10,
+                client: None,
+                mode: FileMode::Regular,
+            }],
+            timestamp: id * 10,
+            author: author.map(Into::into),
+        }
+    }
+
+    fn ids(commits: &[Commit]) -> Vec<u64> {
+        commits.iter().map(|c| c.id).collect()
+    }
+
+    #[test]
+    fn stores_and_reopens_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(COMMIT_DB_FILE);
+        let mut db = SqliteBackend::open(&path).unwrap();
+        assert_eq!(db.last_id(), 0);
+        assert_eq!(db.latest(), None);
+        db.append(&[commit(1, "a", None), commit(2, "b", Some("ann"))]).unwrap();
+        drop(db);
+
+        let mut db = SqliteBackend::open(&path).unwrap();
+        assert_eq!(db.last_id(), 2);
+        assert_eq!(db.latest(), Some(commit(2, "b", Some("ann"))));
+        let reader = db.reader();
+        assert_eq!(reader.get(1).unwrap(), Some(commit(1, "a", None)));
+        assert_eq!(reader.get(3).unwrap(), None);
+        // A failed batch leaves nothing behind.
+        assert!(db.append(&[commit(3, "c", None), commit(2, "c", None)]).is_err());
+        assert_eq!(db.last_id(), 2);
+        assert_eq!(reader.get(3).unwrap(), None);
+    }
+
+    #[test]
+    fn iterates_in_batches_up_to_the_newest_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = SqliteBackend::open(dir.path().join(COMMIT_DB_FILE)).unwrap();
+        let total = BATCH_SIZE as u64 * 2 + 3;
+        let commits: Vec<Commit> = (1..=total).map(|id| commit(id, "a", None)).collect();
+        db.append(&commits).unwrap();
+        let reader = db.reader();
+        let iter = reader.iter_after(5).unwrap();
+        db.append(&[commit(total + 1, "a", None)]).unwrap();
+        let seen: Vec<u64> = iter.map(|c| c.unwrap().id).collect();
+        assert_eq!(seen, (6..=total).collect::<Vec<_>>());
+        assert_eq!(reader.iter_after(total + 1).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn queries_by_path_author_and_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = SqliteBackend::open(dir.path().join(COMMIT_DB_FILE)).unwrap();
+        db.append(&[
+            commit(1, "a", Some("ann")),
+            commit(2, "b", Some("bob")),
+            commit(3, "a", Some("bob")),
+            commit(4, "a", None),
+        ])
+        .unwrap();
+
+        let reader = db.reader();
+        let query = |q: CommitQuery| ids(&reader.query(&q).unwrap());
+        let mut q = CommitQuery {
+            path: Some("a".into()),
+            ..Default::default()
+        };
+        assert_eq!(query(q.clone()), vec![1, 3, 4]);
+        q.author = Some("bob".into());
+        assert_eq!(query(q.clone()), vec![3]);
+        assert_eq!(
+            query(CommitQuery {
+                since: Some(20),
+                until: Some(40),
+                ..Default::default()
+            }),
+            vec![2, 3]
+        );
+        assert_eq!(
+            query(CommitQuery {
+                after: 1,
+                limit: Some(2),
+                ..Default::default()
+            }),
+            vec![2, 3]
+        );
+        assert_eq!(
+            query(CommitQuery {
+                path: Some("missing".into()),
+                ..Default::default()
+            }),
+            Vec::<u64>::new()
+        );
+    }
+}
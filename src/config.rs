@@ -0,0 +1,792 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Location of the repository configuration file.
+pub const CONFIG_PATH: &str = ".hit/config";
+
+/// Server used when no remote is configured.
+pub const DEFAULT_SERVER_URL: &str = "http://localhost:8888";
+
+/// Repository configuration stored as TOML in `.hit/config`.
+///
+/// Every section is optional; missing keys fall back to their defaults so an
+/// empty file (as created by `hit init`) is a valid configuration.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+    pub user: UserConfig,
+    /// Servers to sync with, by name, e.g. `[remote.origin]`.
+    pub remote: BTreeMap<String, RemoteConfig>,
+    pub sync: SyncConfig,
+    pub ignore: IgnoreConfig,
+    pub gc: GcConfig,
+    pub server: ServerConfig,
+}
+
+/// Who is making the changes in this working copy.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemoteConfig {
+    pub url: String,
+    /// Bearer token issued by the server with `hit serve token add`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// PEM file with the CA certificates to trust instead of the system's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<PathBuf>,
+    /// SHA-256 fingerprint the server's certificate must have.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<String>,
+}
+
+/// Settings for `hit watch` and `hit sync`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Name of the remote to send changes to and receive them from.
+    pub remote: String,
+    /// Longest wait between reconnection attempts.
+    pub max_backoff_secs: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            remote: "origin".into(),
+            max_backoff_secs: 30,
+        }
+    }
+}
+
+/// Files the watcher does not record. `.hit` is always ignored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IgnoreConfig {
+    /// File name endings, e.g. editor swap files.
+    pub suffixes: Vec<String>,
+    /// Files or directories relative to the repository root, e.g. `target`.
+    pub paths: Vec<String>,
+}
+
+impl Default for IgnoreConfig {
+    fn default() -> Self {
+        Self {
+            suffixes: crate::watcher::IGNORED_SUFFIXES.iter().map(|s| s.to_string()).collect(),
+            paths: Vec::new(),
+        }
+    }
+}
+
+impl IgnoreConfig {
+    /// Whether `path`, relative to the repository root, should be ignored.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let path = path.strip_prefix(".").unwrap_or(path);
+        if path.components().any(|c| c.as_os_str() == ".hit") {
+            return true;
+        }
+        if self.paths.iter().any(|p| path.starts_with(p.trim_end_matches('/'))) {
+            return true;
+        }
+        let name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+        self.suffixes.iter().any(|suffix| name.ends_with(suffix.as_str()))
+    }
+}
+
+/// Settings for `hit serve`.
+///
+/// Read from the `[server]` section of `.hit/config`, or from the file given
+/// with `hit serve --config`, which holds the same keys at the top level.
+/// `HIT_SERVER_*` environment variables override the file, e.g.
+/// `HIT_SERVER_BIND` for `bind`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address to listen on.
+    pub bind: String,
+    /// Directory holding the objects and commit history.
+    pub data_dir: PathBuf,
+    pub commit_backend: CommitBackendKind,
+    /// Change events buffered for each `/events` client; a client that falls
+    /// further behind skips events.
+    pub broadcast_capacity: usize,
+    /// Largest accepted JSON request body, such as a `POST /changes`.
+    pub max_request_bytes: usize,
+    /// Largest accepted object upload.
+    pub max_object_bytes: u64,
+    /// One of `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: String,
+    /// Accept requests without a token. Only meant for local testing.
+    pub allow_anonymous: bool,
+    /// Serve HTTPS with this certificate instead of plain HTTP.
+    pub tls: Option<TlsConfig>,
+    /// Role of authenticated users without an entry in `users`.
+    pub default_role: Role,
+    /// Access rules per user, e.g. `[server.users.ada]`.
+    pub users: BTreeMap<String, UserAccess>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:8888".into(),
+            data_dir: PathBuf::from(".hit"),
+            commit_backend: CommitBackendKind::default(),
+            broadcast_capacity: 100,
+            max_request_bytes: 1024 * 1024,
+            max_object_bytes: 64 * 1024 * 1024,
+            log_level: "info".into(),
+            allow_anonymous: false,
+            tls: None,
+            default_role: Role::default(),
+            users: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Load the server settings from `path`, or from the `[server]` section
+    /// of `.hit/config` if no path is given, and apply environment overrides.
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("cannot read {}: {}", path.display(), e))
+                })?;
+                toml::from_str(&text).map_err(|e| invalid_config(path, e))?
+            }
+            None => RepoConfig::load()?.server,
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Override settings from `HIT_SERVER_*` variables, looked up with `var`.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> io::Result<()> {
+        if let Some(v) = var("HIT_SERVER_BIND") {
+            self.bind = v;
+        }
+        if let Some(v) = var("HIT_SERVER_DATA_DIR") {
+            self.data_dir = PathBuf::from(v);
+        }
+        if let Some(v) = var("HIT_SERVER_COMMIT_BACKEND") {
+            self.commit_backend = parse_env("HIT_SERVER_COMMIT_BACKEND", &v)?;
+        }
+        if let Some(v) = var("HIT_SERVER_BROADCAST_CAPACITY") {
+            self.broadcast_capacity = parse_env("HIT_SERVER_BROADCAST_CAPACITY", &v)?;
+        }
+        if let Some(v) = var("HIT_SERVER_MAX_REQUEST_BYTES") {
+            self.max_request_bytes = parse_env("HIT_SERVER_MAX_REQUEST_BYTES", &v)?;
+        }
+        if let Some(v) = var("HIT_SERVER_MAX_OBJECT_BYTES") {
+            self.max_object_bytes = parse_env("HIT_SERVER_MAX_OBJECT_BYTES", &v)?;
+        }
+        if let Some(v) = var("HIT_SERVER_LOG_LEVEL") {
+            self.log_level = v;
+        }
+        if let Some(v) = var("HIT_SERVER_ALLOW_ANONYMOUS") {
+            self.allow_anonymous = parse_env("HIT_SERVER_ALLOW_ANONYMOUS", &v)?;
+        }
+        match (var("HIT_SERVER_TLS_CERT"), var("HIT_SERVER_TLS_KEY")) {
+            (Some(cert), Some(key)) => {
+                self.tls = Some(TlsConfig {
+                    cert: cert.into(),
+                    key: key.into(),
+                })
+            }
+            (None, None) => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "HIT_SERVER_TLS_CERT and HIT_SERVER_TLS_KEY must be set together",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject settings the server cannot run with.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        if self.broadcast_capacity == 0 {
+            return invalid("broadcast_capacity must be at least 1".into());
+        }
+        if self.log_level.parse::<tracing::Level>().is_err() {
+            return invalid(format!("unknown log_level {:?}", self.log_level));
+        }
+        for (user, access) in &self.users {
+            for prefix in &access.paths {
+                let path = Path::new(prefix);
+                if prefix.is_empty()
+                    || path.is_absolute()
+                    || path.components().any(|c| matches!(c, std::path::Component::ParentDir))
+                {
+                    return invalid(format!("invalid path {:?} for user {}", prefix, user));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The configured log level, `info` if it is not valid.
+    pub fn level(&self) -> tracing::Level {
+        self.log_level.parse().unwrap_or(tracing::Level::INFO)
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid value {:?} for {}", value, name),
+        )
+    })
+}
+
+/// Certificate chain and private key for serving HTTPS, both PEM files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// What a user may do on the server. Each role includes the ones before it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Receive changes and read history and objects.
+    Reader,
+    /// Also upload objects and commit changes.
+    #[default]
+    Writer,
+    /// Not limited by path rules.
+    Admin,
+}
+
+/// Access rules for one user of the server.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserAccess {
+    /// Defaults to the server's `default_role`.
+    pub role: Option<Role>,
+    /// Path prefixes the user may see and change, e.g. `docs/`. Empty means
+    /// the whole repository.
+    pub paths: Vec<String>,
+}
+
+/// Where the server keeps its commit history.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitBackendKind {
+    /// Append-only log file (`commits.log`).
+    #[default]
+    Log,
+    /// SQLite database (`commits.db`) with indexes for querying by path,
+    /// author and time.
+    Sqlite,
+    /// Nothing is persisted; history is lost when the server stops.
+    Memory,
+}
+
+impl FromStr for CommitBackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log" => Ok(Self::Log),
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            other => Err(format!("unknown commit backend {:?}", other)),
+        }
+    }
+}
+
+/// Settings for `hit gc`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    /// Unreachable objects younger than this are never pruned, so uploads
+    /// whose change has not been committed yet survive a concurrent gc.
+    pub grace_period_hours: u64,
+    pub retention: RetentionPolicy,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            grace_period_hours: 24,
+            retention: RetentionPolicy::default(),
+        }
+    }
+}
+
+/// How long recorded versions of a file are kept.
+///
+/// Every version is kept for `keep_all_days`, then the newest version per hour
+/// until `keep_hourly_days`, then the newest version per day until
+/// `keep_daily_days` (forever when unset). The newest version of each path is
+/// always kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    pub keep_all_days: u64,
+    pub keep_hourly_days: u64,
+    pub keep_daily_days: Option<u64>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            keep_all_days: 7,
+            keep_hourly_days: 30,
+            keep_daily_days: None,
+        }
+    }
+}
+
+impl RepoConfig {
+    /// Load the configuration of the repository in the current directory.
+    pub fn load() -> io::Result<Self> {
+        Self::load_from(CONFIG_PATH)
+    }
+
+    /// The server to sync with.
+    ///
+    /// `HIT_SERVER_URL`, looked up with `var`, overrides the remote selected
+    /// by `sync.remote`; without either, [`DEFAULT_SERVER_URL`] is used.
+    /// `HIT_SERVER_TOKEN`, `HIT_SERVER_CA` and `HIT_SERVER_PIN` override
+    /// the remote's other settings. A remote's settings are never used for a
+    /// URL given in the environment.
+    pub fn server(&self, var: impl Fn(&str) -> Option<String>) -> io::Result<RemoteConfig> {
+        let from_env = RemoteConfig {
+            url: var("HIT_SERVER_URL").unwrap_or_default(),
+            token: var("HIT_SERVER_TOKEN"),
+            ca: var("HIT_SERVER_CA").map(PathBuf::from),
+            pin: var("HIT_SERVER_PIN"),
+        };
+        if !from_env.url.is_empty() {
+            return Ok(from_env);
+        }
+        match self.remote.get(&self.sync.remote) {
+            Some(remote) => Ok(RemoteConfig {
+                url: remote.url.clone(),
+                token: from_env.token.or_else(|| remote.token.clone()),
+                ca: from_env.ca.or_else(|| remote.ca.clone()),
+                pin: from_env.pin.or_else(|| remote.pin.clone()),
+            }),
+            None if self.remote.is_empty() => Ok(RemoteConfig {
+                url: DEFAULT_SERVER_URL.into(),
+                ..from_env
+            }),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no remote named {:?}", self.sync.remote),
+            )),
+        }
+    }
+
+    /// Load a configuration file, returning defaults if it does not exist.
+    pub fn load_from(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = match fs::read_to_string(path.as_ref()) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        toml::from_str(&text).map_err(|e| invalid_config(path.as_ref(), e))
+    }
+}
+
+/// The server for the repository in the current directory, see
+/// [`RepoConfig::server`].
+pub fn server() -> io::Result<RemoteConfig> {
+    RepoConfig::load()?.server(|name| std::env::var(name).ok())
+}
+
+/// `.hit/config` as written by the user, for `hit config` and `hit remote`.
+///
+/// Keys are dotted paths such as `user.name` or `remote.origin.url`. Only
+/// keys present in the file are listed; every change is checked against
+/// [`RepoConfig`] before it is saved.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFile {
+    path: PathBuf,
+    table: toml::Table,
+}
+
+impl ConfigFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let table = match fs::read_to_string(&path) {
+            Ok(text) => text.parse().map_err(|e| invalid_config(&path, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => toml::Table::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, table })
+    }
+
+    /// The value of `key` as it appears in `list`, or `None` if unset.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut parts = key.split('.');
+        let mut value = self.table.get(parts.next()?)?;
+        for part in parts {
+            value = value.as_table()?.get(part)?;
+        }
+        match value {
+            toml::Value::Table(_) => None,
+            other => Some(display_value(other)),
+        }
+    }
+
+    /// Set `key` to `value`, read as a TOML value (`30`, `true`, `["a"]`)
+    /// and otherwise as a string.
+    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        let value = format!("v = {}", value)
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|mut t| t.remove("v"))
+            .unwrap_or_else(|| toml::Value::String(value.into()));
+        self.set_value(key, value)
+    }
+
+    fn set_value(&mut self, key: &str, value: toml::Value) -> io::Result<()> {
+        let (parent, name) = split_key(key)?;
+        let mut edited = self.clone();
+        let mut table = &mut edited.table;
+        for part in parent {
+            table = table
+                .entry(part)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| invalid_key(key))?;
+        }
+        table.insert(name.into(), value);
+        edited.validate()?;
+        *self = edited;
+        Ok(())
+    }
+
+    /// Remove `key`, which may name a whole section. Returns whether it was
+    /// set.
+    pub fn unset(&mut self, key: &str) -> io::Result<bool> {
+        let (parent, name) = split_key(key)?;
+        let mut table = &mut self.table;
+        for part in parent {
+            match table.get_mut(part).and_then(|v| v.as_table_mut()) {
+                Some(t) => table = t,
+                None => return Ok(false),
+            }
+        }
+        Ok(table.remove(name).is_some())
+    }
+
+    /// Every key set in the file with its value, sorted by key. Tokens are
+    /// masked; `get` shows them.
+    pub fn list(&self) -> Vec<(String, String)> {
+        fn walk(prefix: &str, table: &toml::Table, out: &mut Vec<(String, String)>) {
+            for (name, value) in table {
+                let key = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+                match value {
+                    toml::Value::Table(t) => walk(&key, t, out),
+                    _ if name == "token" => out.push((key, MASKED_TOKEN.into())),
+                    other => out.push((key, display_value(other))),
+                }
+            }
+        }
+        let mut out = Vec::new();
+        walk("", &self.table, &mut out);
+        out.sort();
+        out
+    }
+
+    /// The configuration this file describes.
+    pub fn config(&self) -> io::Result<RepoConfig> {
+        self.table
+            .clone()
+            .try_into()
+            .map_err(|e: toml::de::Error| invalid_config(&self.path, e))
+    }
+
+    pub fn add_remote(&mut self, name: &str, url: &str, token: Option<&str>) -> io::Result<()> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid remote name {:?}", name),
+            ));
+        }
+        if self.get(&format!("remote.{}.url", name)).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("remote {} already exists", name),
+            ));
+        }
+        let mut remote = toml::Table::new();
+        remote.insert("url".into(), url.into());
+        if let Some(token) = token {
+            remote.insert("token".into(), token.into());
+        }
+        self.set_value(&format!("remote.{}", name), toml::Value::Table(remote))
+    }
+
+    pub fn remove_remote(&mut self, name: &str) -> io::Result<()> {
+        if !self.unset(&format!("remote.{}", name))? {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no remote named {:?}", name),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        self.validate()?;
+        let text = toml::to_string(&self.table)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Remote tokens are kept here, so only the owner may read the file
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        io::Write::write_all(&mut options.open(&tmp)?, text.as_bytes())?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        self.config().map(|_| ())
+    }
+}
+
+fn split_key(key: &str) -> io::Result<(Vec<&str>, &str)> {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    if parts.iter().any(|p| p.is_empty()) {
+        return Err(invalid_key(key));
+    }
+    let name = parts.pop().ok_or_else(|| invalid_key(key))?;
+    Ok((parts, name))
+}
+
+fn invalid_key(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid config key {:?}", key))
+}
+
+/// Shown by [`ConfigFile::list`] in place of a token.
+const MASKED_TOKEN: &str = "********";
+
+/// Strings are shown without quotes; other values in TOML syntax.
+fn display_value(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn invalid_config(path: &Path, e: toml::de::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid config {}: {}", path.display(), e),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let cfg: RepoConfig = toml::from_str("").unwrap();
+        assert_eq!(cfg, RepoConfig::default());
+        assert_eq!(cfg.gc.retention.keep_all_days, 7);
+    }
+
+    #[test]
+    fn parses_retention_policy() {
+        let cfg: RepoConfig = toml::from_str(
+            "[gc]\ngrace_period_hours = 1\n[gc.retention]\nkeep_all_days = 2\nkeep_daily_days = 90\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.gc.grace_period_hours, 1);
+        assert_eq!(cfg.gc.retention.keep_all_days, 2);
+        assert_eq!(cfg.gc.retention.keep_hourly_days, 30);
+        assert_eq!(cfg.gc.retention.keep_daily_days, Some(90));
+    }
+
+    #[test]
+    fn parses_commit_backend() {
+        assert_eq!(RepoConfig::default().server.commit_backend, CommitBackendKind::Log);
+        let cfg: RepoConfig = toml::from_str("[server]\ncommit_backend = \"sqlite\"\n").unwrap();
+        assert_eq!(cfg.server.commit_backend, CommitBackendKind::Sqlite);
+        assert!(toml::from_str::<RepoConfig>("[server]\ncommit_backend = \"csv\"\n").is_err());
+    }
+
+    #[test]
+    fn server_file_env_and_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        fs::write(&path, "bind = \"127.0.0.1:9000\"\ndata_dir = \"/srv/hit\"\nmax_object_bytes = 10\n").unwrap();
+        let cfg = ServerConfig::load(Some(&path)).unwrap();
+        assert_eq!(cfg.bind, "127.0.0.1:9000");
+        assert_eq!(cfg.data_dir, PathBuf::from("/srv/hit"));
+        assert_eq!(cfg.max_object_bytes, 10);
+        assert_eq!(cfg.broadcast_capacity, 100);
+
+        let mut cfg = ServerConfig::default();
+        let env = |name: &str| match name {
+            "HIT_SERVER_BIND" => Some("[::1]:1".to_string()),
+            "HIT_SERVER_COMMIT_BACKEND" => Some("sqlite".to_string()),
+            "HIT_SERVER_BROADCAST_CAPACITY" => Some("7".to_string()),
+            _ => None,
+        };
+        cfg.apply_env(env).unwrap();
+        assert_eq!(cfg.bind, "[::1]:1");
+        assert_eq!(cfg.commit_backend, CommitBackendKind::Sqlite);
+        assert_eq!(cfg.broadcast_capacity, 7);
+        let bad = |name: &str| (name == "HIT_SERVER_MAX_REQUEST_BYTES").then(|| "lots".to_string());
+        assert!(cfg.apply_env(bad).is_err());
+
+        cfg.log_level = "verbose".into();
+        assert!(cfg.validate().is_err());
+        cfg.log_level = "debug".into();
+        assert_eq!(cfg.level(), tracing::Level::DEBUG);
+        cfg.broadcast_capacity = 0;
+        assert!(cfg.validate().is_err());
+    }
+
+    #[test]
+    fn resolves_server() {
+        let no_env = |_: &str| None;
+        let remote = |url: &str, token: Option<&str>| RemoteConfig {
+            url: url.into(),
+            token: token.map(Into::into),
+            ca: None,
+            pin: None,
+        };
+        let mut cfg = RepoConfig::default();
+        assert_eq!(cfg.server(no_env).unwrap(), remote(DEFAULT_SERVER_URL, None));
+        cfg.remote.insert("backup".into(), remote("http://b", Some("hit_b")));
+        assert!(cfg.server(no_env).is_err());
+        cfg.remote.insert("origin".into(), remote("http://o", None));
+        assert_eq!(cfg.server(no_env).unwrap(), remote("http://o", None));
+        cfg.sync.remote = "backup".into();
+        assert_eq!(cfg.server(no_env).unwrap(), remote("http://b", Some("hit_b")));
+        let env = |name: &str| (name == "HIT_SERVER_URL").then(|| "http://env".to_string());
+        assert_eq!(cfg.server(env).unwrap(), remote("http://env", None));
+        let env = |name: &str| (name == "HIT_SERVER_TOKEN").then(|| "hit_env".to_string());
+        assert_eq!(cfg.server(env).unwrap(), remote("http://b", Some("hit_env")));
+    }
+
+    #[test]
+    fn ignores_configured_paths() {
+        let ignore = IgnoreConfig {
+            paths: vec!["target/".into()],
+            ..IgnoreConfig::default()
+        };
+        assert!(ignore.is_ignored(Path::new(".hit/objects/ab")));
+        assert!(ignore.is_ignored(Path::new("target/debug/hit")));
+        assert!(ignore.is_ignored(Path::new("./target/debug/hit")));
+        assert!(ignore.is_ignored(Path::new("src/main.rs.swp")));
+        assert!(!ignore.is_ignored(Path::new("src/target.rs")));
+        assert!(!ignore.is_ignored(Path::new("targets/a")));
+    }
+
+    #[test]
+    fn edits_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        let mut file = ConfigFile::open(&path).unwrap();
+        file.set("user.name", "Ada Lovelace").unwrap();
+        file.set("sync.max_backoff_secs", "5").unwrap();
+        file.set("ignore.paths", "[\"target\"]").unwrap();
+        assert!(file.set("user.nickname", "ada").is_err());
+        assert!(file.set("sync.max_backoff_secs", "soon").is_err());
+        file.add_remote("origin", "http://example.com:8888", None).unwrap();
+        assert!(file.add_remote("origin", "http://other", None).is_err());
+        assert!(file.add_remote("a.b", "http://other", None).is_err());
+        file.save().unwrap();
+
+        let mut file = ConfigFile::open(&path).unwrap();
+        assert_eq!(file.get("user.name").as_deref(), Some("Ada Lovelace"));
+        assert_eq!(file.get("user"), None);
+        let cfg = file.config().unwrap();
+        assert_eq!(cfg.sync.max_backoff_secs, 5);
+        assert_eq!(cfg.ignore.paths, vec!["target".to_string()]);
+        assert_eq!(cfg.remote["origin"].url, "http://example.com:8888");
+        assert_eq!(
+            file.list(),
+            vec![
+                ("ignore.paths".to_string(), "[\"target\"]".to_string()),
+                ("remote.origin.url".to_string(), "http://example.com:8888".to_string()),
+                ("sync.max_backoff_secs".to_string(), "5".to_string()),
+                ("user.name".to_string(), "Ada Lovelace".to_string()),
+            ]
+        );
+        file.remove_remote("origin").unwrap();
+        assert!(file.remove_remote("origin").is_err());
+        assert!(file.unset("user.name").unwrap());
+        assert!(!file.unset("user.name").unwrap());
+    }
+
+    #[test]
+    fn keeps_tokens_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        fs::write(&path, "").unwrap();
+        let mut file = ConfigFile::open(&path).unwrap();
+        file.add_remote("origin", "http://example.com", Some("hit_secret")).unwrap();
+        file.save().unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        let file = ConfigFile::open(&path).unwrap();
+        assert_eq!(file.get("remote.origin.token").as_deref(), Some("hit_secret"));
+        assert_eq!(
+            file.list(),
+            vec![
+                ("remote.origin.token".to_string(), MASKED_TOKEN.to_string()),
+                ("remote.origin.url".to_string(), "http://example.com".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_access_rules() {
+        let cfg: RepoConfig = toml::from_str(
+            "[server]\ndefault_role = \"reader\"\n[server.users.ada]\nrole = \"writer\"\npaths = [\"docs/\"]\n[server.users.bob]\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.server.default_role, Role::Reader);
+        assert_eq!(cfg.server.users["ada"].role, Some(Role::Writer));
+        assert_eq!(cfg.server.users["ada"].paths, vec!["docs/".to_string()]);
+        assert_eq!(cfg.server.users["bob"], UserAccess::default());
+        cfg.server.validate().unwrap();
+        assert!(Role::Reader < Role::Writer && Role::Writer < Role::Admin);
+
+        let mut server = cfg.server;
+        server.users.get_mut("ada").unwrap().paths.push("docs/../src".into());
+        assert!(server.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<RepoConfig>("[gc]\ngrace = 1\n").is_err());
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::commit::{Commit, read_history};
+use crate::commit_log::{COMMIT_LOG_FILE, CommitLog, CorruptRecord, LogScan};
+use crate::config::{CommitBackendKind, RetentionPolicy, ServerConfig};
+use crate::gc::retained_hashes;
+use crate::hosting::hosted_dirs;
+use crate::object::{Header, Object, legacy_hash};
+use crate::object_id::ObjectId;
+use crate::repo::read_refs_in;
+use crate::storage::ObjectStore;
+
+/// Directory inside the repository's directory where `hit fsck --repair`
+/// moves corrupt and dangling items.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// An object file whose content does not match its name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptObject {
+    pub hash: ObjectId,
+    pub reason: String,
+}
+
+/// A reference to an object that is not present in the object store.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DanglingRef {
+    /// Where the reference was found, e.g. `commit 3 (src/lib.rs)`.
+    pub source: String,
+    /// Kept as text because refs may hold strings that are not valid ids.
+    pub hash: String,
+}
+
+/// Outcome of [`fsck`].
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub objects_checked: usize,
+    pub commits_checked: usize,
+    pub corrupt_objects: Vec<CorruptObject>,
+    pub corrupt_records: Vec<CorruptRecord>,
+    /// Offset of a torn record at the end of the commit log.
+    pub torn_tail: Option<u64>,
+    pub dangling: Vec<DanglingRef>,
+    /// Files written to the quarantine directory during repair.
+    pub quarantined: Vec<PathBuf>,
+}
+
+impl FsckReport {
+    /// Whether any problem was found.
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_objects.is_empty()
+            && self.corrupt_records.is_empty()
+            && self.torn_tail.is_none()
+            && self.dangling.is_empty()
+    }
+}
+
+/// Verify the integrity of the repository stored in `dir`, such as `.hit`
+/// or a server's data directory, whose history `backend` keeps.
+///
+/// Every object is re-hashed and compared to its file name, every commit is
+/// read, and every hash referenced from the history, refs or other objects
+/// must exist. Versions `policy` has released to `hit gc` may be missing
+/// without being reported. With `repair` set, corrupt objects are moved to
+/// [`QUARANTINE_DIR`] and a commit log is rewritten without corrupt records
+/// or commits that reference missing objects; the original log is kept in
+/// quarantine. Other backends cannot be rewritten, so their repair is
+/// refused when commits would have to be dropped.
+pub fn fsck(
+    dir: &Path,
+    backend: CommitBackendKind,
+    policy: &RetentionPolicy,
+    repair: bool,
+) -> io::Result<FsckReport> {
+    let objects = ObjectStore::in_dir(dir);
+    let mut report = FsckReport::default();
+
+    let mut present = HashSet::new();
+    let mut references = Vec::new();
+    for hash in objects.list_objects()? {
+        report.objects_checked += 1;
+        match verify_object(&objects, &hash) {
+            Ok(children) => {
+                references.extend(children.into_iter().map(|c| (hash, c)));
+                present.insert(hash);
+            }
+            Err(reason) => {
+                warn!(%hash, reason, "corrupt object");
+                report.corrupt_objects.push(CorruptObject { hash, reason });
+            }
+        }
+    }
+    for (parent, hash) in references {
+        if !present.contains(&hash) {
+            let source = format!("object {}", parent);
+            warn!(source, %hash, "dangling reference");
+            report.dangling.push(DanglingRef { source, hash: hash.to_hex() });
+        }
+    }
+
+    // Damaged records can only be told apart from good ones in a log
+    let scan = match backend {
+        CommitBackendKind::Log => CommitLog::scan(dir.join(COMMIT_LOG_FILE))?,
+        _ => LogScan {
+            commits: read_history(backend, dir)?,
+            ..LogScan::default()
+        },
+    };
+    report.commits_checked = scan.commits.len();
+    report.corrupt_records = scan.corrupt;
+    report.torn_tail = scan.torn_tail;
+    for bad in &report.corrupt_records {
+        warn!(offset = bad.offset, reason = %bad.reason, "corrupt commit record");
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?
+        .as_secs();
+    let retained = retained_hashes(&scan.commits, policy, now);
+
+    let mut kept: Vec<Commit> = Vec::with_capacity(scan.commits.len());
+    for commit in scan.commits {
+        let missing: Vec<DanglingRef> = commit
+            .changes
+            .iter()
+            .filter(|c| retained.contains(&c.hash) && !present.contains(&c.hash))
+            .map(|c| DanglingRef {
+                source: format!("commit {} ({})", commit.id, c.path),
+                hash: c.hash.to_hex(),
+            })
+            .collect();
+        if missing.is_empty() {
+            kept.push(commit);
+        } else {
+            for d in &missing {
+                warn!(source = %d.source, hash = %d.hash, "dangling reference");
+            }
+            report.dangling.extend(missing);
+        }
+    }
+
+    for (name, hash) in read_refs_in(dir)? {
+        let found = hash.parse::<ObjectId>().is_ok_and(|id| present.contains(&id));
+        if !found {
+            warn!(reference = %name, hash, "dangling reference");
+            report.dangling.push(DanglingRef { source: name, hash });
+        }
+    }
+
+    if repair {
+        repair_repository(dir, backend, &mut report, &kept)?;
+    }
+    Ok(report)
+}
+
+/// Run [`fsck`] on the server's repository in its data directory and on
+/// every repository it hosts there. Each report is labelled with the name
+/// of the hosted repository, `None` for the server's own.
+pub fn fsck_server(
+    server: &ServerConfig,
+    policy: &RetentionPolicy,
+    repair: bool,
+) -> io::Result<Vec<(Option<String>, FsckReport)>> {
+    let mut reports = vec![(None, fsck(&server.data_dir, server.commit_backend, policy, repair)?)];
+    for (name, dir) in hosted_dirs(&server.data_dir)? {
+        let report = fsck(&dir, server.commit_backend, policy, repair)?;
+        reports.push((Some(name), report));
+    }
+    Ok(reports)
+}
+
+/// Check an object against its name and return the hashes it references.
+///
+/// Objects in the legacy bincode layout are checked against their legacy id.
+fn verify_object(objects: &ObjectStore, hash: &ObjectId) -> Result<Vec<ObjectId>, String> {
+    let bytes = fs::read(objects.object_path(hash)).map_err(|e| e.to_string())?;
+    let obj = Object::decode(&bytes).map_err(|e| e.to_string())?;
+    let actual = if Header::parse(&bytes).is_some() {
+        ObjectId::from(Sha256::digest(&bytes))
+    } else {
+        legacy_hash(&obj)
+    };
+    if actual != *hash {
+        return Err(format!("content hashes to {}", actual));
+    }
+    Ok(obj.references())
+}
+
+fn repair_repository(
+    dir: &Path,
+    backend: CommitBackendKind,
+    report: &mut FsckReport,
+    kept: &[Commit],
+) -> io::Result<()> {
+    let log_damaged = !report.corrupt_records.is_empty()
+        || report.torn_tail.is_some()
+        || kept.len() != report.commits_checked;
+    if log_damaged && backend != CommitBackendKind::Log {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only the log commit backend can drop damaged commits; restore the missing objects instead",
+        ));
+    }
+
+    let store = ObjectStore::in_dir(dir);
+    let quarantine = dir.join(QUARANTINE_DIR);
+    let objects = quarantine.join("objects");
+    for bad in &report.corrupt_objects {
+        fs::create_dir_all(&objects)?;
+        let dest = objects.join(bad.hash.to_hex());
+        fs::rename(store.object_path(&bad.hash), &dest)?;
+        info!(hash = %bad.hash, dest = %dest.display(), "quarantined corrupt object");
+        report.quarantined.push(dest);
+    }
+
+    if log_damaged {
+        let log = dir.join(COMMIT_LOG_FILE);
+        fs::create_dir_all(&quarantine)?;
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_secs();
+        let dest = quarantine.join(format!("commits.log.{}", stamp));
+        fs::copy(&log, &dest)?;
+        CommitLog::rewrite(&log, kept)?;
+        info!(dest = %dest.display(), kept = kept.len(), "rewrote commit log");
+        report.quarantined.push(dest);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commit::CommitBackend;
+    use crate::commit_sqlite::{COMMIT_DB_FILE, SqliteBackend};
+    use crate::object::{Blob, FileMode, Hashable};
+    use crate::server::Change;
+
+    fn commit(id: u64, hash: ObjectId) -> Commit {
+        Commit {
+            id,
+            changes: vec![Change { hash, path: "f".into(), timestamp: id, client: None, mode: FileMode::Regular }],
+            timestamp: id,
+            author: None,
+        }
+    }
+
+    fn blob(objects: &ObjectStore, content: &[u8]) -> ObjectId {
+        objects.write_object(&Object::Blob(Blob { content: content.to_vec() })).unwrap()
+    }
+
+    fn fsck_log(dir: &Path, repair: bool) -> FsckReport {
+        fsck(dir, CommitBackendKind::Log, &RetentionPolicy::default(), repair).unwrap()
+    }
+
+    #[test]
+    fn clean_repository_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = blob(&ObjectStore::in_dir(dir.path()), b"ok");
+        let mut log = CommitLog::open(dir.path().join(COMMIT_LOG_FILE)).unwrap();
+        log.append(&commit(1, hash)).unwrap();
+        drop(log);
+
+        let report = fsck_log(dir.path(), false);
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(report.objects_checked, 1);
+        assert_eq!(report.commits_checked, 1);
+    }
+
+    #[test]
+    fn detects_and_quarantines_problems() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::in_dir(dir.path());
+        let good = blob(&objects, b"good");
+        let bad = blob(&objects, b"bad");
+        fs::write(objects.object_path(&bad), b"garbage").unwrap();
+        let log_path = dir.path().join(COMMIT_LOG_FILE);
+        let mut log = CommitLog::open(&log_path).unwrap();
+        let missing = ObjectId::from([0; 32]);
+        log.append(&commit(1, good)).unwrap();
+        log.append(&commit(2, missing)).unwrap();
+        drop(log);
+
+        let report = fsck_log(dir.path(), false);
+        assert_eq!(report.corrupt_objects.len(), 1);
+        assert_eq!(report.corrupt_objects[0].hash, bad);
+        assert_eq!(report.dangling.len(), 1);
+        assert_eq!(report.dangling[0].hash, missing.to_hex());
+        assert!(report.quarantined.is_empty());
+
+        let report = fsck_log(dir.path(), true);
+        assert_eq!(report.quarantined.len(), 2);
+        assert!(report.quarantined.iter().all(|p| p.starts_with(dir.path().join(QUARANTINE_DIR))));
+        assert!(!objects.object_path(&bad).exists());
+        assert_eq!(CommitLog::load(&log_path).unwrap(), vec![commit(1, good)]);
+
+        assert!(fsck_log(dir.path(), false).is_clean());
+    }
+
+    #[test]
+    fn checks_sqlite_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = blob(&ObjectStore::in_dir(dir.path()), b"good");
+        let mut db = SqliteBackend::open(dir.path().join(COMMIT_DB_FILE)).unwrap();
+        db.append(&[commit(1, good)]).unwrap();
+        let check = |repair| fsck(dir.path(), CommitBackendKind::Sqlite, &RetentionPolicy::default(), repair);
+
+        let report = check(false).unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(report.commits_checked, 1);
+
+        let missing = ObjectId::from([0; 32]);
+        db.append(&[commit(2, missing)]).unwrap();
+        let report = check(false).unwrap();
+        assert_eq!(report.dangling.len(), 1);
+        assert_eq!(report.dangling[0].hash, missing.to_hex());
+        // The database cannot be rewritten without the commit
+        assert_eq!(check(true).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn visits_hosted_repositories() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosted = dir.path().join(crate::hosting::REPOS_DIR).join("docs");
+        let objects = ObjectStore::in_dir(&hosted);
+        let bad = blob(&objects, b"bad");
+        fs::write(objects.object_path(&bad), b"garbage").unwrap();
+        let server = ServerConfig {
+            data_dir: dir.path().into(),
+            ..ServerConfig::default()
+        };
+
+        let reports = fsck_server(&server, &RetentionPolicy::default(), false).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0].1.is_clean());
+        assert_eq!(reports[1].0.as_deref(), Some("docs"));
+        assert_eq!(reports[1].1.corrupt_objects.len(), 1);
+    }
+
+    #[test]
+    fn accepts_legacy_objects_under_legacy_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::in_dir(dir.path());
+        let obj = Object::Blob(Blob { content: b"legacy".to_vec() });
+        fs::create_dir_all(objects.dir()).unwrap();
+        fs::write(
+            objects.object_path(&legacy_hash(&obj)),
+            bincode::serialize(&obj).unwrap(),
+        )
+        .unwrap();
+        fs::write(objects.object_path(&obj.hash()), bincode::serialize(&obj).unwrap()).unwrap();
+
+        let report = fsck_log(dir.path(), false);
+        assert_eq!(report.objects_checked, 2);
+        assert_eq!(report.corrupt_objects.len(), 1);
+        assert_eq!(report.corrupt_objects[0].hash, obj.hash());
+    }
+}
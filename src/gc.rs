@@ -0,0 +1,356 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{info, warn};
+
+use crate::commit::{Commit, read_history};
+use crate::config::{CommitBackendKind, GcConfig, RetentionPolicy, ServerConfig};
+use crate::hosting::hosted_dirs;
+use crate::object_id::ObjectId;
+use crate::outbox::{OUTBOX_FILE, Outbox};
+use crate::repo::read_refs_in;
+use crate::storage::ObjectStore;
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// Outcome of [`gc`].
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub objects_checked: usize,
+    pub reachable: usize,
+    /// Unreachable objects still inside the grace period.
+    pub kept_recent: usize,
+    pub pruned: Vec<ObjectId>,
+    pub bytes_freed: u64,
+}
+
+/// Select the object hashes the retention policy keeps from the commit history.
+///
+/// Versions are grouped by path and aged by their commit timestamp relative to
+/// `now` (seconds since the epoch).
+pub fn retained_hashes(commits: &[Commit], policy: &RetentionPolicy, now: u64) -> HashSet<ObjectId> {
+    let mut by_path: HashMap<&str, Vec<(u64, ObjectId)>> = HashMap::new();
+    for commit in commits {
+        for change in &commit.changes {
+            by_path
+                .entry(change.path.as_str())
+                .or_default()
+                .push((commit.timestamp, change.hash));
+        }
+    }
+
+    let mut keep = HashSet::new();
+    for versions in by_path.values_mut() {
+        // Newest first, so the first version seen in a bucket is the one kept.
+        versions.sort_by_key(|v| std::cmp::Reverse(v.0));
+        let mut hours = HashSet::new();
+        let mut days = HashSet::new();
+        for (i, &(timestamp, hash)) in versions.iter().enumerate() {
+            let age = now.saturating_sub(timestamp);
+            let kept = if i == 0 || age <= policy.keep_all_days * DAY {
+                true
+            } else if age <= policy.keep_hourly_days * DAY {
+                hours.insert(timestamp / HOUR)
+            } else if policy.keep_daily_days.is_none_or(|d| age <= d * DAY) {
+                days.insert(timestamp / DAY)
+            } else {
+                false
+            };
+            if kept {
+                keep.insert(hash);
+            }
+        }
+    }
+    keep
+}
+
+/// Compute the set of objects in `dir` reachable from its refs, the changes
+/// waiting in its outbox and the retained part of `commits`, following
+/// references such as the chunks listed in a manifest.
+pub fn reachable_objects(
+    dir: &Path,
+    commits: &[Commit],
+    policy: &RetentionPolicy,
+    now: u64,
+) -> io::Result<HashSet<ObjectId>> {
+    let objects = ObjectStore::in_dir(dir);
+    let mut reachable = retained_hashes(commits, policy, now);
+    for (name, hash) in read_refs_in(dir)? {
+        match hash.parse() {
+            Ok(hash) => {
+                reachable.insert(hash);
+            }
+            Err(e) => warn!(reference = %name, %e, "ignoring invalid ref"),
+        }
+    }
+    // Not committed yet, but the watcher still has to upload them
+    for change in Outbox::at(dir.join(OUTBOX_FILE)).pending()? {
+        reachable.insert(change.hash);
+    }
+
+    let mut pending: Vec<ObjectId> = reachable.iter().copied().collect();
+    while let Some(hash) = pending.pop() {
+        if !objects.object_path(&hash).exists() {
+            continue;
+        }
+        for child in objects.read_references(&hash)? {
+            if reachable.insert(child) {
+                pending.push(child);
+            }
+        }
+    }
+    Ok(reachable)
+}
+
+/// Prune unreachable objects from the repository stored in `dir`, such as
+/// `.hit` or a server's data directory, whose history `backend` keeps.
+///
+/// Objects are roots if a ref points at them, a change in the outbox records
+/// them or the retention policy in `config` keeps the commit that recorded
+/// them. Unreachable objects are
+/// only removed once their file is older than the configured grace period.
+/// With `dry_run` set nothing is deleted, but the report lists what would be.
+pub fn gc(dir: &Path, backend: CommitBackendKind, config: &GcConfig, dry_run: bool) -> io::Result<GcReport> {
+    let objects = ObjectStore::in_dir(dir);
+    let commits = read_history(backend, dir)?;
+    let now = SystemTime::now();
+    let now_secs = now
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?
+        .as_secs();
+    let grace = Duration::from_secs(config.grace_period_hours * HOUR);
+    let reachable = reachable_objects(dir, &commits, &config.retention, now_secs)?;
+
+    let mut report = GcReport::default();
+    for hash in objects.list_objects()? {
+        report.objects_checked += 1;
+        if reachable.contains(&hash) {
+            report.reachable += 1;
+            continue;
+        }
+        let path = objects.object_path(&hash);
+        let meta = fs::metadata(&path)?;
+        let age = now.duration_since(meta.modified()?).unwrap_or_default();
+        if age < grace {
+            report.kept_recent += 1;
+            continue;
+        }
+        if !dry_run {
+            fs::remove_file(&path)?;
+            info!(%hash, "pruned object");
+        }
+        report.bytes_freed += meta.len();
+        report.pruned.push(hash);
+    }
+    Ok(report)
+}
+
+/// Run [`gc`] on the server's repository in its data directory and on every
+/// repository it hosts there. Each report is labelled with the name of the
+/// hosted repository, `None` for the server's own.
+pub fn gc_server(
+    server: &ServerConfig,
+    config: &GcConfig,
+    dry_run: bool,
+) -> io::Result<Vec<(Option<String>, GcReport)>> {
+    let mut reports = vec![(None, gc(&server.data_dir, server.commit_backend, config, dry_run)?)];
+    for (name, dir) in hosted_dirs(&server.data_dir)? {
+        let report = gc(&dir, server.commit_backend, config, dry_run)?;
+        reports.push((Some(name), report));
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commit::CommitBackend;
+    use crate::commit_log::{COMMIT_LOG_FILE, CommitLog};
+    use crate::commit_sqlite::{COMMIT_DB_FILE, SqliteBackend};
+    use crate::object::{Blob, ChunkedBlob, FileMode, Object};
+    use crate::server::Change;
+    use sha2::{Digest, Sha256};
+
+    const NOW: u64 = 1_000 * DAY + 12 * HOUR + 1_800;
+
+    fn commit(id: u64, path: &str, hash: ObjectId, timestamp: u64) -> Commit {
+        Commit {
+            id,
+            changes: vec![Change { hash, path: path.into(), timestamp, client: None, mode: FileMode::Regular }],
+            timestamp,
+            author: None,
+        }
+    }
+
+    /// Stand-in id for a named version.
+    fn id(name: &str) -> ObjectId {
+        ObjectId::from(Sha256::digest(name))
+    }
+
+    #[test]
+    fn keeps_recent_versions_and_thins_older_ones() {
+        let policy = RetentionPolicy {
+            keep_all_days: 1,
+            keep_hourly_days: 2,
+            keep_daily_days: Some(10),
+        };
+        let commits = vec![
+            // older than keep_daily_days: dropped
+            commit(1, "f", id("ancient"), NOW - 20 * DAY),
+            // same day, daily window: only the newest survives
+            commit(2, "f", id("daily-old"), NOW - 5 * DAY - 10),
+            commit(3, "f", id("daily-new"), NOW - 5 * DAY),
+            // same hour, hourly window
+            commit(4, "f", id("hourly-old"), NOW - DAY - 2 * HOUR - 10),
+            commit(5, "f", id("hourly-new"), NOW - DAY - 2 * HOUR),
+            // inside keep_all: everything
+            commit(6, "f", id("recent-1"), NOW - 20),
+            commit(7, "f", id("recent-2"), NOW - 10),
+            // the newest version of a path is kept regardless of age
+            commit(8, "g", id("only"), NOW - 100 * DAY),
+        ];
+        let kept = retained_hashes(&commits, &policy, NOW);
+        let expected: HashSet<ObjectId> = ["daily-new", "hourly-new", "recent-1", "recent-2", "only"]
+            .into_iter()
+            .map(id)
+            .collect();
+        assert_eq!(kept, expected);
+    }
+
+    fn blob(objects: &ObjectStore, content: &[u8]) -> ObjectId {
+        objects.write_object(&Object::Blob(Blob { content: content.to_vec() })).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn prunes_only_unreachable_objects_past_grace() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::in_dir(dir.path());
+        let kept = blob(&objects, b"kept");
+        let garbage = blob(&objects, b"garbage");
+        let mut log = CommitLog::open(dir.path().join(COMMIT_LOG_FILE)).unwrap();
+        log.append(&commit(1, "f", kept, now())).unwrap();
+        drop(log);
+        let gc = |config: &GcConfig, dry_run| gc(dir.path(), CommitBackendKind::Log, config, dry_run).unwrap();
+
+        // Default grace period protects the fresh garbage object.
+        let report = gc(&GcConfig::default(), false);
+        assert_eq!(report.reachable, 1);
+        assert_eq!(report.kept_recent, 1);
+        assert!(report.pruned.is_empty());
+
+        let config = GcConfig {
+            grace_period_hours: 0,
+            ..GcConfig::default()
+        };
+        let report = gc(&config, true);
+        assert_eq!(report.pruned, vec![garbage]);
+        assert!(objects.object_path(&garbage).exists());
+
+        let report = gc(&config, false);
+        assert_eq!(report.pruned, vec![garbage]);
+        assert!(!objects.object_path(&garbage).exists());
+        assert!(objects.object_path(&kept).exists());
+    }
+
+    #[test]
+    fn reads_history_from_sqlite() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::in_dir(dir.path());
+        let kept = blob(&objects, b"kept");
+        let garbage = blob(&objects, b"garbage");
+        let mut db = SqliteBackend::open(dir.path().join(COMMIT_DB_FILE)).unwrap();
+        db.append(&[commit(1, "f", kept, now())]).unwrap();
+        let config = GcConfig {
+            grace_period_hours: 0,
+            ..GcConfig::default()
+        };
+
+        let report = gc(dir.path(), CommitBackendKind::Sqlite, &config, false).unwrap();
+        assert_eq!(report.reachable, 1);
+        assert_eq!(report.pruned, vec![garbage]);
+        assert!(objects.object_path(&kept).exists());
+
+        let err = gc(dir.path(), CommitBackendKind::Memory, &config, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(objects.object_path(&kept).exists());
+    }
+
+    #[test]
+    fn visits_hosted_repositories() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosted = dir.path().join(crate::hosting::REPOS_DIR).join("docs");
+        let objects = ObjectStore::in_dir(&hosted);
+        let kept = blob(&objects, b"kept");
+        let garbage = blob(&objects, b"garbage");
+        let mut log = CommitLog::open(hosted.join(COMMIT_LOG_FILE)).unwrap();
+        log.append(&commit(1, "f", kept, now())).unwrap();
+        drop(log);
+        let server = ServerConfig {
+            data_dir: dir.path().into(),
+            ..ServerConfig::default()
+        };
+        let config = GcConfig {
+            grace_period_hours: 0,
+            ..GcConfig::default()
+        };
+
+        let reports = gc_server(&server, &config, false).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].0, None);
+        assert_eq!(reports[0].1.objects_checked, 0);
+        assert_eq!(reports[1].0.as_deref(), Some("docs"));
+        assert_eq!(reports[1].1.pruned, vec![garbage]);
+        assert!(objects.object_path(&kept).exists());
+    }
+
+    #[test]
+    fn keeps_objects_of_pending_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::in_dir(dir.path());
+        let chunk = blob(&objects, b"chunk");
+        let manifest = objects
+            .write_object(&Object::Chunked(ChunkedBlob {
+                size: 5,
+                chunks: vec![chunk],
+            }))
+            .unwrap();
+        let change = Change { hash: manifest, path: "big".into(), timestamp: now(), client: None, mode: FileMode::Regular };
+        Outbox::at(dir.path().join(OUTBOX_FILE)).push(&change).unwrap();
+        let config = GcConfig {
+            grace_period_hours: 0,
+            ..GcConfig::default()
+        };
+
+        let report = gc(dir.path(), CommitBackendKind::Log, &config, false).unwrap();
+        assert_eq!(report.reachable, 2);
+        assert!(report.pruned.is_empty());
+        assert!(objects.object_path(&chunk).exists());
+    }
+
+    #[test]
+    fn chunks_of_retained_manifests_are_reachable() {
+        let dir = tempfile::tempdir().unwrap();
+        let objects = ObjectStore::in_dir(dir.path());
+        let chunk = blob(&objects, b"chunk");
+        let manifest = objects
+            .write_object(&Object::Chunked(ChunkedBlob {
+                size: 5,
+                chunks: vec![chunk],
+            }))
+            .unwrap();
+        let now = now();
+        let commits = vec![commit(1, "big", manifest, now)];
+
+        let reachable = reachable_objects(dir.path(), &commits, &RetentionPolicy::default(), now).unwrap();
+        assert!(reachable.contains(&manifest));
+        assert!(reachable.contains(&chunk));
+    }
+}
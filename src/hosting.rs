@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use axum::extract::{Path as UrlPath, Request, State};
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, delete, get};
+use axum::{Extension, Json, Router, middleware};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast;
+use tower_service::Service;
+
+use crate::auth::Permissions;
+use crate::commit::CommitStore;
+use crate::config::{CommitBackendKind, Role};
+use crate::server::{self, AppState, ReadableObjects};
+use crate::storage::ObjectStore;
+
+/// Directory under the server's data directory holding one directory per
+/// hosted repository.
+pub const REPOS_DIR: &str = "repos";
+
+/// The named repositories of a server, each with its own commit history,
+/// objects and event stream.
+///
+/// Repository `name` is served under `/repos/<name>`, with the same routes
+/// the server's default repository has at the top level, so clients use
+/// `https://host/repos/<name>` as their remote URL. Tokens and access rules
+/// are shared by all repositories.
+pub struct Repos {
+    dir: PathBuf,
+    backend: CommitBackendKind,
+    broadcast_capacity: usize,
+    /// State of the default repository; hosted repositories share its
+    /// limits, tokens and access rules.
+    template: AppState,
+    hosted: RwLock<BTreeMap<String, Router>>,
+}
+
+/// A hosted repository, as listed by `GET /repos`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RepoInfo {
+    pub name: String,
+}
+
+impl Repos {
+    /// Open every repository under `data_dir/repos`.
+    pub fn open(
+        data_dir: impl AsRef<Path>,
+        backend: CommitBackendKind,
+        broadcast_capacity: usize,
+        template: AppState,
+    ) -> io::Result<Self> {
+        let repos = Self {
+            dir: data_dir.as_ref().join(REPOS_DIR),
+            backend,
+            broadcast_capacity,
+            template,
+            hosted: RwLock::new(BTreeMap::new()),
+        };
+        for (name, _) in hosted_dirs(data_dir)? {
+            let router = repos.load(&name)?;
+            repos.write_hosted()?.insert(name, router);
+        }
+        Ok(repos)
+    }
+
+    pub fn list(&self) -> io::Result<Vec<RepoInfo>> {
+        Ok(self
+            .read_hosted()?
+            .keys()
+            .map(|name| RepoInfo { name: name.clone() })
+            .collect())
+    }
+
+    /// Create an empty repository called `name`.
+    pub fn create(&self, name: &str) -> io::Result<()> {
+        if !valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid repository name {:?}", name),
+            ));
+        }
+        let mut hosted = self.write_hosted()?;
+        if hosted.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("repository {} already exists", name),
+            ));
+        }
+        let router = self.load(name)?;
+        hosted.insert(name.into(), router);
+        tracing::info!(repo = name, "created repository");
+        Ok(())
+    }
+
+    /// Stop serving repository `name` and delete its history and objects.
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        if self.write_hosted()?.remove(name).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no repository named {:?}", name),
+            ));
+        }
+        std::fs::remove_dir_all(self.dir.join(name))?;
+        tracing::info!(repo = name, "deleted repository");
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> io::Result<Option<Router>> {
+        Ok(self.read_hosted()?.get(name).cloned())
+    }
+
+    /// Open the storage of repository `name`, creating it if needed, and
+    /// build its router.
+    fn load(&self, name: &str) -> io::Result<Router> {
+        let dir = self.dir.join(name);
+        let objects = ObjectStore::in_dir(&dir);
+        std::fs::create_dir_all(objects.dir())?;
+        let commits = CommitStore::open(self.backend, &dir).map_err(|e| io::Error::other(e.to_string()))?;
+        let (broadcaster, _) = broadcast::channel(self.broadcast_capacity);
+        Ok(server::app(AppState {
+            commits,
+            broadcaster,
+            objects,
+            readable: ReadableObjects::default(),
+            ..self.template.clone()
+        }))
+    }
+
+    fn read_hosted(&self) -> io::Result<std::sync::RwLockReadGuard<'_, BTreeMap<String, Router>>> {
+        self.hosted.read().map_err(|_| io::Error::other("repository table lock poisoned"))
+    }
+
+    fn write_hosted(&self) -> io::Result<std::sync::RwLockWriteGuard<'_, BTreeMap<String, Router>>> {
+        self.hosted.write().map_err(|_| io::Error::other("repository table lock poisoned"))
+    }
+}
+
+/// Name and directory of every repository hosted under `data_dir`, in
+/// name order, for tools that maintain each of them.
+pub fn hosted_dirs(data_dir: impl AsRef<Path>) -> io::Result<Vec<(String, PathBuf)>> {
+    let dir = data_dir.as_ref().join(REPOS_DIR);
+    let mut hosted = Vec::new();
+    if !dir.exists() {
+        return Ok(hosted);
+    }
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        if entry.file_type()?.is_dir() && valid_name(&name) {
+            hosted.push((name, entry.path()));
+        }
+    }
+    hosted.sort();
+    Ok(hosted)
+}
+
+/// Names become directory names and URL segments, so they are kept to
+/// letters, digits, `-` and `_`.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CreateRepo {
+    name: String,
+}
+
+async fn list_handler(State(repos): State<Arc<Repos>>) -> Response {
+    match repos.list() {
+        Ok(list) => Json(list).into_response(),
+        Err(e) => {
+            tracing::error!("failed to list repositories: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn create_handler(
+    State(repos): State<Arc<Repos>>,
+    Extension(permissions): Extension<Permissions>,
+    Json(body): Json<CreateRepo>,
+) -> Response {
+    if permissions.role < Role::Admin {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let result = tokio::task::spawn_blocking(move || repos.create(&body.name)).await;
+    match result {
+        Ok(Ok(())) => (StatusCode::CREATED, Json(json!({"created": true}))).into_response(),
+        Ok(Err(e)) => error_response("create", e),
+        Err(e) => {
+            tracing::error!("repository task failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn delete_handler(
+    State(repos): State<Arc<Repos>>,
+    Extension(permissions): Extension<Permissions>,
+    UrlPath(name): UrlPath<String>,
+) -> Response {
+    if permissions.role < Role::Admin {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let result = tokio::task::spawn_blocking(move || repos.delete(&name)).await;
+    match result {
+        Ok(Ok(())) => StatusCode::NO_CONTENT.into_response(),
+        Ok(Err(e)) => error_response("delete", e),
+        Err(e) => {
+            tracing::error!("repository task failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn error_response(action: &str, e: io::Error) -> Response {
+    let status = match e.kind() {
+        io::ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        io::ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if status == StatusCode::INTERNAL_SERVER_ERROR {
+        tracing::error!("failed to {} repository: {}", action, e);
+    } else {
+        tracing::warn!("cannot {} repository: {}", action, e);
+    }
+    (status, e.to_string()).into_response()
+}
+
+/// Hand a request for `/repos/<name>/<rest>` to the router of repository
+/// `name` as a request for `/<rest>`. That router authenticates it.
+async fn repo_handler(
+    State(repos): State<Arc<Repos>>,
+    UrlPath((name, rest)): UrlPath<(String, String)>,
+    request: Request,
+) -> Response {
+    let mut router = match repos.get(&name) {
+        Ok(Some(router)) => router,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::error!("failed to look up repository {}: {}", name, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let (mut parts, body) = request.into_parts();
+    let path_and_query = match parts.uri.query() {
+        Some(query) => format!("/{}?{}", rest, query),
+        None => format!("/{}", rest),
+    };
+    match path_and_query.parse::<Uri>() {
+        Ok(uri) => parts.uri = uri,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    }
+    // The extensions hold this route's path parameters, which would be
+    // mixed up with those of the repository's routes.
+    parts.extensions.clear();
+    match router.call(Request::from_parts(parts, body)).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+/// The routes of the default repository plus `/repos`: `GET` lists the
+/// hosted repositories, `POST` with `{"name": ...}` creates one and
+/// `DELETE /repos/<name>` deletes one. Creating and deleting needs the
+/// admin role.
+pub fn app(default: AppState, repos: Arc<Repos>) -> Router {
+    let admin = Router::new()
+        .route("/repos", get(list_handler).post(create_handler))
+        .route("/repos/:name", delete(delete_handler))
+        .with_state(repos.clone())
+        .layer(middleware::from_fn_with_state(default.clone(), server::require_auth));
+    let hosted = Router::new()
+        .route("/repos/:name/*rest", any(repo_handler))
+        .with_state(repos);
+    server::app(default).merge(admin).merge(hosted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AccessRules, TokenStore};
+    use crate::commit::Commit;
+    use crate::config::{ServerConfig, UserAccess};
+    use crate::object::{Blob, Hashable, Object};
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn hosts_separate_repositories() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = TokenStore::in_dir(dir.path());
+        let root = tokens.issue("root").unwrap();
+        let ada = tokens.issue("ada").unwrap();
+        let mut config = ServerConfig::default();
+        config.users.insert(
+            "root".into(),
+            UserAccess {
+                role: Some(Role::Admin),
+                paths: Vec::new(),
+            },
+        );
+        let (tx, _) = broadcast::channel(8);
+        let mut state = AppState::new(CommitStore::default(), tx);
+        state.auth = Some(Arc::new(tokens));
+        state.access = Arc::new(AccessRules::from(&config));
+        let open = || Repos::open(dir.path(), CommitBackendKind::Log, 8, state.clone()).unwrap();
+        let app = app(state.clone(), Arc::new(open()));
+
+        let send = |token: &str, method: &str, uri: &str, body: Body| {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", token))
+                .header("content-type", "application/json")
+                .body(body)
+                .unwrap();
+            let app = app.clone();
+            async move { app.oneshot(req).await.unwrap() }
+        };
+        let create = |token: &str, name: &str| {
+            send(token, "POST", "/repos", Body::from(json!({ "name": name }).to_string()))
+        };
+        let commits = |uri: &str| {
+            let res = send(&ada, "GET", uri, Body::empty());
+            async move {
+                let body = axum::body::to_bytes(res.await.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<Vec<Commit>>(&body).unwrap()
+            }
+        };
+
+        assert_eq!(create(&ada, "alpha").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(create(&root, "alpha").await.status(), StatusCode::CREATED);
+        assert_eq!(create(&root, "beta").await.status(), StatusCode::CREATED);
+        assert_eq!(create(&root, "alpha").await.status(), StatusCode::CONFLICT);
+        assert_eq!(create(&root, "../x").await.status(), StatusCode::BAD_REQUEST);
+
+        let blob = Object::Blob(Blob { content: b"hi".to_vec() });
+        let uri = format!("/repos/alpha/objects/{}", blob.hash());
+        assert_eq!(send(&ada, "PUT", &uri, Body::from(blob.encode())).await.status(), StatusCode::OK);
+        assert!(dir.path().join("repos/alpha/objects").join(blob.hash().to_string()).exists());
+        let change = json!({"hash": blob.hash(), "path": "a.txt", "timestamp": 1});
+        let res = send(&ada, "POST", "/repos/alpha/changes", Body::from(change.to_string())).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        assert_eq!(commits("/repos/alpha/commits?after=0").await.len(), 1);
+        assert!(commits("/repos/beta/commits").await.is_empty());
+        assert!(commits("/commits").await.is_empty());
+        let res = send(&ada, "GET", "/repos/alpha/commits", Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = send("hit_wrong", "GET", "/repos/alpha/commits", Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = send(&ada, "GET", "/repos/gamma/commits", Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // Repositories survive a restart.
+        let names: Vec<String> = open().list().unwrap().into_iter().map(|r| r.name).collect();
+        assert_eq!(names, vec!["alpha", "beta"]);
+
+        assert_eq!(send(&ada, "DELETE", "/repos/beta", Body::empty()).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(send(&root, "DELETE", "/repos/beta", Body::empty()).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(send(&root, "DELETE", "/repos/beta", Body::empty()).await.status(), StatusCode::NOT_FOUND);
+        assert!(!dir.path().join("repos/beta").exists());
+        let res = send(&ada, "GET", "/repos", Body::empty()).await;
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let list: Vec<RepoInfo> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(list, vec![RepoInfo { name: "alpha".into() }]);
+    }
+}
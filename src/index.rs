@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::object::FileMode;
+use crate::object_id::ObjectId;
+use crate::outbox::write_atomic;
+
+/// Last-known content of the working tree, see [`Index`].
+pub const INDEX_PATH: &str = ".hit/index";
+
+/// What a path held when it was last recorded or synced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub hash: ObjectId,
+    #[serde(default, skip_serializing_if = "FileMode::is_regular")]
+    pub mode: FileMode,
+}
+
+/// The content and mode of every repository path as the watcher last
+/// recorded it or sync last wrote it, kept as JSON in `.hit/index`.
+///
+/// When the watcher starts it compares the working tree against the index
+/// to find what changed while it was not running.
+#[derive(Debug)]
+pub struct Index {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: BTreeMap<String, IndexEntry>,
+    /// Whether `entries` differ from the file.
+    dirty: bool,
+}
+
+impl Index {
+    /// The index of the repository in the current directory.
+    pub fn load() -> io::Result<Self> {
+        Self::open(INDEX_PATH)
+    }
+
+    /// The index kept at `path`, empty if there is none yet.
+    ///
+    /// An unreadable index is treated as empty, as it only saves work: the
+    /// next scan stores every file again and finds the objects already there.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::warn!(%e, path = %path.display(), "ignoring unreadable index");
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(State { entries, dirty: false }),
+        })
+    }
+
+    pub fn get(&self, path: &str) -> Option<IndexEntry> {
+        self.lock().entries.get(path).copied()
+    }
+
+    /// Remember `entry` at `path` until the next [`save`](Self::save).
+    pub fn insert(&self, path: String, entry: IndexEntry) {
+        let mut state = self.lock();
+        if state.entries.insert(path, entry) != Some(entry) {
+            state.dirty = true;
+        }
+    }
+
+    pub fn remove(&self, path: &str) {
+        let mut state = self.lock();
+        if state.entries.remove(path).is_some() {
+            state.dirty = true;
+        }
+    }
+
+    /// Every path in the index, in order.
+    pub fn paths(&self) -> Vec<String> {
+        self.lock().entries.keys().cloned().collect()
+    }
+
+    /// Write the index out if it changed since it was loaded or last saved.
+    pub fn save(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        if !state.dirty {
+            return Ok(());
+        }
+        let data = serde_json::to_vec(&state.entries).map_err(io::Error::other)?;
+        write_atomic(&self.path, &data)?;
+        state.dirty = false;
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_changed_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let index = Index::open(&path).unwrap();
+        assert_eq!(index.get("a"), None);
+
+        let entry = IndexEntry { hash: ObjectId::from([1; 32]), mode: FileMode::Executable };
+        index.insert("a".into(), entry);
+        index.insert("b/c".into(), IndexEntry { hash: ObjectId::from([2; 32]), mode: FileMode::Regular });
+        index.save().unwrap();
+        let loaded = Index::open(&path).unwrap();
+        assert_eq!(loaded.get("a"), Some(entry));
+        assert_eq!(loaded.paths(), vec!["a", "b/c"]);
+
+        // Unchanged entries are not written again
+        fs::remove_file(&path).unwrap();
+        loaded.insert("a".into(), entry);
+        loaded.save().unwrap();
+        assert!(!path.exists());
+        loaded.remove("b/c");
+        loaded.save().unwrap();
+        assert_eq!(Index::open(&path).unwrap().paths(), vec!["a"]);
+
+        fs::write(&path, b"{\"a\":").unwrap();
+        assert!(Index::open(&path).unwrap().paths().is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 pub mod object;
+pub mod object_id;
 pub mod storage;
 pub mod repo;
 pub mod watcher;
@@ -7,3 +8,14 @@ pub mod streaming;
 pub mod sync;
 pub mod commit;
 pub mod commit_log;
+pub mod commit_sqlite;
+pub mod fsck;
+pub mod config;
+pub mod auth;
+pub mod tls;
+pub mod outbox;
+pub mod session;
+pub mod index;
+pub mod hosting;
+pub mod gc;
+pub mod chunking;
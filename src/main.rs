@@ -1,4 +1,9 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
 use clap::{Parser, Subcommand};
+use hit_with_gpt::config::{CONFIG_PATH, CommitBackendKind, ConfigFile, RepoConfig, ServerConfig};
+use hit_with_gpt::object::{Hashable, Object, TreeEntry};
 
 #[derive(Parser)]
 #[command(name = "hit")]
@@ -12,18 +17,155 @@ struct Cli {
 enum Commands {
     Init,
     Watch,
-    Serve,
+    /// Run the sync server
+    Serve {
+        /// Address to listen on, e.g. 127.0.0.1:8888
+        #[arg(long)]
+        bind: Option<String>,
+        /// Directory holding the objects and commit history
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Server config file, instead of the [server] section of .hit/config
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[command(subcommand)]
+        admin: Option<ServeAdmin>,
+    },
     Sync,
+    /// Watch and sync in one process, without sending synced files back
+    Dev,
+    /// Verify object and commit history integrity
+    Fsck {
+        /// Move corrupt and dangling items to the quarantine directory
+        #[arg(long)]
+        repair: bool,
+        /// Directory holding the objects and commit history, as for `hit serve`
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Server config file selecting the data directory and commit backend
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Prune unreachable objects according to the retention policy
+    Gc {
+        /// Report what would be pruned without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Directory holding the objects and commit history, as for `hit serve`
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Server config file selecting the data directory and commit backend
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Rewrite the commit log compactly, keeping all history (stop `hit serve` first)
+    Compact {
+        /// Directory holding the commit log, as for `hit serve`
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+        /// Server config file selecting the data directory and commit backend
+        #[arg(long)]
+        config: Option<PathBuf>,
+    },
+    /// Print a stored object, given its id or a unique prefix of it
+    Show {
+        id: String,
+    },
+    /// Read and change settings in .hit/config
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Manage the servers this repository syncs with
+    Remote {
+        #[command(subcommand)]
+        action: RemoteAction,
+    },
+    /// Show changes waiting to be sent to the server
+    Status,
+}
+
+#[derive(Subcommand)]
+enum ServeAdmin {
+    /// Manage the tokens clients authenticate with
+    Token {
+        #[command(subcommand)]
+        action: TokenAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum TokenAction {
+    /// Issue a token for a user and print it
+    Add { user: String },
+    /// List the users holding tokens
+    List,
+    /// Revoke every token of a user
+    Revoke { user: String },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the value of a key such as user.name
+    Get { key: String },
+    /// Set a key; the value is read as TOML if possible, otherwise as a string
+    Set { key: String, value: String },
+    /// Remove a key or a whole section
+    Unset { key: String },
+    /// Print every key set in .hit/config
+    List,
+}
+
+#[derive(Subcommand)]
+enum RemoteAction {
+    Add {
+        name: String,
+        url: String,
+        /// Token issued by the server with `hit serve token add`
+        #[arg(long)]
+        token: Option<String>,
+    },
+    Remove { name: String },
+    List,
+}
+
+/// Server settings from the config file and environment, overridden by the
+/// flags of `hit serve` and the maintenance commands.
+fn server_config(
+    path: Option<&Path>,
+    bind: Option<String>,
+    data_dir: Option<PathBuf>,
+) -> io::Result<ServerConfig> {
+    let mut config = ServerConfig::load(path)?;
+    if let Some(bind) = bind {
+        config.bind = bind;
+    }
+    if let Some(data_dir) = data_dir {
+        config.data_dir = data_dir;
+    }
+    Ok(config)
 }
 
 fn main() {
+    let cli = Cli::parse();
+    // `hit serve` takes its log level from the server config, so that is
+    // loaded before the subscriber is installed.
+    let server = match &cli.command {
+        Commands::Serve { bind, data_dir, config, .. } => {
+            Some(server_config(config.as_deref(), bind.clone(), data_dir.clone()))
+        }
+        _ => None,
+    };
+    let level = match &server {
+        Some(Ok(config)) => config.level(),
+        _ => tracing::Level::INFO,
+    };
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(level)
         .with_target(true)
         .pretty()
         .init();
 
-    let cli = Cli::parse();
     match cli.command {
         Commands::Init => {
             if let Err(e) = hit_with_gpt::repo::init() {
@@ -31,16 +173,34 @@ fn main() {
             }
         }
         Commands::Watch => {
-            if let Err(e) = hit_with_gpt::watcher::watch_and_store_changes() {
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("failed to build runtime");
+            if let Err(e) = rt.block_on(hit_with_gpt::watcher::watch_and_store_changes()) {
                 tracing::error!(%e, "Watcher error");
             }
         }
-        Commands::Serve => {
+        Commands::Serve { admin, .. } => {
+            let config = match server.expect("server config is loaded for serve") {
+                Ok(config) => config,
+                Err(e) => {
+                    tracing::error!(%e, "Failed to load config");
+                    std::process::exit(1);
+                }
+            };
+            if let Some(ServeAdmin::Token { action }) = admin {
+                if let Err(e) = token(&config, action) {
+                    tracing::error!(%e, "token command failed");
+                    std::process::exit(1);
+                }
+                return;
+            }
             let rt = tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
                 .build()
                 .expect("failed to build runtime");
-            if let Err(e) = rt.block_on(hit_with_gpt::server::start_server()) {
+            if let Err(e) = rt.block_on(hit_with_gpt::server::start_server(config)) {
                 tracing::error!(%e, "Server error");
             }
         }
@@ -51,5 +211,288 @@ fn main() {
                 .expect("failed to build runtime");
             rt.block_on(hit_with_gpt::sync::sync_from_server());
         }
+        Commands::Dev => {
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("failed to build runtime");
+            if let Err(e) = rt.block_on(hit_with_gpt::session::dev()) {
+                tracing::error!(%e, "dev error");
+            }
+        }
+        Commands::Fsck { repair, data_dir, config } => match server_config(config.as_deref(), None, data_dir)
+            .and_then(|server| {
+                let policy = RepoConfig::load()?.gc.retention;
+                hit_with_gpt::fsck::fsck_server(&server, &policy, repair)
+            }) {
+            Ok(reports) => {
+                for (name, report) in &reports {
+                    if let Some(name) = name {
+                        println!("repository {}:", name);
+                    }
+                    println!(
+                        "checked {} objects, {} commits",
+                        report.objects_checked, report.commits_checked
+                    );
+                    for bad in &report.corrupt_objects {
+                        println!("corrupt object {}: {}", bad.hash, bad.reason);
+                    }
+                    for bad in &report.corrupt_records {
+                        println!("corrupt commit record at offset {}: {}", bad.offset, bad.reason);
+                    }
+                    if let Some(offset) = report.torn_tail {
+                        println!("torn commit record at offset {}", offset);
+                    }
+                    for d in &report.dangling {
+                        println!("dangling {} -> {}", d.source, d.hash);
+                    }
+                    for path in &report.quarantined {
+                        println!("quarantined {}", path.display());
+                    }
+                }
+                if !repair && reports.iter().any(|(_, report)| !report.is_clean()) {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                tracing::error!(%e, "fsck failed");
+                std::process::exit(1);
+            }
+        },
+        Commands::Gc { dry_run, data_dir, config } => match server_config(config.as_deref(), None, data_dir)
+            .and_then(|server| {
+                let gc = RepoConfig::load()?.gc;
+                hit_with_gpt::gc::gc_server(&server, &gc, dry_run)
+            }) {
+            Ok(reports) => {
+                let verb = if dry_run { "would prune" } else { "pruned" };
+                for (name, report) in reports {
+                    if let Some(name) = name {
+                        println!("repository {}:", name);
+                    }
+                    println!(
+                        "checked {} objects: {} reachable, {} within grace period, {} {} ({} bytes)",
+                        report.objects_checked,
+                        report.reachable,
+                        report.kept_recent,
+                        verb,
+                        report.pruned.len(),
+                        report.bytes_freed
+                    );
+                }
+            }
+            Err(e) => {
+                tracing::error!(%e, "gc failed");
+                std::process::exit(1);
+            }
+        },
+        Commands::Compact { data_dir, config } => {
+            match server_config(config.as_deref(), None, data_dir).and_then(compact) {
+                Ok(report) => println!(
+                    "compacted {} commits: {} -> {} bytes",
+                    report.commits, report.bytes_before, report.bytes_after
+                ),
+                Err(e) => {
+                    tracing::error!(%e, "compaction failed");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Commands::Show { id } => {
+            if let Err(e) = show(&id) {
+                tracing::error!(%e, "show failed");
+                std::process::exit(1);
+            }
+        }
+        Commands::Config { action } => {
+            if let Err(e) = config(action) {
+                tracing::error!(%e, "config failed");
+                std::process::exit(1);
+            }
+        }
+        Commands::Remote { action } => {
+            if let Err(e) = remote(action) {
+                tracing::error!(%e, "remote failed");
+                std::process::exit(1);
+            }
+        }
+        Commands::Status => {
+            if let Err(e) = status() {
+                tracing::error!(%e, "status failed");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Compact the commit log of the server configured in `server`; other
+/// backends have no log to compact.
+fn compact(server: ServerConfig) -> io::Result<hit_with_gpt::commit_log::CompactReport> {
+    match server.commit_backend {
+        CommitBackendKind::Log => {
+            let log = server.data_dir.join(hit_with_gpt::commit_log::COMMIT_LOG_FILE);
+            hit_with_gpt::commit_log::CommitLog::compact(log)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only the log commit backend can be compacted",
+        )),
+    }
+}
+
+fn config(action: ConfigAction) -> io::Result<()> {
+    let mut file = ConfigFile::open(CONFIG_PATH)?;
+    match action {
+        ConfigAction::Get { key } => match file.get(&key) {
+            Some(value) => println!("{}", value),
+            // Like `git config`, a missing key is reported by the exit code.
+            None => std::process::exit(1),
+        },
+        ConfigAction::Set { key, value } => {
+            file.set(&key, &value)?;
+            file.save()?;
+        }
+        ConfigAction::Unset { key } => {
+            if file.unset(&key)? {
+                file.save()?;
+            }
+        }
+        ConfigAction::List => {
+            for (key, value) in file.list() {
+                println!("{}={}", key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn token(config: &ServerConfig, action: TokenAction) -> io::Result<()> {
+    let tokens = hit_with_gpt::auth::TokenStore::in_dir(&config.data_dir);
+    match action {
+        TokenAction::Add { user } => {
+            let token = tokens.issue(&user)?;
+            // Only the hash is stored, so this is the one chance to copy it.
+            println!("{}", token);
+        }
+        TokenAction::List => {
+            for entry in tokens.list()? {
+                println!("{}\tcreated {}", entry.user, entry.created);
+            }
+        }
+        TokenAction::Revoke { user } => match tokens.revoke(&user)? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no tokens", user),
+                ));
+            }
+            n => println!("revoked {} token(s) of {}", n, user),
+        },
+    }
+    Ok(())
+}
+
+fn remote(action: RemoteAction) -> io::Result<()> {
+    let mut file = ConfigFile::open(CONFIG_PATH)?;
+    match action {
+        RemoteAction::Add { name, url, token } => {
+            file.add_remote(&name, &url, token.as_deref())?;
+            file.save()?;
+        }
+        RemoteAction::Remove { name } => {
+            file.remove_remote(&name)?;
+            file.save()?;
+        }
+        RemoteAction::List => {
+            for (name, remote) in file.config()?.remote {
+                println!("{}\t{}", name, remote.url);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// List the changes in the outbox and how sending them last went, and
+/// those the server refused.
+fn status() -> io::Result<()> {
+    let outbox = hit_with_gpt::outbox::Outbox::default();
+    let rejected = outbox.rejected()?;
+    if !rejected.is_empty() {
+        println!("{} change(s) rejected by the server:", rejected.len());
+        for entry in &rejected {
+            println!(
+                "  {}\t{}\t{}",
+                entry.change.path,
+                &entry.change.hash.to_hex()[..12],
+                entry.reason
+            );
+        }
+    }
+    let pending = outbox.pending()?;
+    if pending.is_empty() {
+        println!("nothing to send");
+        return Ok(());
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(io::Error::other)?
+        .as_secs();
+    println!("{} change(s) waiting to be sent:", pending.len());
+    for change in &pending {
+        println!(
+            "  {}\t{}\tdetected {}s ago",
+            change.path,
+            &change.hash.to_hex()[..12],
+            now.saturating_sub(change.timestamp)
+        );
+    }
+    let retry = outbox.retry_state()?;
+    if let Some(error) = &retry.last_error {
+        println!("last attempt failed ({} in a row): {}", retry.failures, error);
+    }
+    if retry.is_due(now) {
+        println!("sent as soon as `hit watch` is running");
+    } else {
+        println!("next attempt in {}s", retry.next_attempt - now);
+    }
+    Ok(())
+}
+
+/// Write the object matching `prefix` to stdout: file content for blobs and
+/// chunked files, a listing for trees and the message for commits.
+fn show(prefix: &str) -> io::Result<()> {
+    let id = hit_with_gpt::storage::resolve_prefix(prefix)?;
+    let mut out = io::stdout().lock();
+    match hit_with_gpt::storage::read_object(&id)? {
+        Object::Blob(blob) => out.write_all(&blob.content)?,
+        Object::Chunked(manifest) => {
+            for chunk in &manifest.chunks {
+                match hit_with_gpt::storage::read_object(chunk)? {
+                    Object::Blob(blob) => out.write_all(&blob.content)?,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("chunk {} is not a blob", chunk),
+                        ));
+                    }
+                }
+            }
+        }
+        Object::Tree(tree) => {
+            for entry in &tree.entries {
+                match entry {
+                    TreeEntry::Blob { name, blob } => writeln!(out, "blob {} {}", blob.hash(), name)?,
+                    TreeEntry::Tree { name, tree } => writeln!(out, "tree {} {}", tree.hash(), name)?,
+                    TreeEntry::Executable { name, blob } => writeln!(out, "executable {} {}", blob.hash(), name)?,
+                    TreeEntry::Symlink { name, target } => writeln!(out, "symlink {} -> {}", name, target)?,
+                }
+            }
+        }
+        Object::Commit(commit) => {
+            writeln!(out, "tree {}", commit.tree.hash())?;
+            writeln!(out)?;
+            writeln!(out, "{}", commit.message)?;
+        }
     }
+    out.flush()
 }
@@ -1,9 +1,99 @@
+//! Object model and its canonical encoding.
+//!
+//! Every object is stored, transferred and hashed as
+//! `<kind> <version> <len>\0<payload>`, e.g. `blob 1 5\0hello`. The payload
+//! layout is defined here explicitly, so object ids do not depend on how serde
+//! or bincode lay out the Rust types. Objects written by older builds as raw
+//! bincode are still readable through [`Object::decode`].
+
+use std::io;
+
 use serde::{Serialize, Deserialize};
 use sha2::{Digest, Sha256};
 
+use crate::object_id::ObjectId;
+
+/// Version of the canonical encoding written by this build.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Upper bound on the length of an encoded header, including the NUL.
+pub const MAX_HEADER_LEN: usize = 64;
+
+/// Deepest nesting of trees, counting a commit's tree, that is decoded.
+/// Decoding recurses once per level, so deeper input could overflow the
+/// stack; it is rejected instead.
+pub const MAX_TREE_DEPTH: usize = 256;
+
 /// Trait for objects that can produce a stable hash identifier.
 pub trait Hashable {
-    fn hash(&self) -> String;
+    fn hash(&self) -> ObjectId;
+}
+
+/// The kind of an object, written as the first word of its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Blob,
+    Tree,
+    Commit,
+    Chunked,
+}
+
+impl ObjectKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ObjectKind::Blob => "blob",
+            ObjectKind::Tree => "tree",
+            ObjectKind::Commit => "commit",
+            ObjectKind::Chunked => "chunked",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "blob" => Some(ObjectKind::Blob),
+            "tree" => Some(ObjectKind::Tree),
+            "commit" => Some(ObjectKind::Commit),
+            "chunked" => Some(ObjectKind::Chunked),
+            _ => None,
+        }
+    }
+}
+
+/// Parsed `<kind> <version> <len>\0` prefix of an encoded object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub kind: ObjectKind,
+    pub version: u32,
+    /// Length of the payload following the header.
+    pub len: u64,
+    /// Length of the header itself, including the NUL.
+    pub size: usize,
+}
+
+impl Header {
+    pub fn new(kind: ObjectKind, len: u64) -> Self {
+        let size = format!("{} {} {}\0", kind.as_str(), FORMAT_VERSION, len).len();
+        Self { kind, version: FORMAT_VERSION, len, size }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        format!("{} {} {}\0", self.kind.as_str(), self.version, self.len).into_bytes()
+    }
+
+    /// Parse the header at the start of `bytes`, or `None` if `bytes` does not
+    /// begin with a canonical header (e.g. a legacy bincode object).
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let end = bytes.iter().take(MAX_HEADER_LEN).position(|&b| b == 0)?;
+        let text = std::str::from_utf8(&bytes[..end]).ok()?;
+        let mut parts = text.split(' ');
+        let kind = ObjectKind::from_str(parts.next()?)?;
+        let version = parts.next()?.parse().ok()?;
+        let len = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Self { kind, version, len, size: end + 1 })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -12,17 +102,133 @@ pub struct Blob {
 }
 
 impl Hashable for Blob {
-    fn hash(&self) -> String {
+    fn hash(&self) -> ObjectId {
+        let mut hasher = BlobHasher::new(self.content.len() as u64);
+        hasher.update(&self.content);
+        hasher.finish()
+    }
+}
+
+/// Incrementally computes a [`Blob`] hash for content of a known length,
+/// so large files can be hashed without holding them in memory.
+pub struct BlobHasher {
+    hasher: Sha256,
+}
+
+impl BlobHasher {
+    pub fn new(len: u64) -> Self {
         let mut hasher = Sha256::new();
-        hasher.update(&bincode::serialize(self).expect("failed to serialize blob"));
-        format!("{:x}", hasher.finalize())
+        hasher.update(Header::new(ObjectKind::Blob, len).encode());
+        Self { hasher }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub fn finish(self) -> ObjectId {
+        self.hasher.finalize().into()
+    }
+}
+
+/// Manifest for a large file stored as content-defined chunks.
+///
+/// Each entry of `chunks` is the hash of a [`Blob`] holding one chunk; the file
+/// content is their concatenation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkedBlob {
+    pub size: u64,
+    pub chunks: Vec<ObjectId>,
+}
+
+impl Hashable for ChunkedBlob {
+    fn hash(&self) -> ObjectId {
+        hash_encoded(ObjectKind::Chunked, &self.encode_payload())
+    }
+}
+
+impl ChunkedBlob {
+    // Payload: size, chunk count, then each chunk hash as a string.
+    fn encode_payload(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_u64(&mut out, self.size);
+        put_u64(&mut out, self.chunks.len() as u64);
+        for chunk in &self.chunks {
+            put_bytes(&mut out, chunk.to_hex().as_bytes());
+        }
+        out
+    }
+
+    fn decode_payload(r: &mut PayloadReader) -> io::Result<Self> {
+        let size = r.u64()?;
+        let count = r.u64()?;
+        let mut chunks = Vec::new();
+        for _ in 0..count {
+            chunks.push(r.string()?.parse().map_err(|_| invalid("invalid chunk id"))?);
+        }
+        Ok(Self { size, chunks })
     }
 }
 
+/// How a file's content is checked out.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FileMode {
+    #[default]
+    Regular,
+    Executable,
+    /// The content is the link's target.
+    Symlink,
+}
+
+impl FileMode {
+    pub fn is_regular(&self) -> bool {
+        *self == FileMode::Regular
+    }
+}
+
+// Variants are only ever appended, so trees written as bincode by older
+// builds still decode.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum TreeEntry {
     Blob { name: String, blob: Blob },
     Tree { name: String, tree: Tree },
+    Executable { name: String, blob: Blob },
+    Symlink { name: String, target: String },
+}
+
+impl TreeEntry {
+    /// An entry for a file with `content` checked out as `mode`.
+    pub fn file(name: String, content: Vec<u8>, mode: FileMode) -> io::Result<Self> {
+        let blob = Blob { content };
+        Ok(match mode {
+            FileMode::Regular => TreeEntry::Blob { name, blob },
+            FileMode::Executable => TreeEntry::Executable { name, blob },
+            FileMode::Symlink => TreeEntry::Symlink {
+                name,
+                target: String::from_utf8(blob.content).map_err(|_| invalid("symlink target is not utf-8"))?,
+            },
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            TreeEntry::Blob { name, .. }
+            | TreeEntry::Tree { name, .. }
+            | TreeEntry::Executable { name, .. }
+            | TreeEntry::Symlink { name, .. } => name,
+        }
+    }
+
+    /// The mode of a file entry, or `None` for a directory.
+    pub fn mode(&self) -> Option<FileMode> {
+        match self {
+            TreeEntry::Blob { .. } => Some(FileMode::Regular),
+            TreeEntry::Executable { .. } => Some(FileMode::Executable),
+            TreeEntry::Symlink { .. } => Some(FileMode::Symlink),
+            TreeEntry::Tree { .. } => None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -31,10 +237,58 @@ pub struct Tree {
 }
 
 impl Hashable for Tree {
-    fn hash(&self) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(&bincode::serialize(self).expect("failed to serialize tree"));
-        format!("{:x}", hasher.finalize())
+    fn hash(&self) -> ObjectId {
+        hash_encoded(ObjectKind::Tree, &self.encode_payload())
+    }
+}
+
+impl Tree {
+    // Payload: entry count, then per entry a kind byte, the name and the
+    // child's full encoding. The kind is `b` for a regular file, `x` for an
+    // executable, `l` for a symlink (a blob of its target) and `t` for a
+    // directory.
+    fn encode_payload(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_u64(&mut out, self.entries.len() as u64);
+        for entry in &self.entries {
+            let (kind, name, child) = match entry {
+                TreeEntry::Blob { name, blob } => {
+                    (b'b', name, Object::Blob(blob.clone()).encode())
+                }
+                TreeEntry::Tree { name, tree } => {
+                    (b't', name, with_header(ObjectKind::Tree, tree.encode_payload()))
+                }
+                TreeEntry::Executable { name, blob } => {
+                    (b'x', name, Object::Blob(blob.clone()).encode())
+                }
+                TreeEntry::Symlink { name, target } => {
+                    (b'l', name, with_header(ObjectKind::Blob, target.as_bytes().to_vec()))
+                }
+            };
+            out.push(kind);
+            put_bytes(&mut out, name.as_bytes());
+            put_bytes(&mut out, &child);
+        }
+        out
+    }
+
+    fn decode_payload(r: &mut PayloadReader, depth: usize) -> io::Result<Self> {
+        let count = r.u64()?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let kind = r.take(1)?[0];
+            let name = r.string()?;
+            let child = Object::decode_nested(r.bytes()?, depth + 1)?;
+            let entry = match (kind, child) {
+                (b'b', Object::Blob(blob)) => TreeEntry::Blob { name, blob },
+                (b't', Object::Tree(tree)) => TreeEntry::Tree { name, tree },
+                (b'x', Object::Blob(blob)) => TreeEntry::Executable { name, blob },
+                (b'l', Object::Blob(blob)) => TreeEntry::file(name, blob.content, FileMode::Symlink)?,
+                _ => return Err(invalid("tree entry kind does not match its object")),
+            };
+            entries.push(entry);
+        }
+        Ok(Self { entries })
     }
 }
 
@@ -45,10 +299,27 @@ pub struct Commit {
 }
 
 impl Hashable for Commit {
-    fn hash(&self) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(&bincode::serialize(self).expect("failed to serialize commit"));
-        format!("{:x}", hasher.finalize())
+    fn hash(&self) -> ObjectId {
+        hash_encoded(ObjectKind::Commit, &self.encode_payload())
+    }
+}
+
+impl Commit {
+    // Payload: the tree's full encoding, then the message.
+    fn encode_payload(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_bytes(&mut out, &with_header(ObjectKind::Tree, self.tree.encode_payload()));
+        put_bytes(&mut out, self.message.as_bytes());
+        out
+    }
+
+    fn decode_payload(r: &mut PayloadReader, depth: usize) -> io::Result<Self> {
+        let tree = match Object::decode_nested(r.bytes()?, depth + 1)? {
+            Object::Tree(tree) => tree,
+            _ => return Err(invalid("commit does not reference a tree")),
+        };
+        let message = r.string()?;
+        Ok(Self { tree, message })
     }
 }
 
@@ -57,22 +328,226 @@ pub enum Object {
     Blob(Blob),
     Tree(Tree),
     Commit(Commit),
+    Chunked(ChunkedBlob),
+}
+
+impl Object {
+    pub fn kind(&self) -> ObjectKind {
+        match self {
+            Object::Blob(_) => ObjectKind::Blob,
+            Object::Tree(_) => ObjectKind::Tree,
+            Object::Commit(_) => ObjectKind::Commit,
+            Object::Chunked(_) => ObjectKind::Chunked,
+        }
+    }
+
+    /// Hashes of other objects this object points to.
+    pub fn references(&self) -> Vec<ObjectId> {
+        match self {
+            Object::Chunked(c) => c.chunks.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Encode the object in the canonical format used for storage, transfer
+    /// and hashing.
+    pub fn encode(&self) -> Vec<u8> {
+        let payload = match self {
+            Object::Blob(b) => b.content.clone(),
+            Object::Tree(t) => t.encode_payload(),
+            Object::Commit(c) => c.encode_payload(),
+            Object::Chunked(c) => c.encode_payload(),
+        };
+        with_header(self.kind(), payload)
+    }
+
+    /// Decode an object in the canonical format, falling back to the legacy
+    /// bincode layout for objects written before it existed.
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        if Header::parse(bytes).is_some() {
+            return Self::decode_canonical(bytes);
+        }
+        if legacy_too_deep(bytes) {
+            return Err(invalid("trees are nested too deeply"));
+        }
+        bincode::deserialize(bytes)
+            .map_err(|e| invalid(&format!("failed to decode object: {}", e)))
+    }
+
+    /// Decode an object that must be in the canonical format.
+    pub fn decode_canonical(bytes: &[u8]) -> io::Result<Self> {
+        Self::decode_nested(bytes, 0)
+    }
+
+    /// Decode an object found `depth` levels down inside another one.
+    fn decode_nested(bytes: &[u8], depth: usize) -> io::Result<Self> {
+        if depth > MAX_TREE_DEPTH {
+            return Err(invalid("trees are nested too deeply"));
+        }
+        let header = Header::parse(bytes).ok_or_else(|| invalid("missing object header"))?;
+        if header.version != FORMAT_VERSION {
+            return Err(invalid(&format!(
+                "unsupported object format version {}",
+                header.version
+            )));
+        }
+        let payload = &bytes[header.size..];
+        if payload.len() as u64 != header.len {
+            return Err(invalid("object length does not match header"));
+        }
+        let mut r = PayloadReader { data: payload };
+        let obj = match header.kind {
+            ObjectKind::Blob => return Ok(Object::Blob(Blob { content: payload.to_vec() })),
+            ObjectKind::Tree => Object::Tree(Tree::decode_payload(&mut r, depth)?),
+            ObjectKind::Commit => Object::Commit(Commit::decode_payload(&mut r, depth)?),
+            ObjectKind::Chunked => Object::Chunked(ChunkedBlob::decode_payload(&mut r)?),
+        };
+        if !r.data.is_empty() {
+            return Err(invalid("trailing bytes after object payload"));
+        }
+        Ok(obj)
+    }
 }
 
 impl Hashable for Object {
-    fn hash(&self) -> String {
+    fn hash(&self) -> ObjectId {
         match self {
             Object::Blob(b) => b.hash(),
             Object::Tree(t) => t.hash(),
             Object::Commit(c) => c.hash(),
+            Object::Chunked(c) => c.hash(),
         }
     }
 }
 
+/// Hash an object the way builds before the canonical encoding did: SHA-256
+/// over the bincode serialization of the inner value. Objects stored under
+/// such ids remain valid and are verified with this function.
+pub fn legacy_hash(obj: &Object) -> ObjectId {
+    let bytes = match obj {
+        Object::Blob(b) => bincode::serialize(b),
+        Object::Tree(t) => bincode::serialize(t),
+        Object::Commit(c) => bincode::serialize(c),
+        Object::Chunked(c) => bincode::serialize(c),
+    }
+    .expect("failed to serialize object");
+    Sha256::digest(bytes).into()
+}
+
+/// Whether a legacy bincode object holds trees nested deeper than
+/// [`MAX_TREE_DEPTH`]. Serde decodes them recursively, so this walks the
+/// layout first without recursing. Malformed input is left for bincode to
+/// report.
+fn legacy_too_deep(bytes: &[u8]) -> bool {
+    let scan = |r: &mut PayloadReader| -> Option<bool> {
+        // Blob, Tree, Commit, Chunked: a commit starts with its tree
+        if !matches!(r.u32().ok()?, 1 | 2) {
+            return Some(false);
+        }
+        // Entries left to read in each open tree, innermost last
+        let mut open = vec![r.u64().ok()?];
+        while let Some(left) = open.last_mut() {
+            if *left == 0 {
+                open.pop();
+                continue;
+            }
+            *left -= 1;
+            let variant = r.u32().ok()?;
+            r.bytes().ok()?;
+            match variant {
+                1 if open.len() > MAX_TREE_DEPTH => return Some(true),
+                1 => open.push(r.u64().ok()?),
+                // The blob's content or the symlink's target
+                0 | 2 | 3 => {
+                    r.bytes().ok()?;
+                }
+                _ => return Some(false),
+            }
+        }
+        Some(false)
+    };
+    scan(&mut PayloadReader { data: bytes }).unwrap_or(false)
+}
+
+fn with_header(kind: ObjectKind, payload: Vec<u8>) -> Vec<u8> {
+    let mut out = Header::new(kind, payload.len() as u64).encode();
+    out.extend_from_slice(&payload);
+    out
+}
+
+fn hash_encoded(kind: ObjectKind, payload: &[u8]) -> ObjectId {
+    let mut hasher = Sha256::new();
+    hasher.update(Header::new(kind, payload.len() as u64).encode());
+    hasher.update(payload);
+    hasher.finalize().into()
+}
+
+fn put_u64(out: &mut Vec<u8>, v: u64) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+struct PayloadReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PayloadReader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(invalid("truncated object payload"));
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().expect("8 bytes")))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = usize::try_from(self.u64()?).map_err(|_| invalid("length overflow"))?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("invalid utf-8 in object"))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn sample_commit() -> Commit {
+        let blob = Blob { content: b"hello".to_vec() };
+        let inner = Tree {
+            entries: vec![TreeEntry::Blob { name: "inner.txt".into(), blob: blob.clone() }],
+        };
+        let tree = Tree {
+            entries: vec![
+                TreeEntry::Blob { name: "file.txt".into(), blob },
+                TreeEntry::Tree { name: "dir".into(), tree: inner },
+                TreeEntry::Executable { name: "run.sh".into(), blob: Blob { content: b"#!/bin/sh".to_vec() } },
+                TreeEntry::Symlink { name: "link".into(), target: "dir/inner.txt".into() },
+                TreeEntry::Tree { name: "empty".into(), tree: Tree { entries: vec![] } },
+            ],
+        };
+        Commit { tree, message: "init".into() }
+    }
+
     #[test]
     fn blob_hash_is_deterministic() {
         let blob = Blob { content: b"hello".to_vec() };
@@ -89,7 +564,140 @@ mod tests {
             tree: tree.clone(),
             message: "init".into(),
         };
-        assert!(!tree.hash().is_empty());
-        assert!(!commit.hash().is_empty());
+        assert_ne!(tree.hash(), commit.hash());
+    }
+
+    #[test]
+    fn blob_hasher_matches_blob_hash() {
+        let blob = Blob { content: b"hello world".to_vec() };
+        let mut hasher = BlobHasher::new(blob.content.len() as u64);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        assert_eq!(hasher.finish(), blob.hash());
+    }
+
+    #[test]
+    fn blob_encoding_is_git_style() {
+        let obj = Object::Blob(Blob { content: b"hello".to_vec() });
+        assert_eq!(obj.encode(), b"blob 1 5\0hello");
+        // Pinned so an accidental format change is caught.
+        assert_eq!(obj.hash(), ObjectId::from(Sha256::digest(b"blob 1 5\0hello")));
+    }
+
+    #[test]
+    fn hash_covers_the_encoding() {
+        let objects = vec![
+            Object::Blob(Blob { content: vec![0, 1, 2] }),
+            Object::Commit(sample_commit()),
+            Object::Tree(sample_commit().tree),
+            Object::Chunked(ChunkedBlob {
+                size: 3,
+                chunks: vec![ObjectId::from([1; 32]), ObjectId::from([2; 32])],
+            }),
+        ];
+        for obj in objects {
+            let bytes = obj.encode();
+            assert_eq!(obj.hash(), ObjectId::from(Sha256::digest(&bytes)));
+            assert_eq!(Object::decode(&bytes).unwrap(), obj);
+        }
+    }
+
+    #[test]
+    fn decodes_legacy_bincode_objects() {
+        let obj = Object::Commit(sample_commit());
+        let legacy = bincode::serialize(&obj).unwrap();
+        assert_eq!(Object::decode(&legacy).unwrap(), obj);
+        assert_ne!(legacy_hash(&obj), obj.hash());
+    }
+
+    #[test]
+    fn modes_do_not_change_regular_tree_ids() {
+        let blob = Blob { content: b"hello".to_vec() };
+        let regular = Tree { entries: vec![TreeEntry::Blob { name: "f".into(), blob: blob.clone() }] };
+        // Pinned: trees of regular files encode as before modes existed
+        let mut expected = Vec::new();
+        put_u64(&mut expected, 1);
+        expected.push(b'b');
+        put_bytes(&mut expected, b"f");
+        put_bytes(&mut expected, b"blob 1 5\0hello");
+        assert_eq!(Object::Tree(regular.clone()).encode(), with_header(ObjectKind::Tree, expected));
+
+        let modes: Vec<ObjectId> = [FileMode::Regular, FileMode::Executable, FileMode::Symlink]
+            .into_iter()
+            .map(|mode| {
+                let entry = TreeEntry::file("f".into(), blob.content.clone(), mode).unwrap();
+                assert_eq!(entry.mode(), Some(mode));
+                Tree { entries: vec![entry] }.hash()
+            })
+            .collect();
+        assert_eq!(modes[0], regular.hash());
+        assert_ne!(modes[0], modes[1]);
+        assert_ne!(modes[1], modes[2]);
+    }
+
+    #[test]
+    fn rejects_malformed_objects() {
+        assert!(Object::decode(b"blob 1 9\0short").is_err());
+        assert!(Object::decode(b"blob 2 5\0hello").is_err());
+        assert!(Object::decode(b"tree 1 3\0abc").is_err());
+        assert!(Object::decode(b"not an object").is_err());
+    }
+
+    /// A tree with a chain of `levels` directories inside it, encoded
+    /// without building the nested value.
+    fn nested_tree(levels: usize) -> Vec<u8> {
+        let mut child = with_header(ObjectKind::Tree, 0u64.to_le_bytes().to_vec());
+        let mut prefixes = Vec::with_capacity(levels);
+        let mut len = child.len() as u64;
+        for _ in 0..levels {
+            let mut payload_head = Vec::new();
+            put_u64(&mut payload_head, 1);
+            payload_head.push(b't');
+            put_bytes(&mut payload_head, b"d");
+            put_u64(&mut payload_head, len);
+            let payload_len = payload_head.len() as u64 + len;
+            let mut prefix = Header::new(ObjectKind::Tree, payload_len).encode();
+            len = prefix.len() as u64 + payload_len;
+            prefix.extend_from_slice(&payload_head);
+            prefixes.push(prefix);
+        }
+        let mut out = Vec::with_capacity(len as usize);
+        for prefix in prefixes.iter().rev() {
+            out.extend_from_slice(prefix);
+        }
+        out.append(&mut child);
+        out
+    }
+
+    #[test]
+    fn rejects_deeply_nested_trees() {
+        assert!(Object::decode_canonical(&nested_tree(MAX_TREE_DEPTH)).is_ok());
+        let err = Object::decode_canonical(&nested_tree(MAX_TREE_DEPTH + 1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Would overflow the stack if decoded recursively
+        let err = Object::decode(&nested_tree(100_000)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // The same in the legacy bincode layout: a tree, then per level one
+        // entry that is a directory named `d`
+        let legacy = |levels: usize| {
+            let mut out = 1u32.to_le_bytes().to_vec();
+            for _ in 0..levels {
+                out.extend_from_slice(&1u64.to_le_bytes());
+                out.extend_from_slice(&1u32.to_le_bytes());
+                out.extend_from_slice(&1u64.to_le_bytes());
+                out.push(b'd');
+            }
+            out.extend_from_slice(&0u64.to_le_bytes());
+            out
+        };
+        let mut tree = Tree { entries: vec![] };
+        for _ in 0..2 {
+            tree = Tree { entries: vec![TreeEntry::Tree { name: "d".into(), tree }] };
+        }
+        assert_eq!(legacy(2), bincode::serialize(&Object::Tree(tree)).unwrap());
+        assert_eq!(Object::decode(&legacy(MAX_TREE_DEPTH)).unwrap().kind(), ObjectKind::Tree);
+        let err = Object::decode(&legacy(100_000)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
     }
 }
@@ -0,0 +1,186 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::digest::Output;
+use sha2::Sha256;
+
+/// Number of hex digits in a full object id.
+pub const HEX_LEN: usize = 64;
+
+/// Shortest prefix accepted when resolving abbreviated ids.
+pub const MIN_PREFIX_LEN: usize = 4;
+
+/// SHA-256 identifier of a stored object.
+///
+/// Ids are always 32 bytes, so anything built from an `ObjectId` (object
+/// paths, URLs) is guaranteed to be plain lowercase hex. Serde represents an
+/// id as its hex string in every format, which keeps JSON readable and lets
+/// commit logs written as bincode before version 3, when ids were `String`s,
+/// still be upgraded.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectId([u8; 32]);
+
+/// Error returned when a string is not a valid object id or id prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseObjectIdError(String);
+
+impl fmt::Display for ParseObjectIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseObjectIdError {}
+
+impl ObjectId {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.to_string()
+    }
+
+    /// Whether the hex form of this id starts with `prefix`.
+    pub fn starts_with(&self, prefix: &str) -> bool {
+        self.to_hex().starts_with(prefix)
+    }
+
+    /// Validate an abbreviated id as typed by a user, e.g. `hit show abc12`.
+    pub fn parse_prefix(prefix: &str) -> Result<&str, ParseObjectIdError> {
+        if prefix.len() < MIN_PREFIX_LEN || prefix.len() > HEX_LEN {
+            return Err(ParseObjectIdError(format!(
+                "object id prefix must be {} to {} hex digits",
+                MIN_PREFIX_LEN, HEX_LEN
+            )));
+        }
+        if !prefix.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(ParseObjectIdError(format!(
+                "object id prefix {:?} is not lowercase hex",
+                prefix
+            )));
+        }
+        Ok(prefix)
+    }
+}
+
+impl From<[u8; 32]> for ObjectId {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<Output<Sha256>> for ObjectId {
+    fn from(digest: Output<Sha256>) -> Self {
+        Self(digest.into())
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = ParseObjectIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != HEX_LEN {
+            return Err(ParseObjectIdError(format!(
+                "object id must be {} hex digits, got {:?}",
+                HEX_LEN, s
+            )));
+        }
+        let mut bytes = [0u8; 32];
+        for (i, pair) in s.as_bytes().chunks(2).enumerate() {
+            let hi = hex_value(pair[0]);
+            let lo = hex_value(pair[1]);
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => bytes[i] = hi << 4 | lo,
+                _ => {
+                    return Err(ParseObjectIdError(format!(
+                        "object id {:?} is not lowercase hex",
+                        s
+                    )));
+                }
+            }
+        }
+        Ok(Self(bytes))
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({})", self)
+    }
+}
+
+impl Serialize for ObjectId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for ObjectId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    #[test]
+    fn hex_roundtrip() {
+        let id = ObjectId::from(Sha256::digest(b"hello"));
+        let hex = id.to_hex();
+        assert_eq!(hex.len(), HEX_LEN);
+        assert_eq!(hex.parse::<ObjectId>().unwrap(), id);
+        assert!(id.starts_with(&hex[..6]));
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        assert!("abc".parse::<ObjectId>().is_err());
+        assert!("../../etc/passwd".parse::<ObjectId>().is_err());
+        assert!("A".repeat(HEX_LEN).parse::<ObjectId>().is_err());
+        assert!("g".repeat(HEX_LEN).parse::<ObjectId>().is_err());
+    }
+
+    #[test]
+    fn validates_prefixes() {
+        assert!(ObjectId::parse_prefix("abc1").is_ok());
+        assert!(ObjectId::parse_prefix("abc").is_err());
+        assert!(ObjectId::parse_prefix("ab/c").is_err());
+    }
+
+    #[test]
+    fn serde_uses_hex_in_json_and_bincode() {
+        let id = ObjectId::from([0xab; 32]);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", "ab".repeat(32)));
+        assert_eq!(serde_json::from_str::<ObjectId>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<ObjectId>("\"nope\"").is_err());
+
+        // Same layout as the `String` the id replaced.
+        let bytes = bincode::serialize(&id).unwrap();
+        assert_eq!(bytes, bincode::serialize(&id.to_hex()).unwrap());
+        assert_eq!(bincode::deserialize::<ObjectId>(&bytes).unwrap(), id);
+    }
+}
@@ -0,0 +1,341 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::server::Change;
+
+/// Changes detected by the watcher that the server has not accepted yet.
+pub const OUTBOX_PATH: &str = ".hit/outbox";
+
+/// File name of the outbox inside a repository's `.hit` directory.
+pub const OUTBOX_FILE: &str = "outbox";
+
+/// How sending the outbox has gone since it last succeeded, kept next to the
+/// outbox so `hit status` can report it while the watcher runs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryState {
+    /// Failed attempts since the last successful one.
+    pub failures: u32,
+    pub last_error: Option<String>,
+    /// Unix time of the last failed attempt.
+    pub last_attempt: Option<u64>,
+    /// Unix time before which no new attempt is made.
+    pub next_attempt: u64,
+}
+
+impl RetryState {
+    /// Whether an attempt may be made at `now`.
+    pub fn is_due(&self, now: u64) -> bool {
+        now >= self.next_attempt
+    }
+}
+
+/// A change the server refused for good, and why.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RejectedChange {
+    pub change: Change,
+    pub reason: String,
+}
+
+/// Held while an outbox file is read or changed. The watcher stores and
+/// uploads at the same time, and a change pushed while [`Outbox::remove_first`]
+/// rewrites the file would otherwise be lost.
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Durable queue of changes waiting to be sent, one JSON [`Change`] per
+/// line, oldest first.
+///
+/// A change is added before anything is sent and only removed once the
+/// server has accepted it, so nothing is lost if the server is unreachable
+/// or the watcher stops. Every outbox in the process shares one lock, so
+/// any number of clones may push and remove concurrently.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outbox {
+    path: PathBuf,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::at(OUTBOX_PATH)
+    }
+}
+
+impl Outbox {
+    pub fn at(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Queue `change` after those already pending.
+    pub fn push(&self, change: &Change) -> io::Result<()> {
+        let _guard = lock();
+        append_line(&self.path, change)
+    }
+
+    /// Every pending change, oldest first.
+    ///
+    /// A line left incomplete by a crash while pushing is skipped.
+    pub fn pending(&self) -> io::Result<Vec<Change>> {
+        let _guard = lock();
+        self.read()
+    }
+
+    fn read(&self) -> io::Result<Vec<Change>> {
+        read_lines(&self.path)
+    }
+
+    pub fn retry_state(&self) -> io::Result<RetryState> {
+        let text = match fs::read_to_string(self.state_path()) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(RetryState::default()),
+            Err(e) => return Err(e),
+        };
+        toml::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Record a failed attempt at `now` and schedule the next one, waiting
+    /// twice as long after each failure up to `max_backoff_secs`.
+    pub fn record_failure(&self, error: &str, now: u64, max_backoff_secs: u64) -> io::Result<RetryState> {
+        let mut state = self.retry_state()?;
+        let delay = 1u64
+            .checked_shl(state.failures)
+            .unwrap_or(u64::MAX)
+            .min(max_backoff_secs.max(1));
+        state.failures += 1;
+        state.last_error = Some(error.into());
+        state.last_attempt = Some(now);
+        state.next_attempt = now + delay;
+        let text = toml::to_string(&state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomic(&self.state_path(), text.as_bytes())?;
+        Ok(state)
+    }
+
+    /// Forget earlier failures once an attempt succeeds.
+    pub fn record_success(&self) -> io::Result<()> {
+        remove_if_exists(&self.state_path())
+    }
+
+    /// Set `change` aside for good because the server refused it. It is
+    /// kept with the reason so `hit status` can list it; the caller still
+    /// removes it from the pending changes.
+    pub fn reject(&self, change: &Change, reason: &str) -> io::Result<()> {
+        let _guard = lock();
+        let rejected = RejectedChange {
+            change: change.clone(),
+            reason: reason.into(),
+        };
+        append_line(&self.rejected_path(), &rejected)
+    }
+
+    /// Every change the server refused, oldest first.
+    pub fn rejected(&self) -> io::Result<Vec<RejectedChange>> {
+        let _guard = lock();
+        read_lines(&self.rejected_path())
+    }
+
+    fn rejected_path(&self) -> PathBuf {
+        self.path.with_extension("rejected")
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.path.with_extension("state")
+    }
+
+    /// Drop the oldest `count` pending changes once they have been sent.
+    pub fn remove_first(&self, count: usize) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let _guard = lock();
+        let rest: Vec<Change> = self.read()?.into_iter().skip(count).collect();
+        if rest.is_empty() {
+            return remove_if_exists(&self.path);
+        }
+        let mut text = Vec::new();
+        for change in &rest {
+            serde_json::to_writer(&mut text, change).map_err(io::Error::other)?;
+            text.push(b'\n');
+        }
+        write_atomic(&self.path, &text)
+    }
+}
+
+/// Append `value` as one JSON line to `path`.
+fn append_line<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut line = serde_json::to_vec(value).map_err(io::Error::other)?;
+    line.push(b'\n');
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    // Start a new line after an entry torn by a crash.
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            line.insert(0, b'\n');
+        }
+    }
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+/// Every JSON line of `path`, skipping any left incomplete by a crash.
+fn read_lines<T: DeserializeOwned>(path: &Path) -> io::Result<Vec<T>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut values = Vec::new();
+    for line in text.lines().filter(|l| !l.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(value) => values.push(value),
+            Err(e) => tracing::warn!(%e, path = %path.display(), "skipping unreadable outbox entry"),
+        }
+    }
+    Ok(values)
+}
+
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    fs::rename(&tmp, path)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::FileMode;
+    use crate::object_id::ObjectId;
+
+    fn change(n: u8) -> Change {
+        Change {
+            hash: ObjectId::from([n; 32]),
+            path: format!("file{}", n),
+            timestamp: n as u64,
+            client: None,
+            mode: FileMode::Regular,
+        }
+    }
+
+    #[test]
+    fn queues_changes_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::at(dir.path().join("outbox"));
+        assert!(outbox.pending().unwrap().is_empty());
+
+        for n in 1..=3 {
+            outbox.push(&change(n)).unwrap();
+        }
+        assert_eq!(outbox.pending().unwrap(), vec![change(1), change(2), change(3)]);
+
+        outbox.remove_first(2).unwrap();
+        assert_eq!(outbox.pending().unwrap(), vec![change(3)]);
+        outbox.remove_first(1).unwrap();
+        assert!(outbox.pending().unwrap().is_empty());
+        assert!(!dir.path().join("outbox").exists());
+    }
+
+    #[test]
+    fn keeps_rejected_changes_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::at(dir.path().join("outbox"));
+        outbox.push(&change(1)).unwrap();
+        outbox.push(&change(2)).unwrap();
+        outbox.reject(&change(1), "server responded with status 403 Forbidden").unwrap();
+        outbox.remove_first(1).unwrap();
+
+        assert_eq!(outbox.pending().unwrap(), vec![change(2)]);
+        let rejected = outbox.rejected().unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].change, change(1));
+        assert!(rejected[0].reason.contains("403"));
+    }
+
+    #[test]
+    fn skips_torn_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::at(dir.path().join("outbox"));
+        outbox.push(&change(1)).unwrap();
+        let mut file = fs::OpenOptions::new().append(true).open(dir.path().join("outbox")).unwrap();
+        file.write_all(b"{\"hash\":").unwrap();
+        assert_eq!(outbox.pending().unwrap(), vec![change(1)]);
+        outbox.push(&change(2)).unwrap();
+        assert_eq!(outbox.pending().unwrap(), vec![change(1), change(2)]);
+    }
+
+    #[test]
+    fn keeps_changes_pushed_while_removing() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::at(dir.path().join("outbox"));
+        let pusher = {
+            let outbox = outbox.clone();
+            std::thread::spawn(move || {
+                for n in 0..200 {
+                    outbox.push(&change(n)).unwrap();
+                }
+            })
+        };
+        let mut removed = 0;
+        while !pusher.is_finished() || removed < 100 {
+            if !outbox.pending().unwrap().is_empty() {
+                outbox.remove_first(1).unwrap();
+                removed += 1;
+            }
+        }
+        pusher.join().unwrap();
+
+        let expected: Vec<Change> = (removed..200).map(|n| change(n as u8)).collect();
+        assert_eq!(outbox.pending().unwrap(), expected);
+    }
+
+    #[test]
+    fn backs_off_after_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::at(dir.path().join("outbox"));
+        assert!(outbox.retry_state().unwrap().is_due(0));
+
+        let delays: Vec<u64> = (0..6)
+            .map(|_| outbox.record_failure("refused", 100, 10).unwrap().next_attempt - 100)
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+        let state = Outbox::at(dir.path().join("outbox")).retry_state().unwrap();
+        assert_eq!(state.failures, 6);
+        assert_eq!(state.last_error.as_deref(), Some("refused"));
+        assert!(!state.is_due(105));
+        assert!(state.is_due(110));
+
+        outbox.record_success().unwrap();
+        assert_eq!(outbox.retry_state().unwrap(), RetryState::default());
+    }
+}
@@ -1,8 +1,11 @@
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
 use tracing::info;
 
+/// Where this install's client id is kept; see [`client_id`].
+pub const CLIENT_ID_PATH: &str = ".hit/client_id";
+
 /// Initialize a new hit repository in the current directory.
 ///
 /// Creates the `.hit` directory along with required subdirectories and files.
@@ -36,5 +39,173 @@ pub fn init() -> std::io::Result<PathBuf> {
         File::create(&main_ref)?;
     }
 
+    client_id_at(&hit_dir.join("client_id"))?;
+
     Ok(hit_dir)
 }
+
+/// Id of this install, sent with every change so sync can tell its own
+/// changes apart from other clients'. Created on first use.
+pub fn client_id() -> io::Result<String> {
+    client_id_at(Path::new(CLIENT_ID_PATH))
+}
+
+fn client_id_at(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(id) if !id.trim().is_empty() => return Ok(id.trim().to_string()),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| io::Error::other(e.to_string()))?;
+    let id: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    fs::write(path, &id)?;
+    Ok(id)
+}
+
+/// `path` as changes name it: relative to the repository root, separated by
+/// `/`, without `.` or `..` components.
+///
+/// `path` may be absolute, as notify reports it, or relative to the current
+/// directory. Paths outside the repository are rejected.
+pub fn to_repo_path(path: &Path) -> io::Result<String> {
+    let root = std::env::current_dir()?;
+    let relative = if path.is_absolute() {
+        match path.strip_prefix(&root) {
+            Ok(rel) => rel.to_path_buf(),
+            // The root may be reached through a symlink, as /tmp is on macOS
+            Err(_) => path
+                .strip_prefix(root.canonicalize()?)
+                .map_err(|_| invalid_path(path.display(), "outside the repository"))?
+                .to_path_buf(),
+        }
+    } else {
+        path.to_path_buf()
+    };
+    let mut parts: Vec<&str> = Vec::new();
+    for component in relative.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                parts
+                    .pop()
+                    .ok_or_else(|| invalid_path(path.display(), "outside the repository"))?;
+            }
+            Component::Normal(name) => parts.push(
+                name.to_str()
+                    .ok_or_else(|| invalid_path(path.display(), "not valid UTF-8"))?,
+            ),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(invalid_path(path.display(), "outside the repository"));
+            }
+        }
+    }
+    let repo_path = parts.join("/");
+    if !is_valid_repo_path(&repo_path) {
+        return Err(invalid_path(path.display(), "not a file in the repository"));
+    }
+    Ok(repo_path)
+}
+
+/// Whether `path` is a normalized repository path as made by
+/// [`to_repo_path`]: relative, `/`-separated, with no empty, `.` or `..`
+/// components, and not inside `.hit`, in any case, as case-insensitive
+/// filesystems treat `.HIT` as the same directory.
+pub fn is_valid_repo_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.contains(['\\', '\0'])
+        && path.split('/').all(|part| !matches!(part, "" | "." | ".."))
+        && !path.split('/').next().is_some_and(|first| first.eq_ignore_ascii_case(".hit"))
+}
+
+/// Where the repository path `path` from a change lives on disk, under the
+/// current directory. Paths that could escape the repository are rejected.
+pub fn from_repo_path(path: &str) -> io::Result<PathBuf> {
+    if !is_valid_repo_path(path) {
+        return Err(invalid_path(path, "not a valid repository path"));
+    }
+    let mut local = std::env::current_dir()?;
+    local.extend(path.split('/'));
+    Ok(local)
+}
+
+fn invalid_path(path: impl std::fmt::Display, reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", path, reason))
+}
+
+/// Collect `(name, hash)` for every non-empty ref under `.hit/refs`.
+pub fn read_refs() -> io::Result<Vec<(String, String)>> {
+    read_refs_in(".hit")
+}
+
+/// Like [`read_refs`], for the refs under `dir/refs`, e.g. in a server's
+/// data directory.
+pub fn read_refs_in(dir: impl AsRef<Path>) -> io::Result<Vec<(String, String)>> {
+    let base = dir.as_ref();
+    let mut refs = Vec::new();
+    let root = base.join("refs");
+    if !root.exists() {
+        return Ok(refs);
+    }
+    let mut stack = vec![root];
+    while let Some(dir) = stack.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                stack.push(path);
+                continue;
+            }
+            let hash = fs::read_to_string(&path)?.trim().to_string();
+            if hash.is_empty() {
+                continue;
+            }
+            let name = path
+                .strip_prefix(base)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned();
+            refs.push((name, hash));
+        }
+    }
+    Ok(refs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_repo_paths() {
+        let root = std::env::current_dir().unwrap();
+        assert_eq!(to_repo_path(&root.join("src/x.rs")).unwrap(), "src/x.rs");
+        assert_eq!(to_repo_path(Path::new("./src/./a/../x.rs")).unwrap(), "src/x.rs");
+        for outside in [Path::new("../x"), Path::new("/elsewhere/x"), Path::new(".hit/objects/ab"), Path::new(".")] {
+            assert!(to_repo_path(outside).is_err(), "{:?}", outside);
+        }
+
+        for valid in ["a", "src/x.rs", ".hitignore", "a/.hit"] {
+            assert!(is_valid_repo_path(valid), "{}", valid);
+        }
+        for invalid in ["", "/etc/passwd", "a//b", "a/", "./a", "a/../../b", "..", ".hit", ".hit/HEAD", ".HIT/config", ".Hit", "a\\b"] {
+            assert!(!is_valid_repo_path(invalid), "{}", invalid);
+            assert!(from_repo_path(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(from_repo_path("src/x.rs").unwrap(), root.join("src").join("x.rs"));
+    }
+
+    #[test]
+    fn keeps_client_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client_id");
+        let id = client_id_at(&path).unwrap();
+        assert_eq!(id.len(), 32);
+        assert_eq!(client_id_at(&path).unwrap(), id);
+
+        let other = tempfile::tempdir().unwrap();
+        assert_ne!(client_id_at(&other.path().join("client_id")).unwrap(), id);
+    }
+}
@@ -1,32 +1,87 @@
-use crate::commit::{Commit, CommitStore};
-use crate::object::{Object, Hashable};
-use crate::storage::{write_object, read_object};
+use crate::auth::{AccessRules, AuthUser, Permissions, TokenStore};
+use crate::commit::{Commit, CommitQuery, CommitStore};
+use crate::config::{ServerConfig, TlsConfig};
+use crate::hosting::Repos;
+use crate::object::FileMode;
+use crate::object_id::ObjectId;
+use crate::storage::ObjectStore;
+use axum_server::tls_rustls::RustlsConfig;
 use axum::{
     Json, Router,
-    extract::{State, Path, rejection::JsonRejection},
-    http::StatusCode,
-    response::IntoResponse,
+    Extension,
+    extract::{DefaultBodyLimit, Query, Request, State, Path, rejection::JsonRejection},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::get,
     routing::post,
     routing::put,
-    body::Bytes,
+    body::Body,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::sync::broadcast;
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Change {
-    pub hash: String,
+    pub hash: ObjectId,
     pub path: String,
     pub timestamp: u64,
+    /// Id of the install that made the change, so its own sync can skip it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// How the file is checked out; regular unless given.
+    #[serde(default, skip_serializing_if = "FileMode::is_regular")]
+    pub mode: FileMode,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub commits: CommitStore,
     pub broadcaster: broadcast::Sender<ChangeEvent>,
+    pub objects: ObjectStore,
+    pub limits: Limits,
+    /// Tokens requests must present; `None` lets anyone in.
+    pub auth: Option<Arc<TokenStore>>,
+    /// What authenticated users may do.
+    pub access: Arc<AccessRules>,
+    /// What users limited to some paths may read, kept across requests.
+    pub readable: ReadableObjects,
+}
+
+impl AppState {
+    /// State serving the object store in the current directory with the
+    /// default limits and without authentication.
+    pub fn new(commits: CommitStore, broadcaster: broadcast::Sender<ChangeEvent>) -> Self {
+        Self {
+            commits,
+            broadcaster,
+            objects: ObjectStore::default(),
+            limits: Limits::from(&ServerConfig::default()),
+            auth: None,
+            access: Arc::default(),
+            readable: ReadableObjects::default(),
+        }
+    }
+}
+
+/// Request size limits, see [`ServerConfig`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_request_bytes: usize,
+    pub max_object_bytes: u64,
+}
+
+impl From<&ServerConfig> for Limits {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            max_request_bytes: config.max_request_bytes,
+            max_object_bytes: config.max_object_bytes,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -36,19 +91,66 @@ pub struct ChangeEvent {
     pub commit_id: u64,
 }
 
+/// Client ids are short tokens of letters, digits, `-` and `_`, as made by
+/// [`crate::repo::client_id`].
+fn is_valid_client_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
 async fn change_handler(
     State(state): State<AppState>,
+    user: Option<Extension<AuthUser>>,
+    Extension(permissions): Extension<Permissions>,
     payload: Result<Json<Change>, JsonRejection>,
 ) -> Result<impl IntoResponse, StatusCode> {
     let Json(change) = match payload {
         Ok(c) => c,
         Err(e) => {
             tracing::warn!("invalid change payload: {}", e);
+            if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
             return Err(StatusCode::BAD_REQUEST);
         }
     };
     tracing::info!("change received: {:?}", change);
-    let commit = match state.commits.add_commit(change.clone()) {
+    if !crate::repo::is_valid_repo_path(&change.path) {
+        tracing::warn!(path = %change.path, "invalid change path");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if let Some(client) = &change.client
+        && !is_valid_client_id(client)
+    {
+        tracing::warn!(%client, "invalid client id");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !permissions.can_write(&change.path) {
+        tracing::warn!(path = %change.path, "change not permitted");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    // Syncing clients fetch the object as soon as the change is broadcast,
+    // so it and everything it references must already be stored.
+    let objects = state.objects.clone();
+    let hash = change.hash;
+    let missing = match tokio::task::spawn_blocking(move || objects.missing(&hash)).await {
+        Ok(Ok(missing)) => missing,
+        Ok(Err(e)) => {
+            tracing::error!("failed to check objects of {}: {}", hash, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        Err(e) => {
+            tracing::error!("object check task failed: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    if !missing.is_empty() {
+        tracing::warn!(%hash, missing = missing.len(), "rejected change with missing objects");
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(MissingResponse { missing })).into_response());
+    }
+    let author = user.map(|Extension(AuthUser(name))| name);
+    let commit = match state.commits.add_commit_by(change.clone(), author).await {
         Ok(c) => c,
         Err(e) => {
             tracing::error!("failed to create commit: {}", e);
@@ -62,11 +164,48 @@ async fn change_handler(
     }) {
         tracing::warn!("failed to broadcast change: {}", e);
     }
-    Ok(Json(json!({"accepted": true})))
+    Ok(Json(json!({"accepted": true})).into_response())
 }
 
-async fn commits_handler(State(state): State<AppState>) -> Result<Json<Vec<Commit>>, StatusCode> {
-    match state.commits.all() {
+/// Commits returned by `GET /commits` when no `limit` is given.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+/// Largest `limit` accepted by `GET /commits`.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// `GET /commits` takes the filters of a [`CommitQuery`] as query
+/// parameters. Clients page through the history by passing the id of the
+/// last commit they received as `after`.
+async fn commits_handler(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Query(mut query): Query<CommitQuery>,
+) -> Result<Json<Vec<Commit>>, StatusCode> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    query.limit = Some(limit);
+    let commits = state.commits.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        // Commits the user may not see are left out, so keep reading until
+        // the page is full or the history ends.
+        let mut page = Vec::new();
+        loop {
+            let batch = commits.query(&query).map_err(|e| e.to_string())?;
+            let done = batch.len() < limit;
+            if let Some(last) = batch.last() {
+                query.after = last.id;
+            }
+            page.extend(batch.into_iter().filter_map(|c| permissions.visible(c)));
+            if done || page.len() >= limit {
+                page.truncate(limit);
+                return Ok::<_, String>(page);
+            }
+        }
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!("commit page task failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    match result {
         Ok(list) => Ok(Json(list)),
         Err(e) => {
             tracing::error!("failed to fetch commits: {}", e);
@@ -75,9 +214,20 @@ async fn commits_handler(State(state): State<AppState>) -> Result<Json<Vec<Commi
     }
 }
 
-async fn latest_commit_handler(State(state): State<AppState>) -> impl IntoResponse {
+/// The newest commit, for clients to learn the current commit id. Changes
+/// the user may not see are left out, and a commit with none left is not
+/// found.
+async fn latest_commit_handler(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+) -> impl IntoResponse {
+    // Kept in memory by the commit store, so there is no I/O to move off
+    // the runtime
     match state.commits.latest() {
-        Ok(Some(c)) => Json(c).into_response(),
+        Ok(Some(c)) => match permissions.visible(c) {
+            Some(c) => Json(c).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
         Ok(None) => StatusCode::NOT_FOUND.into_response(),
         Err(e) => {
             tracing::error!("failed to fetch latest commit: {}", e);
@@ -86,50 +236,201 @@ async fn latest_commit_handler(State(state): State<AppState>) -> impl IntoRespon
     }
 }
 
+async fn commit_handler(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(id): Path<u64>,
+) -> impl IntoResponse {
+    let commits = state.commits.clone();
+    let found = match tokio::task::spawn_blocking(move || commits.get(id).map_err(|e| e.to_string())).await {
+        Ok(found) => found,
+        Err(e) => {
+            tracing::error!("commit lookup task failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match found {
+        Ok(Some(c)) => match permissions.visible(c) {
+            Some(c) => Json(c).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            tracing::error!("failed to fetch commit {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
 async fn store_object_handler(
-    Path(hash): Path<String>,
-    body: Bytes,
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(hash): Path<ObjectId>,
+    body: Body,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Deserialize the object from the request body
-    let obj: Object = match bincode::deserialize(&body) {
-        Ok(obj) => obj,
-        Err(e) => {
-            tracing::warn!("failed to deserialize object: {}", e);
-            return Err(StatusCode::BAD_REQUEST);
+    if !permissions.can_upload() {
+        tracing::warn!(%hash, "upload not permitted");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    // Stream the body to disk; the object is only moved into the store once
+    // its hash has been verified.
+    let mut import = state.objects.import().map_err(|e| {
+        tracing::error!("failed to prepare object {}: {}", hash, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut stream = body.into_data_stream();
+    let received = async {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| {
+                tracing::warn!("failed to read object body: {}", e);
+                StatusCode::BAD_REQUEST
+            })?;
+            if import.len() + chunk.len() as u64 > state.limits.max_object_bytes {
+                tracing::warn!(
+                    "rejected object {}: larger than {} bytes",
+                    hash,
+                    state.limits.max_object_bytes
+                );
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            import.write(&chunk).map_err(|e| {
+                tracing::error!("failed to buffer object {}: {}", hash, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
         }
+        Ok(())
     };
-    
-    // Verify the hash matches
-    if obj.hash() != hash {
-        tracing::warn!("hash mismatch: expected {}, got {}", hash, obj.hash());
-        return Err(StatusCode::BAD_REQUEST);
+    // No partial object is left behind, whatever went wrong
+    if let Err(status) = received.await {
+        import.abort();
+        return Err(status);
     }
-    
-    // Store the object
-    if let Err(e) = write_object(&obj) {
+
+    let finished = tokio::task::spawn_blocking(move || import.finish(&hash))
+        .await
+        .map_err(|e| {
+            tracing::error!("object import task failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if let Err(e) = finished {
+        if e.kind() == std::io::ErrorKind::InvalidData {
+            tracing::warn!("rejected object {}: {}", hash, e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
         tracing::error!("failed to store object {}: {}", hash, e);
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
-    
+
     tracing::info!("stored object {}", hash);
     Ok(Json(json!({"stored": true})))
 }
 
-async fn get_object_handler(Path(hash): Path<String>) -> Result<impl IntoResponse, StatusCode> {
-    match read_object(&hash) {
-        Ok(obj) => {
-            // Serialize the object for response
-            match bincode::serialize(&obj) {
-                Ok(bytes) => Ok((
-                    [("Content-Type", "application/octet-stream")],
-                    bytes,
-                ).into_response()),
-                Err(e) => {
-                    tracing::error!("failed to serialize object {}: {}", hash, e);
-                    Err(StatusCode::INTERNAL_SERVER_ERROR)
+/// Objects readable by users limited to some paths, kept per set of path
+/// limits. Each set is brought up to date with the commits appended since
+/// it was last used, so a request only reads new history.
+#[derive(Clone, Default)]
+pub struct ReadableObjects(Arc<Mutex<HashMap<Vec<String>, SharedSet>>>);
+
+type SharedSet = Arc<Mutex<ReadableSet>>;
+
+impl ReadableObjects {
+    fn set(&self, paths: &[String]) -> SharedSet {
+        let mut sets = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        sets.entry(paths.to_vec()).or_default().clone()
+    }
+}
+
+/// The objects of the changes a user can see, and the chunks those
+/// reference.
+#[derive(Default)]
+struct ReadableSet {
+    /// Id of the newest commit included.
+    after: u64,
+    objects: HashSet<ObjectId>,
+}
+
+impl ReadableSet {
+    /// Include the commits appended since the last update.
+    fn update(&mut self, commits: &CommitStore, objects: &ObjectStore, permissions: &Permissions) -> Result<(), String> {
+        for commit in commits.commits_after(self.after).map_err(|e| e.to_string())? {
+            let commit = commit.map_err(|e| e.to_string())?;
+            for change in commit.changes.iter().filter(|c| permissions.can_read(&c.path)) {
+                if self.objects.insert(change.hash) {
+                    // An object that is gone references nothing
+                    self.objects.extend(objects.read_references(&change.hash).unwrap_or_default());
                 }
             }
+            self.after = commit.id;
+        }
+        Ok(())
+    }
+}
+
+fn lock_set(set: &Mutex<ReadableSet>) -> MutexGuard<'_, ReadableSet> {
+    set.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The objects a user limited to some paths may read, see [`ReadableSet`].
+/// `None` if every object may be read.
+async fn readable_objects(
+    state: &AppState,
+    permissions: &Permissions,
+) -> Result<Option<SharedSet>, StatusCode> {
+    if permissions.can_read_all() {
+        return Ok(None);
+    }
+    let set = state.readable.set(&permissions.paths);
+    let latest = state.commits.latest().ok().flatten().map(|c| c.id);
+    if latest.is_none_or(|id| id <= lock_set(&set).after) {
+        return Ok(Some(set));
+    }
+    let commits = state.commits.clone();
+    let objects = state.objects.clone();
+    let permissions = permissions.clone();
+    let updated = set.clone();
+    let result = tokio::task::spawn_blocking(move || lock_set(&updated).update(&commits, &objects, &permissions))
+        .await
+        .map_err(|e| {
+            tracing::error!("readable objects task failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    match result {
+        Ok(()) => Ok(Some(set)),
+        Err(e) => {
+            tracing::error!("failed to find readable objects: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
+    }
+}
+
+/// Whether `hash` may be read, as far as [`readable_objects`] knows.
+async fn can_read_object(
+    state: &AppState,
+    permissions: &Permissions,
+    hash: &ObjectId,
+) -> Result<bool, StatusCode> {
+    Ok(readable_objects(state, permissions)
+        .await?
+        .is_none_or(|readable| lock_set(&readable).objects.contains(hash)))
+}
+
+/// An object's content. Objects the user may not read are not found, so
+/// their existence is not revealed either.
+async fn get_object_handler(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(hash): Path<ObjectId>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !can_read_object(&state, &permissions, &hash).await? {
+        tracing::warn!(%hash, "object read not permitted");
+        return Err(StatusCode::NOT_FOUND);
+    }
+    match tokio::fs::File::open(state.objects.object_path(&hash)).await {
+        Ok(file) => Ok((
+            [("Content-Type", "application/octet-stream")],
+            Body::from_stream(ReaderStream::new(file)),
+        )
+            .into_response()),
         Err(e) => {
             tracing::warn!("object {} not found: {}", hash, e);
             Err(StatusCode::NOT_FOUND)
@@ -137,48 +438,203 @@ async fn get_object_handler(Path(hash): Path<String>) -> Result<impl IntoRespons
     }
 }
 
+/// Whether the object is stored, without sending it: `200` with its size
+/// as `Content-Length`, or `404`, also for objects the user may not read.
+async fn head_object_handler(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    Path(hash): Path<ObjectId>,
+) -> Response {
+    match can_read_object(&state, &permissions, &hash).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(status) => return status.into_response(),
+    }
+    match tokio::fs::metadata(state.objects.object_path(&hash)).await {
+        Ok(meta) => [(header::CONTENT_LENGTH, meta.len())].into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MissingRequest {
+    pub hashes: Vec<ObjectId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MissingResponse {
+    /// The requested hashes the server does not have, in request order.
+    pub missing: Vec<ObjectId>,
+}
+
+/// Which of a batch of objects the server lacks, so clients only upload
+/// those. Objects the user may not read are reported missing, so their
+/// existence is not revealed; uploading them again does no harm.
+async fn missing_objects_handler(
+    State(state): State<AppState>,
+    Extension(permissions): Extension<Permissions>,
+    payload: Result<Json<MissingRequest>, JsonRejection>,
+) -> Response {
+    let Json(request) = match payload {
+        Ok(payload) => payload,
+        Err(rejection) => {
+            tracing::warn!("invalid missing objects request: {}", rejection);
+            return rejection.into_response();
+        }
+    };
+    let readable = match readable_objects(&state, &permissions).await {
+        Ok(readable) => readable,
+        Err(status) => return status.into_response(),
+    };
+    let mut missing = Vec::new();
+    for hash in request.hashes {
+        if missing.contains(&hash) {
+            continue;
+        }
+        let hidden = readable.as_ref().is_some_and(|r| !lock_set(r).objects.contains(&hash));
+        if hidden || tokio::fs::metadata(state.objects.object_path(&hash)).await.is_err() {
+            missing.push(hash);
+        }
+    }
+    Json(MissingResponse { missing }).into_response()
+}
+
+/// Reject requests without a valid `Authorization: Bearer` token and record
+/// who made the others as an [`AuthUser`], along with their
+/// [`Permissions`].
+pub(crate) async fn require_auth(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(tokens) = &state.auth else {
+        request.extensions_mut().insert(Permissions::unrestricted());
+        return next.run(request).await;
+    };
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let user = match token.map(|t| tokens.authenticate(t.trim())) {
+        Some(Ok(Some(user))) => user,
+        Some(Err(e)) => {
+            tracing::error!("failed to check token: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        _ => {
+            tracing::warn!(uri = %request.uri(), "rejected unauthenticated request");
+            return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response();
+        }
+    };
+    request.extensions_mut().insert(state.access.permissions(&user));
+    request.extensions_mut().insert(AuthUser(user));
+    next.run(request).await
+}
+
 pub fn app(state: AppState) -> Router {
     let changes = Router::new()
         .route("/changes", post(change_handler))
         .route("/commits", get(commits_handler))
         .route("/commits/latest", get(latest_commit_handler))
-        .route("/objects/:hash", put(store_object_handler))
-        .route("/objects/:hash", get(get_object_handler))
+        .route("/commits/:id", get(commit_handler))
+        .route("/objects/missing", post(missing_objects_handler))
+        .layer(DefaultBodyLimit::max(state.limits.max_request_bytes))
+        // Object uploads are streamed and checked against `max_object_bytes`.
+        .route(
+            "/objects/:hash",
+            put(store_object_handler).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/objects/:hash", get(get_object_handler).head(head_object_handler))
         .with_state(state.clone());
-    let stream = crate::streaming::router(crate::streaming::Broadcaster::new(
+    let stream = crate::streaming::router(crate::streaming::Broadcaster::with_history(
         state.broadcaster.clone(),
+        state.commits.clone(),
     ));
-    changes.merge(stream)
+    changes
+        .merge(stream)
+        .layer(middleware::from_fn_with_state(state, require_auth))
 }
 
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+fn auth(config: &ServerConfig) -> std::io::Result<Option<Arc<TokenStore>>> {
+    if config.allow_anonymous {
+        tracing::warn!("authentication is disabled; anyone who can reach the server can change files");
+        return Ok(None);
+    }
+    let tokens = TokenStore::in_dir(&config.data_dir);
+    if tokens.list()?.is_empty() {
+        tracing::warn!("no tokens issued yet; create one with `hit serve token add <user>`");
+    }
+    Ok(Some(Arc::new(tokens)))
+}
 
-pub async fn start_server() -> Result<(), Box<dyn Error>> {
-    std::fs::create_dir_all(".hit")?;
-    std::fs::create_dir_all(".hit/objects")?;
-    let commits = CommitStore::with_log(".hit/commits.log").map_err(|e| {
-        tracing::error!("failed to initialize commit log: {}", e);
+pub async fn start_server(config: ServerConfig) -> Result<(), Box<dyn Error>> {
+    let objects = ObjectStore::in_dir(&config.data_dir);
+    std::fs::create_dir_all(objects.dir())?;
+    let commits = CommitStore::open(config.commit_backend, &config.data_dir).map_err(|e| {
+        tracing::error!("failed to initialize commit store: {}", e);
         e
     })?;
-    let (tx, _) = broadcast::channel(100);
+    let (tx, _) = broadcast::channel(config.broadcast_capacity);
     let state = AppState {
         commits,
         broadcaster: tx,
+        objects,
+        limits: Limits::from(&config),
+        auth: auth(&config)?,
+        access: Arc::new(AccessRules::from(&config)),
+        readable: ReadableObjects::default(),
     };
-    let app = app(state);
-    let addr = "0.0.0.0:8888";
+    let repos = Repos::open(
+        &config.data_dir,
+        config.commit_backend,
+        config.broadcast_capacity,
+        state.clone(),
+    )?;
+    let app = crate::hosting::app(state, Arc::new(repos));
+    let addr = config.bind.as_str();
+    tracing::info!("serving {}", config.data_dir.display());
     let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| {
         tracing::error!("failed to bind to {}: {}", addr, e);
         e
     })?;
-    tracing::info!("listening on http://{}", addr);
-    axum::serve(listener, app).await.map_err(|e| {
+    serve(listener, app, config.tls.as_ref()).await.map_err(|e| {
         tracing::error!("server error: {}", e);
         e
     })?;
     Ok(())
 }
 
+/// Serve `app` on `listener`, over HTTPS if `tls` is given.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    tls: Option<&TlsConfig>,
+) -> std::io::Result<()> {
+    let addr = listener.local_addr()?;
+    let Some(tls) = tls else {
+        tracing::info!("listening on http://{}", addr);
+        return axum::serve(listener, app).await;
+    };
+    let rustls = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await.map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("cannot load {} and {}: {}", tls.cert.display(), tls.key.display(), e),
+        )
+    })?;
+    // Clients without a CA for this certificate can pin this instead.
+    let fingerprint = crate::tls::cert_fingerprint(&tls.cert)?;
+    tracing::info!(%fingerprint, "listening on https://{}", addr);
+    axum_server::from_tcp_rustls(listener.into_std()?, rustls)
+        .serve(app.into_make_service())
+        .await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -189,20 +645,36 @@ mod tests {
     use tokio_stream::StreamExt;
     use tower::ServiceExt; // for `oneshot`
 
+    /// Store the objects of `state` in a new temporary directory holding
+    /// `count` blobs, and return it with their hashes.
+    fn store_blobs(state: &mut AppState, count: u8) -> (tempfile::TempDir, Vec<ObjectId>) {
+        use crate::object::{Blob, Object};
+
+        let dir = tempfile::tempdir().unwrap();
+        state.objects = ObjectStore::in_dir(dir.path());
+        let hashes = (0..count)
+            .map(|i| {
+                let blob = Object::Blob(Blob { content: vec![i] });
+                state.objects.write_object(&blob).unwrap()
+            })
+            .collect();
+        (dir, hashes)
+    }
+
     #[tokio::test]
     async fn accepts_post() {
         let commits = CommitStore::default();
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx,
-        };
+        let mut state = AppState::new(commits.clone(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 1);
         let app = app(state);
 
         let change = Change {
-            hash: "abc".into(),
+            hash: hashes[0],
             path: "src/lib.rs".into(),
             timestamp: 1,
+            client: None,
+            mode: FileMode::Regular,
         };
         let req = Request::builder()
             .method("POST")
@@ -224,10 +696,7 @@ mod tests {
     async fn rejects_invalid_json() {
         let commits = CommitStore::default();
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx,
-        };
+        let state = AppState::new(commits.clone(), tx);
         let app = app(state);
 
         let req = Request::builder()
@@ -245,10 +714,7 @@ mod tests {
     async fn rejects_missing_field() {
         let commits = CommitStore::default();
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx,
-        };
+        let state = AppState::new(commits.clone(), tx);
         let app = app(state);
 
         let body = json!({"path": "x", "timestamp": 1});
@@ -265,28 +731,18 @@ mod tests {
 
     #[tokio::test]
     async fn returns_500_on_commit_error() {
-        let commits = CommitStore::default();
-        // poison the mutex
-        {
-            let c = commits.clone();
-            std::thread::spawn(move || {
-                let _guard = c.commits.lock().unwrap();
-                panic!("boom");
-            })
-            .join()
-            .ok();
-        }
+        let commits = CommitStore::with_backend(crate::commit::FailingBackend::default());
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx,
-        };
+        let mut state = AppState::new(commits.clone(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 1);
         let app = app(state);
 
         let change = Change {
-            hash: "x".into(),
+            hash: hashes[0],
             path: "f".into(),
             timestamp: 1,
+            client: None,
+            mode: FileMode::Regular,
         };
         let req = Request::builder()
             .method("POST")
@@ -302,16 +758,16 @@ mod tests {
     async fn creates_commit_on_change() {
         let commits = CommitStore::default();
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx,
-        };
+        let mut state = AppState::new(commits.clone(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 1);
         let app = app(state);
 
         let change = Change {
-            hash: "h1".into(),
+            hash: hashes[0],
             path: "f".into(),
             timestamp: 1,
+            client: None,
+            mode: FileMode::Regular,
         };
         let req = Request::builder()
             .method("POST")
@@ -321,7 +777,7 @@ mod tests {
             .unwrap();
         let resp = app.oneshot(req).await.unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
-        let commits_vec = commits.commits.lock().unwrap();
+        let commits_vec = commits.all().unwrap();
         assert_eq!(commits_vec.len(), 1);
         assert_eq!(commits_vec[0].id, 1);
     }
@@ -330,17 +786,17 @@ mod tests {
     async fn stores_multiple_changes() {
         let commits = CommitStore::default();
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx,
-        };
+        let mut state = AppState::new(commits.clone(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 2);
         let app = app(state);
 
         for i in 0..2 {
             let change = Change {
-                hash: format!("h{i}"),
+                hash: hashes[i as usize],
                 path: "file".into(),
                 timestamp: i,
+                client: None,
+                mode: FileMode::Regular,
             };
             let req = Request::builder()
                 .method("POST")
@@ -355,13 +811,472 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn streams_changes() {
+    async fn rejects_changes_with_missing_objects() {
+        use crate::object::{ChunkedBlob, Object};
+
         let commits = CommitStore::default();
+        let (tx, mut rx) = broadcast::channel(8);
+        let mut state = AppState::new(commits.clone(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 1);
+        let absent = ObjectId::from([9; 32]);
+        let manifest = state
+            .objects
+            .write_object(&Object::Chunked(ChunkedBlob {
+                size: 2,
+                chunks: vec![hashes[0], absent],
+            }))
+            .unwrap();
+        let app = app(state);
+
+        for hash in [absent, manifest] {
+            let change = Change {
+                hash,
+                path: "f".into(),
+                timestamp: 1,
+                client: None,
+                mode: FileMode::Regular,
+            };
+            let req = Request::builder()
+                .method("POST")
+                .uri("/changes")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&change).unwrap()))
+                .unwrap();
+            let resp = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            let missing: MissingResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(missing.missing, vec![absent]);
+        }
+        assert!(commits.all().unwrap().is_empty());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn records_client_ids() {
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx.clone(),
+        let mut state = AppState::new(CommitStore::default(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 1);
+        let app = app(state);
+        let post = |client: Option<&str>| {
+            let change = Change {
+                hash: hashes[0],
+                path: "f".into(),
+                timestamp: 1,
+                client: client.map(String::from),
+                mode: FileMode::Regular,
+            };
+            Request::builder()
+                .method("POST")
+                .uri("/changes")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&change).unwrap()))
+                .unwrap()
         };
+
+        let too_long = "a".repeat(65);
+        for bad in ["", "has space", "../x", too_long.as_str()] {
+            let res = app.clone().oneshot(post(Some(bad))).await.unwrap();
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{:?}", bad);
+        }
+        for client in [Some("laptop-1_a"), None] {
+            let res = app.clone().oneshot(post(client)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let res = app
+            .oneshot(Request::builder().uri("/commits").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(text.matches("\"client\":\"laptop-1_a\"").count(), 1, "{}", text);
+        assert_eq!(text.matches("\"client\"").count(), 1, "{}", text);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_paths() {
+        let commits = CommitStore::default();
+        let (tx, _) = broadcast::channel(8);
+        let mut state = AppState::new(commits.clone(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 1);
+        let app = app(state);
+
+        for (path, status) in [
+            ("/home/alice/proj/x.rs", StatusCode::BAD_REQUEST),
+            ("../x.rs", StatusCode::BAD_REQUEST),
+            ("src/../../x.rs", StatusCode::BAD_REQUEST),
+            ("./x.rs", StatusCode::BAD_REQUEST),
+            (".hit/config", StatusCode::BAD_REQUEST),
+            ("src/x.rs", StatusCode::OK),
+        ] {
+            let change = Change {
+                hash: hashes[0],
+                path: path.into(),
+                timestamp: 1,
+                client: None,
+                mode: FileMode::Regular,
+            };
+            let req = Request::builder()
+                .method("POST")
+                .uri("/changes")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&change).unwrap()))
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), status, "{}", path);
+        }
+        let paths: Vec<String> = commits.all().unwrap().into_iter().map(|c| c.changes[0].path.clone()).collect();
+        assert_eq!(paths, vec!["src/x.rs"]);
+    }
+
+    #[tokio::test]
+    async fn reports_stored_and_missing_objects() {
+        use crate::object::{Blob, Object};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _) = broadcast::channel(8);
+        let mut state = AppState::new(CommitStore::default(), tx);
+        state.objects = ObjectStore::in_dir(dir.path());
+        let stored = Object::Blob(Blob { content: b"stored".to_vec() });
+        let stored_id = state.objects.write_object(&stored).unwrap();
+        let absent = ObjectId::from([9; 32]);
+        let app = app(state);
+
+        let head = |hash: ObjectId| {
+            Request::builder()
+                .method("HEAD")
+                .uri(format!("/objects/{}", hash))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = app.clone().oneshot(head(stored_id)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()[header::CONTENT_LENGTH],
+            stored.encode().len().to_string()
+        );
+        let res = app.clone().oneshot(head(absent)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/objects/missing")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        json!({"hashes": [stored_id, absent, absent]}).to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let missing: MissingResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(missing.missing, vec![absent]);
+
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/objects/missing")
+                    .header("content-type", "application/json")
+                    .body(Body::from(r#"{"hashes": ["nothex"]}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(res.status().is_client_error());
+    }
+
+    #[tokio::test]
+    async fn enforces_size_limits() {
+        use crate::object::{Blob, Hashable, Object};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _) = broadcast::channel(8);
+        let mut state = AppState::new(CommitStore::default(), tx);
+        state.objects = ObjectStore::in_dir(dir.path());
+        state.limits = Limits {
+            max_request_bytes: 64,
+            max_object_bytes: 16,
+        };
+        let app = app(state);
+
+        let big = Object::Blob(Blob { content: vec![7; 64] });
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(format!("/objects/{}", big.hash()))
+                    .body(Body::from(big.encode()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!dir.path().join("objects").join(big.hash().to_string()).exists());
+        assert_eq!(std::fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+
+        let small = Object::Blob(Blob { content: b"hi".to_vec() });
+        let res = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(format!("/objects/{}", small.hash()))
+                    .body(Body::from(small.encode()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(dir.path().join("objects").join(small.hash().to_string()).exists());
+
+        let change = json!({"hash": small.hash(), "path": "x".repeat(100), "timestamp": 1});
+        let res = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/changes")
+                    .header("content-type", "application/json")
+                    .body(Body::from(change.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn requires_token_and_records_author() {
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = TokenStore::in_dir(dir.path());
+        let token = tokens.issue("ada").unwrap();
+        let commits = CommitStore::default();
+        let (tx, _) = broadcast::channel(8);
+        let mut state = AppState::new(commits.clone(), tx);
+        state.auth = Some(Arc::new(tokens));
+        let (_objects, hashes) = store_blobs(&mut state, 1);
+        let app = app(state);
+
+        let change = json!({"hash": hashes[0], "path": "a.txt", "timestamp": 1});
+        let post = |auth: Option<String>| {
+            let mut req = Request::builder()
+                .method("POST")
+                .uri("/changes")
+                .header("content-type", "application/json");
+            if let Some(auth) = auth {
+                req = req.header("authorization", auth);
+            }
+            req.body(Body::from(change.to_string())).unwrap()
+        };
+
+        for auth in [None, Some("Bearer hit_wrong".to_string()), Some(token.clone())] {
+            let res = app.clone().oneshot(post(auth)).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(res.headers()["www-authenticate"], "Bearer");
+        }
+        let res = app
+            .clone()
+            .oneshot(Request::builder().uri("/commits").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(commits.all().unwrap().is_empty());
+
+        let res = app.oneshot(post(Some(format!("Bearer {}", token)))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let all = commits.all().unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].author.as_deref(), Some("ada"));
+    }
+
+    #[tokio::test]
+    async fn enforces_roles_and_paths() {
+        use crate::config::{Role, UserAccess};
+
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = TokenStore::in_dir(dir.path());
+        let ada = tokens.issue("ada").unwrap();
+        let eve = tokens.issue("eve").unwrap();
+        let mut config = ServerConfig {
+            default_role: Role::Reader,
+            ..ServerConfig::default()
+        };
+        config.users.insert(
+            "ada".into(),
+            UserAccess {
+                role: Some(Role::Writer),
+                paths: vec!["docs/".into()],
+            },
+        );
+        let commits = CommitStore::default();
+        for path in ["docs/a", "src/b", "src/c", "docs/d"] {
+            let change = Change {
+                hash: ObjectId::from([1; 32]),
+                path: path.into(),
+                timestamp: 1,
+                client: None,
+                mode: FileMode::Regular,
+            };
+            commits.add_commit(change).await.unwrap();
+        }
+        let (tx, _) = broadcast::channel(8);
+        let mut state = AppState::new(commits.clone(), tx);
+        state.auth = Some(Arc::new(tokens));
+        state.access = Arc::new(AccessRules::from(&config));
+        let (_objects, hashes) = store_blobs(&mut state, 1);
+        let app = app(state);
+
+        let request = |token: &str, method: &str, uri: &str, body: Body| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", token))
+                .header("content-type", "application/json")
+                .body(body)
+                .unwrap()
+        };
+        let post = |token: &str, path: &str| {
+            let change = json!({"hash": hashes[0], "path": path, "timestamp": 1});
+            request(token, "POST", "/changes", Body::from(change.to_string()))
+        };
+        let status = |req: Request<Body>| {
+            let app = app.clone();
+            async move { app.oneshot(req).await.unwrap().status() }
+        };
+        let ids = |req: Request<Body>| {
+            let app = app.clone();
+            async move {
+                let res = app.oneshot(req).await.unwrap();
+                let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                let list: Vec<Commit> = serde_json::from_slice(&body).unwrap();
+                list.iter().map(|c| c.id).collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(status(post(&ada, "src/x")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(post(&ada, "docs/../src/x")).await, StatusCode::BAD_REQUEST);
+        assert_eq!(status(post(&ada, "docs/x")).await, StatusCode::OK);
+        assert_eq!(status(post(&eve, "docs/x")).await, StatusCode::FORBIDDEN);
+        let upload = request(&eve, "PUT", &format!("/objects/{}", ObjectId::from([3; 32])), Body::from("x"));
+        assert_eq!(status(upload).await, StatusCode::FORBIDDEN);
+
+        assert_eq!(ids(request(&eve, "GET", "/commits", Body::empty())).await, vec![1, 2, 3, 4, 5]);
+        assert_eq!(ids(request(&ada, "GET", "/commits", Body::empty())).await, vec![1, 4, 5]);
+        assert_eq!(ids(request(&ada, "GET", "/commits?after=1&limit=1", Body::empty())).await, vec![4]);
+        assert_eq!(status(request(&ada, "GET", "/commits/2", Body::empty())).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(request(&ada, "GET", "/commits/4", Body::empty())).await, StatusCode::OK);
+
+        let res = app.clone().oneshot(request(&ada, "GET", "/events?after=1", Body::empty())).await.unwrap();
+        let mut stream = res.into_body().into_data_stream();
+        let mut bytes = Vec::new();
+        while !bytes.ends_with(b"\n\n") {
+            bytes.extend_from_slice(&stream.next().await.unwrap().unwrap());
+        }
+        let first = String::from_utf8(bytes).unwrap();
+        assert!(first.lines().any(|l| l == "id: 4"), "{}", first);
+    }
+
+    #[tokio::test]
+    async fn limits_objects_to_readable_paths() {
+        use crate::config::{Role, UserAccess};
+
+        let dir = tempfile::tempdir().unwrap();
+        let tokens = TokenStore::in_dir(dir.path());
+        let ada = tokens.issue("ada").unwrap();
+        let eve = tokens.issue("eve").unwrap();
+        let mut config = ServerConfig {
+            default_role: Role::Reader,
+            ..ServerConfig::default()
+        };
+        config.users.insert(
+            "ada".into(),
+            UserAccess {
+                role: None,
+                paths: vec!["docs/".into()],
+            },
+        );
+        let commits = CommitStore::default();
+        let (tx, _) = broadcast::channel(8);
+        let mut state = AppState::new(commits.clone(), tx);
+        state.auth = Some(Arc::new(tokens));
+        state.access = Arc::new(AccessRules::from(&config));
+        let (_objects, hashes) = store_blobs(&mut state, 3);
+        for (hash, path) in hashes.iter().zip(["docs/a", "src/b"]) {
+            let change = Change {
+                hash: *hash,
+                path: path.into(),
+                timestamp: 1,
+                client: None,
+                mode: FileMode::Regular,
+            };
+            commits.add_commit(change).await.unwrap();
+        }
+        let app = app(state);
+
+        let request = |token: &str, method: &str, uri: String, body: Body| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", token))
+                .header("content-type", "application/json")
+                .body(body)
+                .unwrap()
+        };
+        let status = |req: Request<Body>| {
+            let app = app.clone();
+            async move { app.oneshot(req).await.unwrap().status() }
+        };
+        let object = |token: &str, method: &str, hash: ObjectId| {
+            request(token, method, format!("/objects/{}", hash), Body::empty())
+        };
+
+        assert_eq!(status(object(&ada, "GET", hashes[0])).await, StatusCode::OK);
+        assert_eq!(status(object(&ada, "GET", hashes[1])).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(object(&ada, "HEAD", hashes[0])).await, StatusCode::OK);
+        assert_eq!(status(object(&ada, "HEAD", hashes[1])).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(object(&eve, "GET", hashes[1])).await, StatusCode::OK);
+
+        let body = json!({"hashes": &hashes[..2]}).to_string();
+        let res = app
+            .clone()
+            .oneshot(request(&ada, "POST", "/objects/missing".into(), Body::from(body)))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let missing: MissingResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(missing.missing, vec![hashes[1]]);
+
+        // The newest commit only changes a path ada cannot see
+        let latest = |token: &str| request(token, "GET", "/commits/latest".into(), Body::empty());
+        assert_eq!(status(latest(&ada)).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(latest(&eve)).await, StatusCode::OK);
+
+        // Objects of commits appended later become readable
+        assert_eq!(status(object(&ada, "GET", hashes[2])).await, StatusCode::NOT_FOUND);
+        let change = Change {
+            hash: hashes[2],
+            path: "docs/c".into(),
+            timestamp: 2,
+            client: None,
+            mode: FileMode::Regular,
+        };
+        commits.add_commit(change).await.unwrap();
+        assert_eq!(status(object(&ada, "GET", hashes[2])).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn streams_changes() {
+        let commits = CommitStore::default();
+        let (tx, _) = broadcast::channel(8);
+        let mut state = AppState::new(commits.clone(), tx.clone());
+        let (_dir, hashes) = store_blobs(&mut state, 1);
         let app = app(state);
 
         let req = Request::builder()
@@ -384,9 +1299,11 @@ mod tests {
         });
 
         let change = Change {
-            hash: "c1".into(),
+            hash: hashes[0],
             path: "f".into(),
             timestamp: 1,
+            client: None,
+            mode: FileMode::Regular,
         };
         let req = Request::builder()
             .method("POST")
@@ -398,8 +1315,11 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
 
         let data = reader.await.unwrap();
-        assert!(data.starts_with("data: "));
-        let json_str = data.trim_start_matches("data: ").trim();
+        assert!(data.lines().any(|l| l == "id: 1"));
+        let json_str = data
+            .lines()
+            .find_map(|l| l.strip_prefix("data: "))
+            .expect("data line");
         let streamed: ChangeEvent = serde_json::from_str(json_str).unwrap();
         assert_eq!(streamed.change, change);
         assert_eq!(streamed.commit_id, 1);
@@ -409,16 +1329,16 @@ mod tests {
     async fn commit_history_endpoint() {
         let commits = CommitStore::default();
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx,
-        };
+        let mut state = AppState::new(commits.clone(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 1);
         let app = app(state);
 
         let change = Change {
-            hash: "c1".into(),
+            hash: hashes[0],
             path: "f".into(),
             timestamp: 1,
+            client: None,
+            mode: FileMode::Regular,
         };
         let req = Request::builder()
             .method("POST")
@@ -446,16 +1366,16 @@ mod tests {
     async fn latest_commit_endpoint() {
         let commits = CommitStore::default();
         let (tx, _) = broadcast::channel(8);
-        let state = AppState {
-            commits: commits.clone(),
-            broadcaster: tx,
-        };
+        let mut state = AppState::new(commits.clone(), tx);
+        let (_dir, hashes) = store_blobs(&mut state, 1);
         let app = app(state);
 
         let change = Change {
-            hash: "c1".into(),
+            hash: hashes[0],
             path: "f".into(),
             timestamp: 1,
+            client: None,
+            mode: FileMode::Regular,
         };
         let req = Request::builder()
             .method("POST")
@@ -477,4 +1397,80 @@ mod tests {
         let commit: Commit = serde_json::from_slice(&body).unwrap();
         assert_eq!(commit.id, 1);
     }
+
+    #[tokio::test]
+    async fn commit_by_id_endpoint() {
+        let commits = CommitStore::default();
+        let (tx, _) = broadcast::channel(8);
+        let app = app(AppState::new(commits.clone(), tx));
+        for i in 1..=3 {
+            commits
+                .add_commit(Change {
+                    hash: ObjectId::from([i; 32]),
+                    path: "f".into(),
+                    timestamp: i as u64,
+                    client: None,
+                    mode: FileMode::Regular,
+                })
+                .await
+                .unwrap();
+        }
+
+        let req = Request::builder().uri("/commits/2").body(Body::empty()).unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let commit: Commit = serde_json::from_slice(&body).unwrap();
+        assert_eq!(commit.id, 2);
+        assert_eq!(commit.changes[0].hash, ObjectId::from([2; 32]));
+
+        let req = Request::builder().uri("/commits/9").body(Body::empty()).unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn pages_through_commits() {
+        let commits = CommitStore::default();
+        let (tx, _) = broadcast::channel(8);
+        let app = app(AppState::new(commits.clone(), tx));
+        for i in 1..=5 {
+            commits
+                .add_commit(Change {
+                    hash: ObjectId::from([i; 32]),
+                    path: "f".into(),
+                    timestamp: i as u64,
+                    client: None,
+                    mode: FileMode::Regular,
+                })
+                .await
+                .unwrap();
+        }
+
+        let page = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                let resp = app.oneshot(req).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let list: Vec<Commit> = serde_json::from_slice(&body).unwrap();
+                list.into_iter().map(|c| c.id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(page("/commits").await, vec![1, 2, 3, 4, 5]);
+        assert_eq!(page("/commits?limit=2").await, vec![1, 2]);
+        assert_eq!(page("/commits?after=2&limit=2").await, vec![3, 4]);
+        assert_eq!(page("/commits?after=4").await, vec![5]);
+        assert_eq!(page("/commits?after=5").await, Vec::<u64>::new());
+        // Commits are timestamped when they are made, not by their changes.
+        assert_eq!(page("/commits?since=1&after=3").await, vec![4, 5]);
+        assert_eq!(page("/commits?until=1").await, Vec::<u64>::new());
+        assert_eq!(page("/commits?path=f&limit=1").await, vec![1]);
+        assert_eq!(page("/commits?path=g").await, Vec::<u64>::new());
+    }
 }
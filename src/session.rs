@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use reqwest::Client;
+
+use crate::config::RepoConfig;
+use crate::index::Index;
+use crate::object_id::ObjectId;
+
+/// What `hit watch`, `hit sync` and `hit dev` work with: the repository's
+/// config and one HTTP client for its server.
+#[derive(Clone)]
+pub struct Session {
+    pub config: RepoConfig,
+    pub client: Client,
+    /// Base URL of the server.
+    pub base: String,
+    /// This install's id, sent with its changes; see [`crate::repo::client_id`].
+    pub client_id: String,
+    /// Files written by sync, so the watcher does not send them back.
+    pub synced: Arc<SyncedWrites>,
+    /// Last-known content of the working tree, updated by both.
+    pub index: Arc<Index>,
+}
+
+impl Session {
+    /// A session for the server selected by `.hit/config` and the
+    /// environment; see [`RepoConfig::server`].
+    pub fn open() -> Result<Self, Box<dyn Error>> {
+        let config = RepoConfig::load()?;
+        let remote = config.server(|name| std::env::var(name).ok())?;
+        let client = crate::sync::connect(&remote)?;
+        Ok(Self {
+            config,
+            client,
+            base: remote.url,
+            client_id: crate::repo::client_id()?,
+            synced: Arc::new(SyncedWrites::default()),
+            index: Arc::new(Index::load()?),
+        })
+    }
+
+    /// URL of `path` on the server, e.g. `url("/changes")`.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base.trim_end_matches('/'), path)
+    }
+}
+
+/// Contents sync wrote to files, by repository-relative path.
+///
+/// When the watcher then sees one of these files with the same content the
+/// change came from the server, and is not sent back as a new one.
+#[derive(Debug, Default)]
+pub struct SyncedWrites {
+    writes: Mutex<HashMap<PathBuf, ObjectId>>,
+}
+
+impl SyncedWrites {
+    /// Remember that sync is writing `hash` to `path`.
+    pub fn record(&self, path: &Path, hash: ObjectId) {
+        self.lock().insert(repo_key(path), hash);
+    }
+
+    /// Whether `hash` at `path` is what sync wrote there. Either way the
+    /// write is forgotten, as the file is now up to date or was edited
+    /// since.
+    pub fn is_echo(&self, path: &Path, hash: &ObjectId) -> bool {
+        self.lock().remove(&repo_key(path)).is_some_and(|synced| synced == *hash)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, ObjectId>> {
+        self.writes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// `path` relative to the repository root without `.` components, so the
+/// watcher's `./a.txt` and sync's `/home/ada/repo/a.txt` compare equal.
+fn repo_key(path: &Path) -> PathBuf {
+    let root = std::env::current_dir().unwrap_or_default();
+    path.strip_prefix(&root)
+        .unwrap_or(path)
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect()
+}
+
+/// Run the watcher and sync together until `Ctrl+C`, sharing one client.
+/// Files written by sync are not sent back to the server.
+pub async fn dev() -> Result<(), Box<dyn Error>> {
+    let session = Session::open()?;
+    // The watcher's error is kept as text so the future stays `Send`
+    let watch = async { crate::watcher::watch_with(session.clone()).await.map_err(|e| e.to_string()) };
+    let (watched, ()) = tokio::join!(watch, crate::sync::sync_with(&session));
+    Ok(watched?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_echoes_once() {
+        let synced = SyncedWrites::default();
+        let a = ObjectId::from([1; 32]);
+        let b = ObjectId::from([2; 32]);
+        let root = std::env::current_dir().unwrap();
+
+        synced.record(&root.join("src/a.txt"), a);
+        assert!(synced.is_echo(Path::new("./src/a.txt"), &a));
+        assert!(!synced.is_echo(Path::new("src/a.txt"), &a));
+
+        // Edited after sync wrote it
+        synced.record(Path::new("b.txt"), a);
+        assert!(!synced.is_echo(Path::new("b.txt"), &b));
+        assert!(!synced.is_echo(Path::new("b.txt"), &a));
+    }
+}
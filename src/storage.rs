@@ -1,39 +1,518 @@
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{BufReader, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::object::{Object, Hashable};
+use sha2::{Digest, Sha256};
+
+use crate::chunking::Chunker;
+use crate::object::{
+    Blob, BlobHasher, ChunkedBlob, FileMode, Header, Hashable, MAX_HEADER_LEN, Object, ObjectKind, Tree,
+    legacy_hash,
+};
+use crate::object_id::ObjectId;
 
 /// Directory where objects are stored.
 pub const OBJECT_DIR: &str = ".hit/objects";
 
-pub fn write_object(obj: &Object) -> std::io::Result<String> {
-    fs::create_dir_all(OBJECT_DIR)?;
-    let hash = obj.hash();
-    let path: PathBuf = Path::new(OBJECT_DIR).join(&hash);
-    if !path.exists() {
-        let bytes = bincode::serialize(obj)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-        let mut file = File::create(&path)?;
-        file.write_all(&bytes)?;
+/// Scratch space for objects being written; on the same filesystem as
+/// [`OBJECT_DIR`] so finished objects can be renamed into place.
+pub const TMP_DIR: &str = ".hit/tmp";
+
+/// Files larger than this are stored as content-defined chunks plus a
+/// [`ChunkedBlob`] manifest instead of a single blob.
+pub const CHUNKING_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+/// Largest tree, commit, manifest or legacy object an import accepts. These
+/// are decoded in memory to be checked, unlike canonical blobs.
+pub const MAX_STRUCTURED_OBJECT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Objects stored under a data directory: `objects/` holds one file per
+/// object and `tmp/` objects that are still being written.
+///
+/// The free functions of this module work on the store of the repository
+/// in the current directory (`.hit`); a server uses the store in its
+/// configured data directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectStore {
+    objects: PathBuf,
+    tmp: PathBuf,
+}
+
+impl Default for ObjectStore {
+    fn default() -> Self {
+        Self {
+            objects: PathBuf::from(OBJECT_DIR),
+            tmp: PathBuf::from(TMP_DIR),
+        }
+    }
+}
+
+impl ObjectStore {
+    /// The store in `data_dir`, e.g. `.hit`.
+    pub fn in_dir(data_dir: impl AsRef<Path>) -> Self {
+        let data_dir = data_dir.as_ref();
+        Self {
+            objects: data_dir.join("objects"),
+            tmp: data_dir.join("tmp"),
+        }
+    }
+
+    /// Directory holding the object files.
+    pub fn dir(&self) -> &Path {
+        &self.objects
+    }
+
+    pub fn write_object(&self, obj: &Object) -> std::io::Result<ObjectId> {
+        fs::create_dir_all(&self.objects)?;
+        let hash = obj.hash();
+        let path = self.object_path(&hash);
+        if !path.exists() {
+            let mut file = File::create(&path)?;
+            file.write_all(&obj.encode())?;
+        }
+        Ok(hash)
+    }
+
+    pub fn read_object(&self, hash: &ObjectId) -> std::io::Result<Object> {
+        let mut file = File::open(self.object_path(hash))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        Object::decode(&buf)
+    }
+
+    /// List the hashes of all objects in the store.
+    ///
+    /// Files whose name is not an object id (e.g. left behind by other tools)
+    /// are skipped.
+    pub fn list_objects(&self) -> std::io::Result<Vec<ObjectId>> {
+        let entries = match fs::read_dir(&self.objects) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut hashes = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            match entry.file_name().to_str().map(str::parse) {
+                Some(Ok(hash)) => hashes.push(hash),
+                _ => tracing::warn!(name = ?entry.file_name(), "ignoring unexpected file in object store"),
+            }
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    /// Path of the stored object with the given hash.
+    pub fn object_path(&self, hash: &ObjectId) -> PathBuf {
+        self.objects.join(hash.to_hex())
+    }
+
+    /// Resolve an abbreviated id such as `abc12` to the single stored object
+    /// whose id starts with it.
+    pub fn resolve_prefix(&self, prefix: &str) -> std::io::Result<ObjectId> {
+        let prefix = ObjectId::parse_prefix(prefix)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let matches: Vec<ObjectId> = self.list_objects()?
+            .into_iter()
+            .filter(|id| id.starts_with(prefix))
+            .collect();
+        match matches.as_slice() {
+            [id] => Ok(*id),
+            [] => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no object matches {}", prefix),
+            )),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is ambiguous: {} objects match", prefix, matches.len()),
+            )),
+        }
+    }
+
+    /// Hashes of the objects referenced by a stored object.
+    ///
+    /// Only manifests are decoded; other objects are recognized from their
+    /// header so large blobs are not read into memory.
+    pub fn read_references(&self, hash: &ObjectId) -> std::io::Result<Vec<ObjectId>> {
+        let mut head = Vec::with_capacity(MAX_HEADER_LEN);
+        File::open(self.object_path(hash))?
+            .take(MAX_HEADER_LEN as u64)
+            .read_to_end(&mut head)?;
+        match Header::parse(&head) {
+            Some(header) if header.kind != ObjectKind::Chunked => Ok(Vec::new()),
+            _ => Ok(self.read_object(hash)?.references()),
+        }
+    }
+
+    /// Objects needed to read `hash` that are not stored: `hash` itself if
+    /// it is absent, otherwise any of its references that are.
+    pub fn missing(&self, hash: &ObjectId) -> std::io::Result<Vec<ObjectId>> {
+        if !self.object_path(hash).exists() {
+            return Ok(vec![*hash]);
+        }
+        Ok(self
+            .read_references(hash)?
+            .into_iter()
+            .filter(|r| !self.object_path(r).exists())
+            .collect())
+    }
+
+    /// Store the content of the file at `path` without reading it into memory.
+    ///
+    /// Files up to [`CHUNKING_THRESHOLD`] become a single blob streamed to disk
+    /// while hashing; larger files are split with [`Chunker`] and recorded as a
+    /// [`ChunkedBlob`] manifest.
+    pub fn store_file(&self, path: &Path) -> std::io::Result<StoredFile> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let (len, mode) = (metadata.len(), file_mode(&metadata));
+        if len <= CHUNKING_THRESHOLD {
+            let (hash, new) = self.write_blob_from_reader(file, len)?;
+            let written = if new { vec![hash] } else { Vec::new() };
+            return Ok(StoredFile { hash, written, mode });
+        }
+
+        let mut chunks = Vec::new();
+        let mut written = Vec::new();
+        let mut size = 0u64;
+        for chunk in Chunker::new(BufReader::new(file)) {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            let obj = Object::Blob(Blob { content: chunk });
+            let hash = obj.hash();
+            if !self.object_path(&hash).exists() {
+                self.write_object(&obj)?;
+                written.push(hash);
+            }
+            chunks.push(hash);
+        }
+        if size != len {
+            return Err(changed_while_reading(path));
+        }
+        let manifest = Object::Chunked(ChunkedBlob { size, chunks });
+        let hash = manifest.hash();
+        if !self.object_path(&hash).exists() {
+            self.write_object(&manifest)?;
+            written.push(hash);
+        }
+        Ok(StoredFile { hash, written, mode })
+    }
+
+    /// Store what is at `path` the way a change carries it: a file as by
+    /// [`store_file`](Self::store_file), a symlink as a blob of its target
+    /// and an empty directory as an empty [`Tree`].
+    ///
+    /// Returns `None` if `path` is gone, is a directory with entries (those
+    /// are stored one by one) or is anything else.
+    pub fn store_path(&self, path: &Path) -> std::io::Result<Option<StoredFile>> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (obj, mode) = if metadata.file_type().is_symlink() {
+            let target = fs::read_link(path)?;
+            let target = target.to_str().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "symlink target is not valid UTF-8")
+            })?;
+            (Object::Blob(Blob { content: target.as_bytes().to_vec() }), FileMode::Symlink)
+        } else if metadata.is_dir() {
+            if fs::read_dir(path)?.next().is_some() {
+                return Ok(None);
+            }
+            (Object::Tree(Tree { entries: Vec::new() }), FileMode::Regular)
+        } else if metadata.is_file() {
+            return self.store_file(path).map(Some);
+        } else {
+            return Ok(None);
+        };
+        let hash = obj.hash();
+        let mut written = Vec::new();
+        if !self.object_path(&hash).exists() {
+            self.write_object(&obj)?;
+            written.push(hash);
+        }
+        Ok(Some(StoredFile { hash, written, mode }))
+    }
+
+    /// Stream `len` bytes from `reader` into a blob object.
+    ///
+    /// Returns the blob hash and whether the object was newly written.
+    fn write_blob_from_reader(&self, mut reader: impl Read, len: u64) -> std::io::Result<(ObjectId, bool)> {
+        let (tmp, mut file) = self.temp_file()?;
+        let result = (|| {
+            file.write_all(&Header::new(ObjectKind::Blob, len).encode())?;
+            let mut hasher = BlobHasher::new(len);
+            let mut buf = vec![0u8; 64 * 1024];
+            let mut copied = 0u64;
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                hasher.update(&buf[..n]);
+                file.write_all(&buf[..n])?;
+                copied += n as u64;
+            }
+            if copied != len {
+                return Err(changed_while_reading(&tmp));
+            }
+            Ok(hasher.finish())
+        })();
+        drop(file);
+        let hash = match result {
+            Ok(hash) => hash,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+        let new = self.persist_temp(&tmp, &hash)?;
+        Ok((hash, new))
+    }
+
+    /// Start receiving an object, see [`ObjectImport`].
+    pub fn import(&self) -> std::io::Result<ObjectImport> {
+        let (tmp, file) = self.temp_file()?;
+        Ok(ObjectImport {
+            store: self.clone(),
+            tmp,
+            file,
+            hasher: Sha256::new(),
+            head: Vec::with_capacity(MAX_HEADER_LEN),
+            len: 0,
+        })
+    }
+
+    fn temp_file(&self) -> std::io::Result<(PathBuf, File)> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        fs::create_dir_all(&self.tmp)?;
+        let name = format!(
+            "{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = self.tmp.join(name);
+        let file = File::create(&path)?;
+        Ok((path, file))
+    }
+
+    /// Move a finished temp file to its object path. Returns `false` if the
+    /// object already existed, in which case the temp file is discarded.
+    fn persist_temp(&self, tmp: &Path, hash: &ObjectId) -> std::io::Result<bool> {
+        fs::create_dir_all(&self.objects)?;
+        let dest = self.object_path(hash);
+        if dest.exists() {
+            fs::remove_file(tmp)?;
+            return Ok(false);
+        }
+        fs::rename(tmp, dest)?;
+        Ok(true)
+    }
+}
+
+pub fn write_object(obj: &Object) -> std::io::Result<ObjectId> {
+    ObjectStore::default().write_object(obj)
+}
+
+pub fn read_object(hash: &ObjectId) -> std::io::Result<Object> {
+    ObjectStore::default().read_object(hash)
+}
+
+/// See [`ObjectStore::list_objects`].
+pub fn list_objects() -> std::io::Result<Vec<ObjectId>> {
+    ObjectStore::default().list_objects()
+}
+
+/// Path of the stored object with the given hash.
+pub fn object_path(hash: &ObjectId) -> PathBuf {
+    ObjectStore::default().object_path(hash)
+}
+
+/// See [`ObjectStore::resolve_prefix`].
+pub fn resolve_prefix(prefix: &str) -> std::io::Result<ObjectId> {
+    ObjectStore::default().resolve_prefix(prefix)
+}
+
+/// See [`ObjectStore::read_references`].
+pub fn read_references(hash: &ObjectId) -> std::io::Result<Vec<ObjectId>> {
+    ObjectStore::default().read_references(hash)
+}
+
+/// See [`ObjectStore::store_file`].
+pub fn store_file(path: &Path) -> std::io::Result<StoredFile> {
+    ObjectStore::default().store_file(path)
+}
+
+pub fn store_path(path: &Path) -> std::io::Result<Option<StoredFile>> {
+    ObjectStore::default().store_path(path)
+}
+
+/// Files anyone may execute are [`FileMode::Executable`] where there is an
+/// executable bit.
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> FileMode {
+    use std::os::unix::fs::PermissionsExt;
+    if metadata.permissions().mode() & 0o111 != 0 {
+        FileMode::Executable
+    } else {
+        FileMode::Regular
+    }
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> FileMode {
+    FileMode::Regular
+}
+
+/// Result of [`ObjectStore::store_file`] and [`ObjectStore::store_path`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFile {
+    /// Hash of the blob or manifest representing the whole file.
+    pub hash: ObjectId,
+    /// Objects that were not already in the store, in upload order: chunks
+    /// before the manifest that references them.
+    pub written: Vec<ObjectId>,
+    pub mode: FileMode,
+}
+
+/// Receives an encoded object in pieces (e.g. from a request body) and stores
+/// it once its hash has been verified, without buffering it in memory.
+///
+/// Objects in the legacy bincode layout are accepted too: under their legacy
+/// id they are stored as received, under their canonical id they are
+/// re-encoded.
+pub struct ObjectImport {
+    store: ObjectStore,
+    tmp: PathBuf,
+    file: File,
+    hasher: Sha256,
+    head: Vec<u8>,
+    len: u64,
+}
+
+impl ObjectImport {
+    /// Import into the store of the repository in the current directory.
+    pub fn new() -> std::io::Result<Self> {
+        ObjectStore::default().import()
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.len += data.len() as u64;
+        if self.head.len() < MAX_HEADER_LEN {
+            let n = (MAX_HEADER_LEN - self.head.len()).min(data.len());
+            self.head.extend_from_slice(&data[..n]);
+        }
+        Ok(())
+    }
+
+    /// Bytes received so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether nothing has been received yet.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Give up on the import and remove the partial data.
+    pub fn abort(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.tmp);
+    }
+
+    /// Verify the received object hashes to `expected` and move it into the
+    /// store. Invalid or mismatching data yields an `InvalidData` error, as
+    /// does a structured or legacy object over
+    /// [`MAX_STRUCTURED_OBJECT_BYTES`]. Blocks while those are read back to be
+    /// decoded.
+    pub fn finish(self, expected: &ObjectId) -> std::io::Result<()> {
+        let Self { store, tmp, file, hasher, head, len } = self;
+        drop(file);
+        let result = match Header::parse(&head) {
+            Some(header) => validate_canonical(&tmp, header, len, hasher, expected)
+                .and_then(|()| store.persist_temp(&tmp, expected).map(|_| ())),
+            None => import_legacy(&store, &tmp, len, expected),
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+}
+
+fn validate_canonical(
+    tmp: &Path,
+    header: Header,
+    len: u64,
+    hasher: Sha256,
+    expected: &ObjectId,
+) -> std::io::Result<()> {
+    if header.size as u64 + header.len != len {
+        return Err(invalid_data("object length does not match header".into()));
+    }
+    if header.kind != ObjectKind::Blob {
+        // Structured objects are small; decode them to reject malformed
+        // payloads. Blob payloads are opaque and need no further checks.
+        check_structured_len(len)?;
+        Object::decode_canonical(&fs::read(tmp)?)?;
+    }
+    let actual = ObjectId::from(hasher.finalize());
+    if actual != *expected {
+        return Err(invalid_data(format!("hash mismatch: expected {}, got {}", expected, actual)));
+    }
+    Ok(())
+}
+
+fn import_legacy(store: &ObjectStore, tmp: &Path, len: u64, expected: &ObjectId) -> std::io::Result<()> {
+    check_structured_len(len)?;
+    let obj = Object::decode(&fs::read(tmp)?)?;
+    if legacy_hash(&obj) == *expected {
+        store.persist_temp(tmp, expected)?;
+    } else if obj.hash() == *expected {
+        store.write_object(&obj)?;
+        fs::remove_file(tmp)?;
+    } else {
+        return Err(invalid_data(format!(
+            "hash mismatch: expected {}, got {}",
+            expected,
+            obj.hash()
+        )));
+    }
+    Ok(())
+}
+
+fn check_structured_len(len: u64) -> std::io::Result<()> {
+    if len > MAX_STRUCTURED_OBJECT_BYTES {
+        return Err(invalid_data(format!(
+            "object of {} bytes is larger than the {} allowed unless it is a blob",
+            len, MAX_STRUCTURED_OBJECT_BYTES
+        )));
     }
-    Ok(hash)
+    Ok(())
 }
 
-pub fn read_object(hash: &str) -> std::io::Result<Object> {
-    let path: PathBuf = Path::new(OBJECT_DIR).join(hash);
-    let mut file = File::open(&path)?;
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf)?;
-    let obj: Object = bincode::deserialize(&buf)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-    Ok(obj)
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn changed_while_reading(path: &Path) -> std::io::Error {
+    invalid_data(format!("{} changed while it was being stored", path.display()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::object::{Blob, Tree, TreeEntry, Commit};
+    use crate::object::{Tree, TreeEntry, Commit};
     use std::fs;
     use serial_test::serial;
 
@@ -82,4 +561,168 @@ mod tests {
         let read = read_object(&hash).unwrap();
         assert_eq!(obj, read);
     }
+
+    #[test]
+    #[serial]
+    fn store_file_streams_small_files_into_a_blob() {
+        clean();
+        fs::create_dir_all(".hit").unwrap();
+        let path = Path::new(".hit/small.txt");
+        fs::write(path, b"small file").unwrap();
+        let stored = store_file(path).unwrap();
+        let expected = Object::Blob(Blob { content: b"small file".to_vec() });
+        assert_eq!(stored.hash, expected.hash());
+        assert_eq!(stored.written, vec![stored.hash]);
+        assert_eq!(read_object(&stored.hash).unwrap(), expected);
+        assert!(store_file(path).unwrap().written.is_empty());
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn store_file_chunks_large_files() {
+        clean();
+        fs::create_dir_all(".hit").unwrap();
+        let path = Path::new(".hit/large.bin");
+        let content: Vec<u8> = (0..CHUNKING_THRESHOLD as usize + 1)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        fs::write(path, &content).unwrap();
+        let stored = store_file(path).unwrap();
+        let manifest = match read_object(&stored.hash).unwrap() {
+            Object::Chunked(m) => m,
+            other => panic!("expected manifest, got {:?}", other),
+        };
+        assert_eq!(manifest.size, content.len() as u64);
+        assert_eq!(stored.written.last(), Some(&stored.hash));
+        assert_eq!(read_references(&stored.hash).unwrap(), manifest.chunks);
+        let mut rebuilt = Vec::new();
+        for chunk in &manifest.chunks {
+            match read_object(chunk).unwrap() {
+                Object::Blob(b) => rebuilt.extend_from_slice(&b.content),
+                other => panic!("expected blob, got {:?}", other),
+            }
+        }
+        assert_eq!(rebuilt, content);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn resolves_unique_prefixes() {
+        clean();
+        fs::create_dir_all(OBJECT_DIR).unwrap();
+        let mut bytes = [0xaa; 32];
+        let first = ObjectId::from(bytes);
+        bytes[31] = 0xbb;
+        let second = ObjectId::from(bytes);
+        let other = ObjectId::from([0xcc; 32]);
+        for id in [first, second, other] {
+            fs::write(object_path(&id), b"").unwrap();
+        }
+
+        assert_eq!(resolve_prefix("cccc").unwrap(), other);
+        assert_eq!(resolve_prefix(&first.to_hex()).unwrap(), first);
+        let kind = |prefix: &str| resolve_prefix(prefix).unwrap_err().kind();
+        assert_eq!(kind("aaaa"), std::io::ErrorKind::InvalidInput);
+        assert_eq!(kind("dddd"), std::io::ErrorKind::NotFound);
+        assert_eq!(kind("cc"), std::io::ErrorKind::InvalidInput);
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn import_verifies_hash() {
+        clean();
+        let obj = Object::Blob(Blob { content: b"imported".to_vec() });
+        let bytes = obj.encode();
+        let mut import = ObjectImport::new().unwrap();
+        import.write(&bytes[..3]).unwrap();
+        import.write(&bytes[3..]).unwrap();
+        import.finish(&obj.hash()).unwrap();
+        assert_eq!(read_object(&obj.hash()).unwrap(), obj);
+
+        let mut import = ObjectImport::new().unwrap();
+        import.write(&bytes).unwrap();
+        let wrong = ObjectId::from([0; 32]);
+        let err = import.finish(&wrong).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!object_path(&wrong).exists());
+        clean();
+    }
+
+    #[test]
+    #[serial]
+    fn import_accepts_legacy_objects() {
+        clean();
+        let obj = Object::Blob(Blob { content: b"old client".to_vec() });
+        let legacy = bincode::serialize(&obj).unwrap();
+
+        // Sent under its legacy id: kept byte for byte.
+        let mut import = ObjectImport::new().unwrap();
+        import.write(&legacy).unwrap();
+        import.finish(&legacy_hash(&obj)).unwrap();
+        assert_eq!(fs::read(object_path(&legacy_hash(&obj))).unwrap(), legacy);
+        assert_eq!(read_object(&legacy_hash(&obj)).unwrap(), obj);
+
+        // Sent under its canonical id: re-encoded.
+        let mut import = ObjectImport::new().unwrap();
+        import.write(&legacy).unwrap();
+        import.finish(&obj.hash()).unwrap();
+        assert_eq!(fs::read(object_path(&obj.hash())).unwrap(), obj.encode());
+        clean();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stores_modes_links_and_empty_directories() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let data = tempfile::tempdir().unwrap();
+        let store = ObjectStore::in_dir(data.path());
+        let work = tempfile::tempdir().unwrap();
+        let script = work.path().join("run.sh");
+        fs::write(&script, b"#!/bin/sh").unwrap();
+        let regular = store.store_path(&script).unwrap().unwrap();
+        assert_eq!(regular.mode, FileMode::Regular);
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let executable = store.store_path(&script).unwrap().unwrap();
+        assert_eq!((executable.hash, executable.mode), (regular.hash, FileMode::Executable));
+        assert!(executable.written.is_empty());
+
+        let link = work.path().join("link");
+        std::os::unix::fs::symlink("run.sh", &link).unwrap();
+        let stored = store.store_path(&link).unwrap().unwrap();
+        assert_eq!(stored.mode, FileMode::Symlink);
+        assert_eq!(store.read_object(&stored.hash).unwrap(), Object::Blob(Blob { content: b"run.sh".to_vec() }));
+
+        let empty = work.path().join("empty");
+        fs::create_dir(&empty).unwrap();
+        let stored = store.store_path(&empty).unwrap().unwrap();
+        assert_eq!(store.read_object(&stored.hash).unwrap(), Object::Tree(Tree { entries: vec![] }));
+        assert_eq!(store.store_path(work.path()).unwrap(), None);
+        assert_eq!(store.store_path(&work.path().join("gone")).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_structured_objects() {
+        let data = tempfile::tempdir().unwrap();
+        let store = ObjectStore::in_dir(data.path());
+        let payload = vec![0u8; MAX_STRUCTURED_OBJECT_BYTES as usize + 1];
+        let cases = [
+            Header::new(ObjectKind::Tree, payload.len() as u64).encode(),
+            // Not a canonical header, so taken for a legacy object
+            Vec::new(),
+        ];
+        for header in cases {
+            let mut import = store.import().unwrap();
+            import.write(&header).unwrap();
+            import.write(&payload).unwrap();
+            let err = import.finish(&ObjectId::from([0; 32])).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("larger than"), "{}", err);
+        }
+        assert!(store.list_objects().unwrap().is_empty());
+        assert_eq!(fs::read_dir(data.path().join("tmp")).unwrap().count(), 0);
+    }
 }
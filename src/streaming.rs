@@ -1,20 +1,38 @@
+use axum::http::HeaderMap;
 use axum::response::sse::{Event, KeepAlive, Sse};
-use axum::{Router, extract::State, routing::get};
+use axum::{Extension, Router, extract::{Query, State}, routing::get};
+use futures_util::stream::{self, BoxStream};
+use serde::Deserialize;
 use std::convert::Infallible;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 use tokio::sync::broadcast::{Receiver, Sender};
-use tokio_stream::{StreamExt, wrappers::BroadcastStream};
+use tokio_stream::wrappers::{BroadcastStream, errors::BroadcastStreamRecvError};
+use tokio_stream::StreamExt;
 
+use crate::auth::Permissions;
+use crate::commit::CommitStore;
 use crate::server::ChangeEvent;
 
 #[derive(Clone)]
 pub struct Broadcaster {
     tx: Sender<ChangeEvent>,
+    history: Option<CommitStore>,
 }
 
 impl Broadcaster {
     pub fn new(tx: Sender<ChangeEvent>) -> Self {
-        Self { tx }
+        Self { tx, history: None }
+    }
+
+    /// A broadcaster that can replay commits from `history` to clients that
+    /// reconnect after missing some events.
+    pub fn with_history(tx: Sender<ChangeEvent>, history: CommitStore) -> Self {
+        Self {
+            tx,
+            history: Some(history),
+        }
     }
 
     pub fn subscribe(&self) -> Receiver<ChangeEvent> {
@@ -22,23 +40,76 @@ impl Broadcaster {
     }
 }
 
+/// Query parameters of `GET /events`.
+#[derive(Debug, Default, Deserialize)]
+pub struct EventsQuery {
+    /// Replay the changes of commits after this id before live events.
+    pub after: Option<u64>,
+}
+
+/// Stream change events to the client.
+///
+/// Every event carries its commit id as the SSE event id. A client passing
+/// `?after=<id>` or a `Last-Event-ID` header first receives the changes it
+/// missed, read from the commit history, and then live events. Changes to
+/// paths outside the user's [`Permissions`] are not sent. The stream ends
+/// if the client falls too far behind, so it reconnects and catches up.
 pub async fn sse_handler(
     State(b): State<Broadcaster>,
+    Extension(permissions): Extension<Permissions>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
 ) -> Sse<impl tokio_stream::Stream<Item = Result<Event, Infallible>>> {
+    let live_permissions = permissions.clone();
+    // Subscribe before replaying so nothing committed meanwhile is lost.
     let rx = b.subscribe();
-    let stream = BroadcastStream::new(rx).filter_map(|msg| match msg {
-        Ok(change) => match serde_json::to_string(&change) {
-            Ok(d) => Some(Ok(Event::default().data(d))),
-            Err(e) => {
-                tracing::warn!("failed to serialize change: {}", e);
+    let after = query.after.or_else(|| {
+        headers
+            .get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+    });
+    let replayed = Arc::new(AtomicU64::new(after.unwrap_or(0)));
+
+    let replay: BoxStream<'static, Event> = match (after, &b.history) {
+        (Some(after), Some(history)) => {
+            let replayed = replayed.clone();
+            let commits = history.stream_after(after).map_while(|commit| match commit {
+                Ok(commit) => Some(commit),
+                Err(e) => {
+                    tracing::error!("failed to replay commits: {}", e);
+                    None
+                }
+            });
+            Box::pin(futures_util::StreamExt::flat_map(commits, move |commit| {
+                replayed.fetch_max(commit.id, Ordering::Relaxed);
+                let events: Vec<Event> = commit
+                    .changes
+                    .into_iter()
+                    .filter(|change| permissions.can_read(&change.path))
+                    .filter_map(|change| to_event(&ChangeEvent { change, commit_id: commit.id }))
+                    .collect();
+                stream::iter(events)
+            }))
+        }
+        _ => Box::pin(stream::empty()),
+    };
+
+    // A client that falls behind has missed events. Ending the stream makes
+    // it reconnect with the last id it received, and the rest are replayed.
+    let live = BroadcastStream::new(rx)
+        .map_while(move |msg| match msg {
+            // Skip commits the replay already sent.
+            Ok(change) if change.commit_id <= replayed.load(Ordering::Relaxed) => Some(None),
+            Ok(change) if !live_permissions.can_read(&change.change.path) => Some(None),
+            Ok(change) => Some(to_event(&change)),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "client fell behind, ending event stream");
                 None
             }
-        },
-        Err(e) => {
-            tracing::warn!("broadcast error: {}", e);
-            None
-        }
-    });
+        })
+        .filter_map(|event| event);
+    let stream = replay.chain(live).map(Ok);
     Sse::new(stream).keep_alive(
         KeepAlive::new()
             .interval(Duration::from_secs(15))
@@ -46,6 +117,16 @@ pub async fn sse_handler(
     )
 }
 
+fn to_event(change: &ChangeEvent) -> Option<Event> {
+    match serde_json::to_string(change) {
+        Ok(d) => Some(Event::default().id(change.commit_id.to_string()).data(d)),
+        Err(e) => {
+            tracing::warn!("failed to serialize change: {}", e);
+            None
+        }
+    }
+}
+
 pub fn router(broadcaster: Broadcaster) -> Router {
     Router::new()
         .route("/events", get(sse_handler))
@@ -1,4 +1,3 @@
-use std::env;
 use std::time::Duration;
 
 use futures_util::StreamExt;
@@ -6,64 +5,72 @@ use reqwest::Client;
 use reqwest_eventsource::{Event, EventSource};
 use tokio::time::sleep;
 
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
-use crate::object::Object;
-use crate::storage::write_object;
+use crate::auth::auth_headers;
+use crate::index::IndexEntry;
+use crate::session::Session;
+use crate::object::{FileMode, Object};
+use crate::object_id::ObjectId;
+use crate::storage::{ObjectImport, object_path, read_object};
 
-use std::path::{Component, Path, PathBuf};
+use std::io::Write;
+use std::path::Path;
 
-fn resolve_repo_path(rel: &str) -> std::io::Result<PathBuf> {
-    let p = Path::new(rel);
-    let root = std::env::current_dir()?;
-    
-    // If it's an absolute path, extract just the filename
-    if p.is_absolute() {
-        if let Some(filename) = p.file_name() {
-            return Ok(root.join(filename));
-        } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "invalid file path",
-            ));
-        }
+/// Download an object into the local store unless it is already present.
+///
+/// The response body is streamed to disk and verified against `hash`.
+async fn fetch_object(
+    client: &Client,
+    base: &str,
+    hash: &ObjectId,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if object_path(hash).exists() {
+        return Ok(());
     }
-    
-    // Check for parent directory traversal
-    if p.components().any(|c| matches!(c, Component::ParentDir)) {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "path outside repository",
-        ));
+    let url = format!("{}/objects/{}", base.trim_end_matches('/'), hash);
+    info!(%hash, url = %url, "fetching object from server");
+    let resp = client.get(&url).send().await?;
+    if !resp.status().is_success() {
+        error!(%hash, url = %url, status = %resp.status(), "server object not found");
+        return Err(format!("server responded with status {}", resp.status()).into());
+    }
+    let mut import = ObjectImport::new()?;
+    let mut body = resp.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        import.write(&chunk?)?;
     }
-    
-    Ok(root.join(p))
+    import.finish(hash)?;
+    Ok(())
 }
 
 /// Fetch the object for the given change from the server and apply it locally.
 /// The object is written to storage and the file content is applied to the
-/// path specified in the [`Change`].
+/// path specified in the [`Change`]. Chunked files are fetched chunk by chunk
+/// and reassembled without holding the whole file in memory.
 pub async fn apply_change(
     client: &Client,
     base: &str,
     change: &Change,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let target_path = resolve_repo_path(&change.path)?;
+    let target_path = crate::repo::from_repo_path(&change.path)?;
+    check_parents(&target_path)?;
 
-    let url = format!("{}/objects/{}", base.trim_end_matches('/'), change.hash);
-    info!(hash = %change.hash, path = %change.path, url = %url, "fetching object from server");
-    let resp = client.get(&url).send().await?;
-    if !resp.status().is_success() {
-        error!(hash = %change.hash, url = %url, status = %resp.status(), "server object not found");
-        return Err(format!("server responded with status {}", resp.status()).into());
-    }
-    let bytes = resp.bytes().await?;
-    let obj: Object = bincode::deserialize(&bytes)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-    write_object(&obj)?;
-
-    let blob = match obj {
-        Object::Blob(b) => b,
+    fetch_object(client, base, &change.hash).await?;
+    let chunks = match read_object(&change.hash)? {
+        Object::Blob(_) => vec![change.hash],
+        Object::Chunked(manifest) => {
+            for chunk in &manifest.chunks {
+                fetch_object(client, base, chunk).await?;
+            }
+            manifest.chunks
+        }
+        // An empty directory
+        Object::Tree(tree) if tree.entries.is_empty() => {
+            std::fs::create_dir_all(&target_path)?;
+            info!(hash = %change.hash, path = %target_path.display(), "applied change");
+            return Ok(());
+        }
         other => {
             error!(?other, "received non-blob object");
             return Err("non-blob object".into());
@@ -74,37 +81,137 @@ pub async fn apply_change(
         std::fs::create_dir_all(parent)?;
     }
 
-    if target_path.exists() {
+    if let Ok(existing) = std::fs::symlink_metadata(&target_path) {
         warn!(path = %target_path.display(), "overwriting existing file");
-        let backup = target_path.with_extension("bak");
-        let _ = std::fs::copy(&target_path, &backup);
+        if existing.is_file() {
+            let backup = target_path.with_extension("bak");
+            let _ = std::fs::copy(&target_path, &backup);
+        }
+        // Links are replaced rather than written through
+        if change.mode == FileMode::Symlink || existing.file_type().is_symlink() {
+            std::fs::remove_file(&target_path)?;
+        }
     }
 
-    std::fs::write(&target_path, &blob.content)?;
-    info!(hash = %change.hash, path = %target_path.display(), "applied change");
+    if change.mode == FileMode::Symlink {
+        let mut target = Vec::new();
+        for chunk in &chunks {
+            match read_object(chunk)? {
+                Object::Blob(blob) => target.extend(blob.content),
+                _ => return Err(format!("chunk {} is not a blob", chunk).into()),
+            }
+        }
+        let target = String::from_utf8(target).map_err(|_| "symlink target is not valid UTF-8")?;
+        create_symlink(&target, &target_path)?;
+    } else {
+        let mut file = std::fs::File::create(&target_path)?;
+        for chunk in &chunks {
+            match read_object(chunk)? {
+                Object::Blob(blob) => file.write_all(&blob.content)?,
+                _ => return Err(format!("chunk {} is not a blob", chunk).into()),
+            }
+        }
+        set_executable(&file, change.mode == FileMode::Executable)?;
+    }
+    info!(hash = %change.hash, path = %target_path.display(), mode = ?change.mode, "applied change");
+    Ok(())
+}
+
+/// Refuse to write below a symlink, which could lead out of the repository.
+fn check_parents(path: &Path) -> std::io::Result<()> {
+    let root = std::env::current_dir()?;
+    let mut dir = root.clone();
+    let relative = path.strip_prefix(&root).unwrap_or(path);
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        dir.push(component);
+        if std::fs::symlink_metadata(&dir).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is below the symlink {}", path.display(), dir.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+/// Without symlinks the link is checked out as a file holding its target.
+#[cfg(not(unix))]
+fn create_symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    std::fs::write(link, target)
+}
+
+/// Set or clear the executable bits, for those who may read the file.
+#[cfg(unix)]
+fn set_executable(file: &std::fs::File, executable: bool) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = file.metadata()?.permissions();
+    let mode = permissions.mode();
+    let mode = if executable { mode | (mode & 0o444) >> 2 } else { mode & !0o111 };
+    permissions.set_mode(mode);
+    file.set_permissions(permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_file: &std::fs::File, _executable: bool) -> std::io::Result<()> {
     Ok(())
 }
 
 use crate::server::{Change, ChangeEvent};
+use crate::config::RemoteConfig;
 use std::collections::HashSet;
 
+/// A client for `remote`, sending its token and trusting its certificates.
+pub(crate) fn connect(remote: &RemoteConfig) -> Result<Client, Box<dyn std::error::Error>> {
+    let mut builder = Client::builder().default_headers(auth_headers(remote.token.as_deref())?);
+    if let Some(tls) = crate::tls::client_config(remote)? {
+        builder = builder.use_preconfigured_tls(tls);
+    }
+    Ok(builder.build()?)
+}
+
 /// Connect to the server and listen for change events via SSE.
 ///
-/// The server URL is taken from the `HIT_SERVER_URL` environment variable,
-/// defaulting to `http://localhost:8888` if unset.
+/// The server is the remote selected by `sync.remote` in `.hit/config`,
+/// unless `HIT_SERVER_URL` is set; see
+/// [`RepoConfig::server`](crate::config::RepoConfig::server).
 ///
 /// On each incoming event a log line is printed. The function retries with
 /// exponential backoff if the connection drops and exits cleanly on `Ctrl+C`.
+/// After a reconnect the server replays the changes committed in between.
 pub async fn sync_from_server() {
-    let base = env::var("HIT_SERVER_URL").unwrap_or_else(|_| "http://localhost:8888".into());
-    let url = format!("{}/events", base.trim_end_matches('/'));
+    match Session::open() {
+        Ok(session) => sync_with(&session).await,
+        Err(e) => error!(%e, "failed to connect to server"),
+    }
+}
+
+/// Sync with the server of `session` until `Ctrl+C`, recording the files
+/// written in `session.synced` and `session.index`.
+pub async fn sync_with(session: &Session) {
+    let client = &session.client;
+    let base = &session.base;
+    let max_backoff = session.config.sync.max_backoff_secs.max(1);
+    let url = session.url("/events");
 
-    let client = Client::new();
     let mut backoff = 1u64;
     let mut processed = HashSet::new();
     let mut last_commit = 0u64;
 
     loop {
+        let url = if last_commit > 0 {
+            format!("{}?after={}", url, last_commit)
+        } else {
+            url.clone()
+        };
         info!(url = %url, "connecting");
         let request = client.get(&url);
         match EventSource::new(request) {
@@ -113,7 +220,7 @@ pub async fn sync_from_server() {
                 loop {
                     tokio::select! {
                         _ = &mut shutdown => {
-                            let _ = source.close();
+                            source.close();
                             return;
                         }
                         message = source.next() => match message {
@@ -132,8 +239,24 @@ pub async fn sync_from_server() {
                                             info!(from = last_commit, to = event.commit_id, "moving to commit");
                                             last_commit = event.commit_id;
                                         }
-                                        if let Err(e) = apply_change(&client, &base, &event.change).await {
-                                            error!(%e, "failed to apply change");
+                                        if event.change.client.as_deref() == Some(session.client_id.as_str()) {
+                                            debug!(id = event.commit_id, "skipping own change");
+                                            continue;
+                                        }
+                                        // Before the file is written, so the watcher
+                                        // recognizes the write whenever it sees it
+                                        if let Ok(path) = crate::repo::from_repo_path(&event.change.path) {
+                                            session.synced.record(&path, event.change.hash);
+                                        }
+                                        match apply_change(client, base, &event.change).await {
+                                            Ok(()) => {
+                                                let entry = IndexEntry { hash: event.change.hash, mode: event.change.mode };
+                                                session.index.insert(event.change.path.clone(), entry);
+                                                if let Err(e) = session.index.save() {
+                                                    warn!(%e, "failed to save index");
+                                                }
+                                            }
+                                            Err(e) => error!(%e, "failed to apply change"),
                                         }
                                     }
                                     Err(e) => warn!(%e, "failed to parse event"),
@@ -156,9 +279,9 @@ pub async fn sync_from_server() {
             }
         }
 
-        let delay = backoff.min(30);
+        let delay = backoff.min(max_backoff);
         info!(delay, "reconnecting");
         sleep(Duration::from_secs(delay)).await;
-        backoff = (backoff * 2).min(30);
+        backoff = (backoff * 2).min(max_backoff);
     }
 }
@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+
+use crate::config::RemoteConfig;
+
+/// TLS settings for connecting to `remote`, or `None` to use the system's
+/// trusted certificates.
+///
+/// With `ca` only certificates issued by that CA are trusted. With `pin`
+/// the server's certificate must have that SHA-256 fingerprint; if `ca` is
+/// set as well, it must also be issued by the CA.
+pub fn client_config(remote: &RemoteConfig) -> io::Result<Option<ClientConfig>> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let roots = match &remote.ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca)? {
+                roots.add(&cert).map_err(|e| {
+                    invalid(format!("invalid CA certificate in {}: {}", ca.display(), e))
+                })?;
+            }
+            Some(roots)
+        }
+        None => None,
+    };
+    let config = match (&remote.pin, roots) {
+        (Some(pin), roots) => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedCert {
+                fingerprint: parse_fingerprint(pin)?,
+                issuer: roots.map(|roots| WebPkiVerifier::new(roots, None)),
+            }))
+            .with_no_client_auth(),
+        (None, Some(roots)) => builder.with_root_certificates(roots).with_no_client_auth(),
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(config))
+}
+
+/// SHA-256 fingerprint of the first certificate in a PEM file, as accepted
+/// by a remote's `pin` setting.
+pub fn cert_fingerprint(path: &Path) -> io::Result<String> {
+    let cert = read_certs(path)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| invalid(format!("no certificate in {}", path.display())))?;
+    Ok(fingerprint(&cert))
+}
+
+fn fingerprint(cert: &Certificate) -> String {
+    Sha256::digest(&cert.0).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a hex fingerprint, optionally prefixed with `sha256:` and with
+/// bytes separated by colons as printed by `openssl x509 -fingerprint`.
+fn parse_fingerprint(pin: &str) -> io::Result<[u8; 32]> {
+    let hex: String = pin
+        .trim()
+        .trim_start_matches("sha256:")
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect::<Option<Vec<u8>>>();
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| invalid(format!("invalid certificate fingerprint {:?}", pin)))
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let file = File::open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot read {}: {}", path.display(), e)))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Accepts only the server certificate with a known fingerprint, whoever
+/// issued it and whatever name it is for, unless `issuer` is set.
+struct PinnedCert {
+    fingerprint: [u8; 32],
+    issuer: Option<WebPkiVerifier>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(issuer) = &self.issuer {
+            issuer.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        }
+        if Sha256::digest(&end_entity.0)[..] != self.fingerprint {
+            return Err(rustls::Error::General(format!(
+                "server certificate {} does not match the pinned fingerprint",
+                fingerprint(end_entity)
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fingerprints() {
+        let hex = "ab".repeat(32);
+        assert_eq!(parse_fingerprint(&hex).unwrap(), [0xab; 32]);
+        assert_eq!(parse_fingerprint(&format!("sha256:{}", hex)).unwrap(), [0xab; 32]);
+        let colons = vec!["AB"; 32].join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), [0xab; 32]);
+        assert!(parse_fingerprint("abcd").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+}
@@ -1,134 +1,746 @@
 use std::path::Path;
-use std::sync::mpsc::channel;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{env, error::Error};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::error::Error;
 use tracing::{error, info, warn};
 
-use reqwest::blocking::Client;
+use reqwest::{Body, StatusCode};
 use serde_json::json;
 
+use tokio::sync::{Notify, mpsc};
+use tokio_util::io::ReaderStream;
+
 use notify::{Event, RecommendedWatcher, RecursiveMode, Result as NotifyResult, Watcher};
 
-use crate::object::{Blob, Object, Hashable};
-use crate::storage::{write_object, OBJECT_DIR};
+use crate::config::{IgnoreConfig, RepoConfig};
+use crate::index::{Index, IndexEntry};
+use crate::object::{FileMode, Hashable, Object};
+use crate::object_id::ObjectId;
+use crate::storage::{object_path, read_references, store_path};
+use crate::outbox::Outbox;
+use crate::server::{Change, MissingResponse};
+use crate::session::{Session, SyncedWrites};
 
-/// File suffixes that should be ignored by the watcher.
+/// File suffixes ignored by the watcher unless `ignore.suffixes` is set.
 pub const IGNORED_SUFFIXES: &[&str] = &["~", ".swp", ".tmp"];
 
 /// Send a newly detected change to the configured server.
-pub fn send_change_to_server(hash: &str, path: &Path) -> Result<(), Box<dyn Error>> {
-    let client = Client::new();
-    let base = env::var("HIT_SERVER_URL").unwrap_or_else(|_| "http://localhost:8888".into());
-    let url = format!("{}/changes", base.trim_end_matches('/'));
-    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-    let body = json!({
-        "hash": hash,
-        "path": path.to_string_lossy(),
-        "timestamp": timestamp,
-    });
-    let resp = client.post(&url).json(&body).send()?;
-    if !resp.status().is_success() {
-        return Err(format!("server responded with status {}", resp.status()).into());
+pub async fn send_change_to_server(hash: &ObjectId, path: &Path) -> Result<(), Box<dyn Error>> {
+    let session = Session::open()?;
+    post_change(&session, &new_change(hash, path, FileMode::Regular)?).await
+}
+
+/// A change to `path`, detected now.
+fn new_change(hash: &ObjectId, path: &Path, mode: FileMode) -> Result<Change, Box<dyn Error>> {
+    Ok(Change {
+        hash: *hash,
+        path: crate::repo::to_repo_path(path)?,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        client: Some(crate::repo::client_id()?),
+        mode,
+    })
+}
+
+/// The server refused a change or one of its objects in a way that sending
+/// it again will not fix.
+#[derive(Debug)]
+struct Rejected(String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Rejected {}
+
+/// The error for an unsuccessful `status`. Client errors are permanent,
+/// except those about timing or the client's credentials rather than the
+/// request itself.
+fn status_error(status: StatusCode, message: String) -> Box<dyn Error> {
+    let retryable = matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS | StatusCode::UNAUTHORIZED
+    );
+    if status.is_client_error() && !retryable {
+        Box::new(Rejected(message))
+    } else {
+        message.into()
+    }
+}
+
+/// Why `change` can never be sent, if `error` says so: the server refused
+/// it, or an object it needs is gone from the local store.
+fn rejection(error: &(dyn Error + 'static)) -> Option<String> {
+    if let Some(rejected) = error.downcast_ref::<Rejected>() {
+        return Some(rejected.0.clone());
+    }
+    match error.downcast_ref::<std::io::Error>() {
+        Some(e) if e.kind() == std::io::ErrorKind::NotFound => Some(format!("object missing locally: {}", e)),
+        _ => None,
+    }
+}
+
+async fn post_change(session: &Session, change: &Change) -> Result<(), Box<dyn Error>> {
+    let resp = session.client.post(session.url("/changes")).json(change).send().await?;
+    let status = resp.status();
+    if status == StatusCode::UNPROCESSABLE_ENTITY {
+        let missing = resp.json::<MissingResponse>().await?.missing;
+        return Err(status_error(
+            status,
+            format!("server is missing {} objects of {}", missing.len(), change.hash),
+        ));
     }
-    info!(hash, path = %path.display(), status = %resp.status(), "Sent change to server");
+    if !status.is_success() {
+        return Err(status_error(status, format!("server responded with status {}", status)));
+    }
+    info!(hash = %change.hash, path = %change.path, status = %resp.status(), "Sent change to server");
     Ok(())
 }
 
+/// Send the changes queued in `outbox`, oldest first, each after uploading
+/// the objects it needs that the server lacks.
+///
+/// A change the server accepts leaves the outbox. One it refuses for good
+/// is moved aside with [`Outbox::reject`] and the rest are still sent. On
+/// any other failure the remaining changes stay queued for the next
+/// attempt. Returns how many changes were sent.
+pub async fn flush_outbox(session: &Session, outbox: &Outbox) -> Result<usize, Box<dyn Error>> {
+    let pending = outbox.pending()?;
+    let mut sent = 0;
+    let mut done = 0;
+    let mut failure = None;
+    for change in &pending {
+        let result = async {
+            // Chunks precede their manifest
+            let mut objects = read_references(&change.hash)?;
+            objects.push(change.hash);
+            upload_missing(session, &objects).await?;
+            post_change(session, change).await
+        };
+        match result.await {
+            Ok(()) => sent += 1,
+            Err(e) => match rejection(e.as_ref()) {
+                Some(reason) => {
+                    warn!(path = %change.path, hash = %change.hash, %reason, "server rejected change");
+                    if let Err(e) = outbox.reject(change, &reason) {
+                        failure = Some(e.to_string());
+                        break;
+                    }
+                }
+                None => {
+                    failure = Some(e.to_string());
+                    break;
+                }
+            },
+        }
+        done += 1;
+    }
+    // Removed together, as rewriting the outbox per change is quadratic
+    outbox.remove_first(done)?;
+    match failure {
+        Some(e) => Err(format!("{} of {} changes sent: {}", sent, pending.len(), e).into()),
+        None => Ok(sent),
+    }
+}
+
 /// Send object data to the server for storage.
-pub fn send_object_to_server(obj: &Object) -> Result<(), Box<dyn Error>> {
-    let client = Client::new();
-    let base = env::var("HIT_SERVER_URL").unwrap_or_else(|_| "http://localhost:8888".into());
-    let hash = obj.hash();
-    let url = format!("{}/objects/{}", base.trim_end_matches('/'), hash);
-    
-    // Serialize the object using bincode (same format as local storage)
-    let serialized = bincode::serialize(obj)
-        .map_err(|e| format!("failed to serialize object: {}", e))?;
-    
-    let resp = client.put(&url)
-        .header("Content-Type", "application/octet-stream")
-        .body(serialized)
-        .send()?;
-        
+pub async fn send_object_to_server(obj: &Object) -> Result<(), Box<dyn Error>> {
+    // Objects travel in the same canonical encoding they are stored in
+    let session = Session::open()?;
+    put_object(&session, &obj.hash(), Body::from(obj.encode())).await
+}
+
+/// Upload an object from the local store, streaming it from disk.
+pub async fn send_stored_object_to_server(hash: &ObjectId) -> Result<(), Box<dyn Error>> {
+    let session = Session::open()?;
+    put_stored_object(&session, hash).await
+}
+
+/// Upload those of `hashes` from the local store that the server does not
+/// have yet, in order. Returns the hashes that were uploaded.
+pub async fn send_missing_objects_to_server(hashes: &[ObjectId]) -> Result<Vec<ObjectId>, Box<dyn Error>> {
+    let session = Session::open()?;
+    upload_missing(&session, hashes).await
+}
+
+async fn upload_missing(session: &Session, hashes: &[ObjectId]) -> Result<Vec<ObjectId>, Box<dyn Error>> {
+    let missing = missing_objects(session, hashes).await?;
+    if missing.len() < hashes.len() {
+        info!(skipped = hashes.len() - missing.len(), "Server already has objects");
+    }
+    for hash in &missing {
+        put_stored_object(session, hash).await?;
+    }
+    Ok(missing)
+}
+
+/// Ask the server which of `hashes` it lacks.
+async fn missing_objects(session: &Session, hashes: &[ObjectId]) -> Result<Vec<ObjectId>, Box<dyn Error>> {
+    match hashes {
+        [] => return Ok(Vec::new()),
+        [hash] => {
+            let resp = session.client.head(session.url(&format!("/objects/{}", hash))).send().await?;
+            return match resp.status() {
+                StatusCode::NOT_FOUND => Ok(vec![*hash]),
+                status if status.is_success() => Ok(Vec::new()),
+                status => Err(format!("server responded with status {}", status).into()),
+            };
+        }
+        _ => {}
+    }
+    let resp = session
+        .client
+        .post(session.url("/objects/missing"))
+        .json(&json!({ "hashes": hashes }))
+        .send()
+        .await?;
     if !resp.status().is_success() {
         return Err(format!("server responded with status {}", resp.status()).into());
     }
-    
-    info!(hash, status = %resp.status(), "Sent object to server");
+    Ok(resp.json::<MissingResponse>().await?.missing)
+}
+
+async fn put_stored_object(session: &Session, hash: &ObjectId) -> Result<(), Box<dyn Error>> {
+    let file = tokio::fs::File::open(object_path(hash)).await?;
+    put_object(session, hash, Body::wrap_stream(ReaderStream::new(file))).await
+}
+
+async fn put_object(session: &Session, hash: &ObjectId, body: Body) -> Result<(), Box<dyn Error>> {
+    let resp = session
+        .client
+        .put(session.url(&format!("/objects/{}", hash)))
+        .header("Content-Type", "application/octet-stream")
+        .body(body)
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        return Err(status_error(resp.status(), format!("server responded with status {}", resp.status())));
+    }
+    info!(%hash, status = %resp.status(), "Sent object to server");
     Ok(())
 }
 
-pub fn watch_and_store_changes() -> NotifyResult<()> {
-    let (tx, rx) = channel();
+/// How long a path must go without events before it is stored, so a burst
+/// of writes to one file is stored once.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Paths waiting to be stored. While it is full the debouncer keeps
+/// collecting events, merging those for the same path.
+const STORE_QUEUE: usize = 64;
 
+/// Watch the working tree until `Ctrl+C`, storing changed files and sending
+/// them to the server.
+///
+/// The work is split into stages connected by bounded queues, so neither
+/// hashing nor a slow server holds up the intake of file events:
+///
+/// 1. notify's handler drops ignored paths and hands the rest to a
+///    [`Debouncer`], which never blocks;
+/// 2. the debounce task passes on paths once they have settled;
+/// 3. the store task first queues what changed since the last run, by
+///    comparing the working tree against `.hit/index`, then hashes and
+///    stores each file and queues the change in the outbox;
+/// 4. the upload task sends the outbox, retrying with backoff.
+pub async fn watch_and_store_changes() -> Result<(), Box<dyn Error>> {
+    let session = Session::open()?;
+    watch_with(session).await
+}
+
+/// Watch the working tree as [`watch_and_store_changes`] does, sending
+/// changes with `session` and skipping the files its sync wrote.
+pub async fn watch_with(session: Session) -> Result<(), Box<dyn Error>> {
+    let config = session.config.clone();
+    let debouncer = Arc::new(Debouncer::default());
+    let intake = debouncer.clone();
+    let ignore = config.ignore.clone();
     let mut watcher = RecommendedWatcher::new(
-        tx,
+        move |res: NotifyResult<Event>| match res {
+            Ok(event) => intake.add(event.paths.into_iter().filter(|p| !should_ignore(p, &ignore))),
+            Err(e) => error!(?e, "watch error"),
+        },
         notify::Config::default()
             .with_poll_interval(Duration::from_secs(1))
             .with_compare_contents(true),
     )?;
-
     watcher.watch(Path::new("."), RecursiveMode::Recursive)?;
 
-    for res in rx {
-        match res {
-            Ok(event) => {
-                if let Err(e) = handle_event(event) {
-                    error!(%e, "error handling event");
+    let (paths_tx, paths_rx) = mpsc::channel(STORE_QUEUE);
+    let queued = Arc::new(Notify::new());
+    let outbox = Outbox::default();
+    let stages = [
+        tokio::spawn(debounce(debouncer, paths_tx)),
+        tokio::spawn(store(
+            paths_rx,
+            config.ignore,
+            session.synced.clone(),
+            session.index.clone(),
+            outbox.clone(),
+            queued.clone(),
+        )),
+        tokio::spawn(upload(session, outbox, queued)),
+    ];
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!(%e, "failed to wait for Ctrl+C");
+    }
+    for stage in stages {
+        stage.abort();
+    }
+    Ok(())
+}
+
+/// Paths with recent events, each with the time of its latest event.
+#[derive(Debug, Default)]
+struct Debouncer {
+    paths: Mutex<HashMap<PathBuf, Instant>>,
+    changed: Notify,
+}
+
+impl Debouncer {
+    fn add(&self, paths: impl IntoIterator<Item = PathBuf>) {
+        let now = Instant::now();
+        let mut added = false;
+        {
+            let mut pending = self.paths.lock().unwrap_or_else(|e| e.into_inner());
+            for path in paths {
+                pending.insert(path, now);
+                added = true;
+            }
+        }
+        if added {
+            self.changed.notify_one();
+        }
+    }
+
+    /// Remove and return the paths without events for [`DEBOUNCE`] as of
+    /// `now`, along with how long until the next of the others settles.
+    fn take_settled(&self, now: Instant) -> (Vec<PathBuf>, Option<Duration>) {
+        let mut pending = self.paths.lock().unwrap_or_else(|e| e.into_inner());
+        let mut settled = Vec::new();
+        let mut next: Option<Duration> = None;
+        pending.retain(|path, last| {
+            let quiet = now.saturating_duration_since(*last);
+            if quiet >= DEBOUNCE {
+                settled.push(path.clone());
+                false
+            } else {
+                let wait = DEBOUNCE - quiet;
+                next = Some(next.map_or(wait, |n| n.min(wait)));
+                true
+            }
+        });
+        settled.sort();
+        (settled, next)
+    }
+}
+
+async fn debounce(debouncer: Arc<Debouncer>, paths: mpsc::Sender<PathBuf>) {
+    loop {
+        let (settled, next) = debouncer.take_settled(Instant::now());
+        for path in settled {
+            // Waits while the store task is behind
+            if paths.send(path).await.is_err() {
+                return;
+            }
+        }
+        match next {
+            Some(wait) => {
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = debouncer.changed.notified() => {}
                 }
             }
-            Err(e) => error!(?e, "watch error"),
+            None => debouncer.changed.notified().await,
+        }
+    }
+}
+
+async fn store(
+    mut paths: mpsc::Receiver<PathBuf>,
+    ignore: IgnoreConfig,
+    synced: Arc<SyncedWrites>,
+    index: Arc<Index>,
+    outbox: Outbox,
+    queued: Arc<Notify>,
+) {
+    let ignore = Arc::new(ignore);
+    // Events arriving meanwhile wait in the debouncer
+    let scan = {
+        let (ignore, synced, index, outbox) = (ignore.clone(), synced.clone(), index.clone(), outbox.clone());
+        tokio::task::spawn_blocking(move || {
+            let queued = scan(&std::env::current_dir()?, &ignore, &synced, &index, &outbox)?;
+            index.save()?;
+            Ok::<_, std::io::Error>(queued)
+        })
+    };
+    match scan.await {
+        Ok(Ok(count)) => {
+            info!(queued = count, "Scanned working tree for changes");
+            queued.notify_one();
+        }
+        Ok(Err(e)) => error!(%e, "error scanning working tree"),
+        Err(e) => error!(%e, "scan task failed"),
+    }
+    while let Some(path) = paths.recv().await {
+        let ignore = ignore.clone();
+        let synced = synced.clone();
+        let index = index.clone();
+        let outbox = outbox.clone();
+        let recorded = tokio::task::spawn_blocking(move || {
+            let queued = record_path(&path, &ignore, &synced, &index, &outbox)?;
+            index.save()?;
+            Ok::<_, std::io::Error>(queued)
+        });
+        match recorded.await {
+            Ok(Ok(true)) => queued.notify_one(),
+            Ok(Ok(false)) => {}
+            Ok(Err(e)) => error!(%e, "error storing change"),
+            Err(e) => error!(%e, "store task failed"),
         }
     }
-    Ok(())
 }
 
-/// Handle a single notify [`Event`].
+async fn upload(session: Session, outbox: Outbox, queued: Arc<Notify>) {
+    loop {
+        retry_outbox(&session, &outbox).await;
+        tokio::select! {
+            _ = queued.notified() => {}
+            _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+        }
+    }
+}
+
+/// How often the watcher checks whether queued changes are due to be sent.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Send the changes queued in `outbox` unless an earlier failure put off
+/// the next attempt, and record the outcome for `hit status`.
+pub async fn retry_outbox(session: &Session, outbox: &Outbox) {
+    let max_backoff_secs = session.config.sync.max_backoff_secs;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let state = match outbox.retry_state() {
+        Ok(state) => state,
+        Err(e) => {
+            warn!(%e, "failed to read outbox retry state");
+            return;
+        }
+    };
+    if !state.is_due(now) {
+        return;
+    }
+    match flush_outbox(session, outbox).await {
+        Ok(sent) => {
+            if sent > 0 {
+                info!(sent, "Sent queued changes to server");
+            }
+            if state.failures > 0
+                && let Err(e) = outbox.record_success()
+            {
+                warn!(%e, "failed to clear outbox retry state");
+            }
+        }
+        Err(e) => match outbox.record_failure(&e.to_string(), now, max_backoff_secs) {
+            Ok(state) => warn!(%e, retry_in_secs = state.next_attempt - now, "failed to send queued changes"),
+            Err(state_err) => warn!(%e, %state_err, "failed to send queued changes"),
+        },
+    }
+}
+
+/// Handle a single notify [`Event`], storing changed files and queueing
+/// the changes in the outbox. The upload stage sends them to the server.
 ///
 /// This function is public so it can be unit tested without running the
-/// watcher loop.
+/// watcher.
 pub fn handle_event(event: Event) -> std::io::Result<()> {
+    let ignore = RepoConfig::load()?.ignore;
+    let index = Index::load()?;
+    let outbox = Outbox::default();
     for path in event.paths {
-        if should_ignore(&path) {
-            continue;
-        }
-        if path.is_file() {
-            let content = std::fs::read(&path)?;
-            let blob = Blob { content };
-            let obj = Object::Blob(blob);
-            let hash = obj.hash();
-            let object_path = Path::new(OBJECT_DIR).join(&hash);
-            if object_path.exists() {
-                info!(path = %path.display(), hash, "Detected change (already stored)");
-            } else {
-                write_object(&obj)?;
-                info!(path = %path.display(), hash, "Detected change stored");
-                
-                // Send object data to server first
-                if let Err(e) = send_object_to_server(&obj) {
-                    warn!(%e, "failed to send object to server");
-                }
-                
-                // Then send change notification
-                if let Err(e) = send_change_to_server(&hash, &path) {
-                    warn!(%e, "failed to send change to server");
+        record_path(&path, &ignore, &SyncedWrites::default(), &index, &outbox)?;
+    }
+    index.save()
+}
+
+/// Queue what changed in the working tree since `.hit/index` was saved, as
+/// the watcher does when it starts. Returns how many changes were queued.
+pub fn scan_changes() -> std::io::Result<usize> {
+    let ignore = RepoConfig::load()?.ignore;
+    let index = Index::load()?;
+    let queued = scan(&std::env::current_dir()?, &ignore, &SyncedWrites::default(), &index, &Outbox::default())?;
+    index.save()?;
+    Ok(queued)
+}
+
+/// Record every file, symlink and empty directory under `root` that is not
+/// ignored, and forget indexed paths that are gone. Returns how many changes
+/// were queued.
+///
+/// A directory below `root` that cannot be read is skipped with a warning,
+/// and the indexed paths under it are kept rather than forgotten.
+///
+/// Deletions are not sent, as changes cannot express them yet.
+fn scan(root: &Path, ignore: &IgnoreConfig, synced: &SyncedWrites, index: &Index, outbox: &Outbox) -> std::io::Result<usize> {
+    let mut queued = 0;
+    let mut found = HashSet::new();
+    // Repository paths of the directories not read in full
+    let mut unread = Vec::new();
+    let mut skip = |path: &Path, e: std::io::Error| {
+        warn!(%e, path = %path.display(), "failed to scan directory");
+        // Without its repository path, nothing is known to be gone
+        unread.push(crate::repo::to_repo_path(path).unwrap_or_default());
+    };
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if dir == root => return Err(e),
+            Err(e) => {
+                skip(&dir, e);
+                continue;
+            }
+        };
+        let mut empty = true;
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    skip(&dir, e);
+                    continue;
                 }
+            };
+            let path = entry.path();
+            empty = false;
+            if should_ignore(&path, ignore) {
+                continue;
             }
+            // Symlinks to directories are recorded as links
+            match entry.file_type() {
+                Ok(kind) if kind.is_dir() => dirs.push(path),
+                Ok(_) => queued += scan_path(&path, ignore, synced, index, outbox, &mut found),
+                Err(e) => skip(&path, e),
+            }
+        }
+        if empty && dir != root {
+            queued += scan_path(&dir, ignore, synced, index, outbox, &mut found);
         }
     }
-    Ok(())
+    let under_unread = |path: &str| {
+        unread.iter().any(|dir| {
+            dir.is_empty() || path.strip_prefix(dir.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+    };
+    for path in index.paths() {
+        if !found.contains(&path) && !under_unread(&path) {
+            info!(%path, "Forgetting path removed since the last scan");
+            index.remove(&path);
+        }
+    }
+    Ok(queued)
 }
 
-fn should_ignore(path: &Path) -> bool {
-    if path.components().any(|c| c.as_os_str() == ".hit") {
-        return true;
+/// Record `path` for [`scan`], which carries on past files it cannot read.
+fn scan_path(
+    path: &Path,
+    ignore: &IgnoreConfig,
+    synced: &SyncedWrites,
+    index: &Index,
+    outbox: &Outbox,
+    found: &mut HashSet<String>,
+) -> usize {
+    if let Ok(repo_path) = crate::repo::to_repo_path(path) {
+        found.insert(repo_path);
     }
-    if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
-        for suffix in IGNORED_SUFFIXES {
-            if name.ends_with(suffix) {
-                return true;
-            }
+    match record_path(path, ignore, synced, index, outbox) {
+        Ok(queued) => queued as usize,
+        Err(e) => {
+            warn!(%e, path = %path.display(), "failed to scan path");
+            0
         }
     }
-    false
+}
+
+/// Store the file, symlink or empty directory at `path` and queue the
+/// change if its content or mode differs from the index, which is updated
+/// to match. The caller saves the index. Returns whether a change was
+/// queued.
+fn record_path(
+    path: &Path,
+    ignore: &IgnoreConfig,
+    synced: &SyncedWrites,
+    index: &Index,
+    outbox: &Outbox,
+) -> std::io::Result<bool> {
+    if should_ignore(path, ignore) {
+        return Ok(false);
+    }
+    let Some(stored) = store_path(path)? else {
+        return Ok(false);
+    };
+    let repo_path = crate::repo::to_repo_path(path)?;
+    let entry = IndexEntry { hash: stored.hash, mode: stored.mode };
+    let (hash, mode) = (entry.hash, entry.mode);
+    let previous = index.get(&repo_path);
+    let skip = if synced.is_echo(path, &hash) {
+        Some("written by sync")
+    } else {
+        match previous {
+            Some(previous) if previous == entry => Some("unchanged"),
+            _ => None,
+        }
+    };
+    if let Some(reason) = skip {
+        info!(path = %path.display(), %hash, reason, "Skipping change");
+        index.insert(repo_path, entry);
+        return Ok(false);
+    }
+    info!(path = %path.display(), %hash, ?mode, objects = stored.written.len(), "Detected change stored");
+    let change = new_change(&hash, path, mode).map_err(|e| std::io::Error::other(e.to_string()))?;
+    outbox.push(&change)?;
+    index.insert(repo_path, entry);
+    Ok(true)
+}
+
+fn should_ignore(path: &Path, ignore: &IgnoreConfig) -> bool {
+    // notify reports absolute paths; ignore rules are relative to the root
+    let relative = std::env::current_dir()
+        .ok()
+        .and_then(|root| path.strip_prefix(root).ok().map(Path::to_path_buf));
+    ignore.is_ignored(relative.as_deref().unwrap_or(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Works in a new temporary directory until dropped, so the files and
+    /// objects a test records stay out of the source tree.
+    struct TempRepo {
+        _dir: tempfile::TempDir,
+        original: PathBuf,
+    }
+
+    impl TempRepo {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let original = std::env::current_dir().unwrap();
+            std::env::set_current_dir(dir.path()).unwrap();
+            Self { _dir: dir, original }
+        }
+    }
+
+    impl Drop for TempRepo {
+        fn drop(&mut self) {
+            std::env::set_current_dir(&self.original).unwrap();
+        }
+    }
+
+    #[test]
+    fn debounces_repeated_events() {
+        let debouncer = Debouncer::default();
+        debouncer.add([PathBuf::from("a"), PathBuf::from("b")]);
+        let start = Instant::now();
+        let (settled, next) = debouncer.take_settled(start);
+        assert!(settled.is_empty());
+        assert!(next.unwrap() <= DEBOUNCE);
+
+        std::thread::sleep(DEBOUNCE / 2);
+        debouncer.add([PathBuf::from("b")]);
+        let (settled, next) = debouncer.take_settled(start + DEBOUNCE);
+        assert_eq!(settled, vec![PathBuf::from("a")]);
+        assert!(next.is_some());
+
+        let (settled, next) = debouncer.take_settled(Instant::now() + DEBOUNCE);
+        assert_eq!(settled, vec![PathBuf::from("b")]);
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[serial_test::serial]
+    fn queues_mode_only_changes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _repo = TempRepo::new();
+        let outbox = Outbox::default();
+        let (ignore, synced) = (IgnoreConfig::default(), SyncedWrites::default());
+        let index = Index::load().unwrap();
+        let path = Path::new("mode-test.sh");
+        std::fs::write(path, "#!/bin/sh\n").unwrap();
+        let record = || record_path(path, &ignore, &synced, &index, &outbox).unwrap();
+
+        let first = record();
+        let unchanged = record();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let chmod = record();
+        let modes: Vec<_> = outbox.pending().unwrap().into_iter().map(|c| (c.path, c.mode)).collect();
+
+        assert_eq!((first, unchanged, chmod), (true, false, true));
+        assert_eq!(
+            modes,
+            vec![
+                ("mode-test.sh".to_string(), FileMode::Regular),
+                ("mode-test.sh".to_string(), FileMode::Executable),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[serial_test::serial]
+    fn keeps_index_entries_under_unreadable_dirs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _repo = TempRepo::new();
+        let outbox = Outbox::default();
+        let (ignore, synced) = (IgnoreConfig::default(), SyncedWrites::default());
+        let index = Index::load().unwrap();
+        let root = std::env::current_dir().unwrap();
+        std::fs::create_dir("locked").unwrap();
+        std::fs::write("locked/a.txt", "a").unwrap();
+        std::fs::write("b.txt", "b").unwrap();
+        assert_eq!(scan(&root, &ignore, &synced, &index, &outbox).unwrap(), 2);
+
+        std::fs::set_permissions("locked", std::fs::Permissions::from_mode(0o000)).unwrap();
+        std::fs::write("c.txt", "c").unwrap();
+        // Permissions do not stop root
+        let enforced = std::fs::read_dir("locked").is_err();
+        let queued = scan(&root, &ignore, &synced, &index, &outbox);
+        std::fs::set_permissions("locked", std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(queued.unwrap(), 1);
+        if enforced {
+            assert_eq!(index.paths(), vec!["b.txt", "c.txt", "locked/a.txt"]);
+        }
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn queues_new_paths_with_stored_content() {
+        let _repo = TempRepo::new();
+        let outbox = Outbox::default();
+        let (ignore, synced) = (IgnoreConfig::default(), SyncedWrites::default());
+        let index = Index::load().unwrap();
+        let paths = [Path::new("same-a.txt"), Path::new("same-b.txt")];
+        for path in paths {
+            std::fs::write(path, "same content\n").unwrap();
+        }
+
+        // The second file's blob is already stored when it is recorded
+        let queued: Vec<_> = paths
+            .iter()
+            .map(|path| record_path(path, &ignore, &synced, &index, &outbox).unwrap())
+            .collect();
+        let pending: Vec<_> = outbox.pending().unwrap().into_iter().map(|c| (c.path, c.hash)).collect();
+
+        assert_eq!(queued, vec![true, true]);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].0, "same-a.txt");
+        assert_eq!(pending[1].0, "same-b.txt");
+        assert_eq!(pending[0].1, pending[1].1);
+    }
 }
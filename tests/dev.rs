@@ -0,0 +1,100 @@
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+use serial_test::serial;
+use tokio::sync::broadcast;
+use tokio::time::sleep;
+
+use hit_with_gpt::commit::{Commit, CommitStore};
+use hit_with_gpt::object::{Blob, FileMode, Hashable, Object};
+use hit_with_gpt::server::{self, AppState, Change};
+use hit_with_gpt::storage::ObjectStore;
+
+/// Wait until `commits` holds `count` commits, or give up after a few seconds.
+async fn wait_for_commits(commits: &CommitStore, count: usize) -> Vec<Commit> {
+    for _ in 0..50 {
+        let all = commits.all().unwrap();
+        if all.len() >= count {
+            return all;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    commits.all().unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn dev_does_not_send_synced_files_back() {
+    let repo = tempfile::tempdir().unwrap();
+    let original_dir = env::current_dir().unwrap();
+    env::set_current_dir(repo.path()).unwrap();
+    fs::create_dir_all(".hit/objects").unwrap();
+
+    let server_dir = tempfile::tempdir().unwrap();
+    let commits = CommitStore::default();
+    let (tx, _) = broadcast::channel(100);
+    let mut state = AppState::new(commits.clone(), tx);
+    state.objects = ObjectStore::in_dir(server_dir.path());
+    let remote = Object::Blob(Blob { content: b"from another client".to_vec() });
+    state.objects.write_object(&remote).unwrap();
+    let app = server::app(state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    unsafe { env::set_var("HIT_SERVER_URL", &url) };
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let dev = tokio::spawn(async { hit_with_gpt::session::dev().await.map_err(|e| e.to_string()) });
+    sleep(Duration::from_millis(500)).await;
+
+    // Another client commits a file, which sync writes here
+    let change = Change {
+        hash: remote.hash(),
+        path: "remote.txt".into(),
+        timestamp: 1,
+        client: None,
+        mode: FileMode::Regular,
+    };
+    let res = reqwest::Client::new()
+        .post(format!("{}/changes", url))
+        .json(&change)
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success());
+    for _ in 0..50 {
+        if fs::metadata("remote.txt").is_ok() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    let synced = fs::read("remote.txt");
+
+    // A local edit is still sent
+    sleep(Duration::from_millis(500)).await;
+    fs::write("local.txt", b"written here").unwrap();
+    let all = wait_for_commits(&commits, 2).await;
+    sleep(Duration::from_millis(500)).await;
+    let after = commits.all().unwrap();
+    // Sync skipped the local change coming back, instead of rewriting the
+    // file and keeping a backup of it
+    let backup = fs::metadata("local.bak").is_ok();
+    let client_id = hit_with_gpt::repo::client_id().unwrap();
+
+    dev.abort();
+    server_handle.abort();
+    env::set_current_dir(original_dir).unwrap();
+    unsafe { env::remove_var("HIT_SERVER_URL") };
+
+    assert_eq!(synced.unwrap(), b"from another client");
+    let paths: Vec<&str> = all.iter().map(|c| c.changes[0].path.as_str()).collect();
+    assert_eq!(paths.len(), 2, "{:?}", paths);
+    assert_eq!(paths[0], "remote.txt");
+    assert_eq!(paths[1], "local.txt");
+    assert_eq!(after.len(), 2);
+    assert_eq!(all[0].changes[0].client, None);
+    assert_eq!(all[1].changes[0].client.as_deref(), Some(client_id.as_str()));
+    assert!(!backup);
+}
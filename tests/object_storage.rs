@@ -1,4 +1,5 @@
-use hit_with_gpt::object::{Blob, Object, Hashable};
+use hit_with_gpt::object::{Blob, Object, Hashable, legacy_hash};
+use hit_with_gpt::object_id::ObjectId;
 use hit_with_gpt::watcher::send_object_to_server;
 use hit_with_gpt::server::AppState;
 use hit_with_gpt::commit::CommitStore;
@@ -21,10 +22,7 @@ use serial_test::serial;
 fn create_test_app() -> Router {
     let commits = CommitStore::default();
     let (tx, _) = broadcast::channel(100);
-    let state = AppState {
-        commits,
-        broadcaster: tx,
-    };
+    let state = AppState::new(commits, tx);
     hit_with_gpt::server::app(state)
 }
 
@@ -40,7 +38,7 @@ async fn test_store_object_endpoint() {
     let hash = obj.hash();
     
     // Serialize the object
-    let serialized = bincode::serialize(&obj).unwrap();
+    let serialized = obj.encode();
     
     // Send PUT request to store the object
     let req = Request::builder()
@@ -85,7 +83,7 @@ async fn test_get_object_endpoint() {
     
     // Verify the response content
     let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
-    let retrieved_obj: Object = bincode::deserialize(&body).unwrap();
+    let retrieved_obj = Object::decode(&body).unwrap();
     
     match retrieved_obj {
         Object::Blob(retrieved_blob) => {
@@ -100,7 +98,7 @@ async fn test_get_nonexistent_object() {
     let app = create_test_app();
     
     // Try to get an object that doesn't exist
-    let fake_hash = "nonexistent_hash_12345";
+    let fake_hash = ObjectId::from([0x12; 32]);
     let req = Request::builder()
         .method("GET")
         .uri(format!("/objects/{}", fake_hash))
@@ -111,6 +109,21 @@ async fn test_get_nonexistent_object() {
     assert_eq!(resp.status(), StatusCode::NOT_FOUND);
 }
 
+#[tokio::test]
+async fn test_malformed_object_ids_are_rejected() {
+    for id in ["nonexistent_hash_12345", "..%2F..%2Fconfig", &"A".repeat(64)] {
+        for method in ["GET", "PUT"] {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("/objects/{}", id))
+                .body(Body::from("data"))
+                .unwrap();
+            let resp = create_test_app().oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{} {}", method, id);
+        }
+    }
+}
+
 #[tokio::test]
 async fn test_store_object_with_hash_mismatch() {
     let app = create_test_app();
@@ -121,10 +134,10 @@ async fn test_store_object_with_hash_mismatch() {
     };
     let obj = Object::Blob(blob);
     let _correct_hash = obj.hash();
-    let wrong_hash = "wrong_hash_12345";
+    let wrong_hash = ObjectId::from([0; 32]);
     
     // Serialize the object
-    let serialized = bincode::serialize(&obj).unwrap();
+    let serialized = obj.encode();
     
     // Send PUT request with wrong hash
     let req = Request::builder()
@@ -142,7 +155,7 @@ async fn test_store_object_with_hash_mismatch() {
 async fn test_store_object_with_invalid_data() {
     let app = create_test_app();
     
-    let hash = "some_hash";
+    let hash = ObjectId::from([1; 32]);
     let invalid_data = b"not a valid serialized object";
     
     // Send PUT request with invalid data
@@ -175,7 +188,7 @@ async fn test_roundtrip_object_storage() {
         let hash = obj.hash();
         
         // Serialize and store the object
-        let serialized = bincode::serialize(&obj).unwrap();
+        let serialized = obj.encode();
         let store_req = Request::builder()
             .method("PUT")
             .uri(format!("/objects/{}", hash))
@@ -198,7 +211,7 @@ async fn test_roundtrip_object_storage() {
         
         // Verify the content matches
         let body = axum::body::to_bytes(get_resp.into_body(), usize::MAX).await.unwrap();
-        let retrieved_obj: Object = bincode::deserialize(&body).unwrap();
+        let retrieved_obj = Object::decode(&body).unwrap();
         
         match retrieved_obj {
             Object::Blob(retrieved_blob) => {
@@ -216,10 +229,7 @@ async fn test_send_object_to_server_integration() {
     // Start a test server
     let commits = CommitStore::default();
     let (tx, _) = broadcast::channel(100);
-    let state = AppState {
-        commits,
-        broadcaster: tx,
-    };
+    let state = AppState::new(commits, tx);
     let app = hit_with_gpt::server::app(state);
     
     // Start the server on a test port
@@ -244,11 +254,8 @@ async fn test_send_object_to_server_integration() {
     };
     let obj = Object::Blob(blob.clone());
     
-    // Test sending the object to the server (run in blocking context)
-    let obj_clone = obj.clone();
-    let result = tokio::task::spawn_blocking(move || -> Result<(), String> {
-        send_object_to_server(&obj_clone).map_err(|e| e.to_string())
-    }).await.unwrap();
+    // Test sending the object to the server
+    let result = send_object_to_server(&obj).await.map_err(|e| e.to_string());
     
     assert!(result.is_ok(), "Failed to send object to server: {:?}", result);
     
@@ -261,7 +268,7 @@ async fn test_send_object_to_server_integration() {
     assert_eq!(resp.status(), reqwest::StatusCode::OK);
     
     let body = resp.bytes().await.unwrap();
-    let retrieved_obj: Object = bincode::deserialize(&body).unwrap();
+    let retrieved_obj = Object::decode(&body).unwrap();
     
     match retrieved_obj {
         Object::Blob(retrieved_blob) => {
@@ -287,9 +294,7 @@ async fn test_send_object_to_server_failure() {
     let obj = Object::Blob(blob);
     
     // This should fail because nothing is listening on port 9999
-    let result = tokio::task::spawn_blocking(move || -> Result<(), String> {
-        send_object_to_server(&obj).map_err(|e| e.to_string())
-    }).await.unwrap();
+    let result = send_object_to_server(&obj).await.map_err(|e| e.to_string());
     
     assert!(result.is_err(), "Expected send_object_to_server to fail with connection refused, but it succeeded");
     
@@ -315,7 +320,7 @@ async fn test_concurrent_object_operations() {
             let hash = obj.hash();
             
             // Store the object
-            let serialized = bincode::serialize(&obj).unwrap();
+            let serialized = obj.encode();
             let store_req = Request::builder()
                 .method("PUT")
                 .uri(format!("/objects/{}", hash))
@@ -337,7 +342,7 @@ async fn test_concurrent_object_operations() {
             assert_eq!(get_resp.status(), StatusCode::OK);
             
             let body = axum::body::to_bytes(get_resp.into_body(), usize::MAX).await.unwrap();
-            let retrieved_obj: Object = bincode::deserialize(&body).unwrap();
+            let retrieved_obj = Object::decode(&body).unwrap();
             
             match retrieved_obj {
                 Object::Blob(retrieved_blob) => {
@@ -367,7 +372,7 @@ async fn test_object_storage_with_large_content() {
     let hash = obj.hash();
     
     // Serialize and store the object
-    let serialized = bincode::serialize(&obj).unwrap();
+    let serialized = obj.encode();
     let store_req = Request::builder()
         .method("PUT")
         .uri(format!("/objects/{}", hash))
@@ -390,7 +395,7 @@ async fn test_object_storage_with_large_content() {
     
     // Verify the content matches
     let body = axum::body::to_bytes(get_resp.into_body(), usize::MAX).await.unwrap();
-    let retrieved_obj: Object = bincode::deserialize(&body).unwrap();
+    let retrieved_obj = Object::decode(&body).unwrap();
     
     match retrieved_obj {
         Object::Blob(retrieved_blob) => {
@@ -400,6 +405,63 @@ async fn test_object_storage_with_large_content() {
     }
 }
 
+#[tokio::test]
+async fn test_store_object_streams_bodies_over_default_limit() {
+    let app = create_test_app();
+
+    // Larger than axum's default 2 MB buffered body limit
+    let content: Vec<u8> = (0..3_000_000).map(|i| (i % 251) as u8).collect();
+    let obj = Object::Blob(Blob { content: content.clone() });
+    let hash = obj.hash();
+
+    let store_req = Request::builder()
+        .method("PUT")
+        .uri(format!("/objects/{}", hash))
+        .header("Content-Type", "application/octet-stream")
+        .body(Body::from(obj.encode()))
+        .unwrap();
+    let store_resp = app.clone().oneshot(store_req).await.unwrap();
+    assert_eq!(store_resp.status(), StatusCode::OK);
+
+    let get_req = Request::builder()
+        .method("GET")
+        .uri(format!("/objects/{}", hash))
+        .body(Body::empty())
+        .unwrap();
+    let get_resp = app.oneshot(get_req).await.unwrap();
+    assert_eq!(get_resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(get_resp.into_body(), usize::MAX).await.unwrap();
+    let retrieved_obj = Object::decode(&body).unwrap();
+    assert_eq!(retrieved_obj, obj);
+}
+
+#[tokio::test]
+async fn test_store_object_accepts_legacy_bincode_body() {
+    let app = create_test_app();
+
+    let obj = Object::Blob(Blob { content: b"sent by an old client".to_vec() });
+    let hash = legacy_hash(&obj);
+
+    let store_req = Request::builder()
+        .method("PUT")
+        .uri(format!("/objects/{}", hash))
+        .header("Content-Type", "application/octet-stream")
+        .body(Body::from(bincode::serialize(&obj).unwrap()))
+        .unwrap();
+    let store_resp = app.clone().oneshot(store_req).await.unwrap();
+    assert_eq!(store_resp.status(), StatusCode::OK);
+
+    let get_req = Request::builder()
+        .method("GET")
+        .uri(format!("/objects/{}", hash))
+        .body(Body::empty())
+        .unwrap();
+    let get_resp = app.oneshot(get_req).await.unwrap();
+    assert_eq!(get_resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(get_resp.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(Object::decode(&body).unwrap(), obj);
+}
+
 #[tokio::test]
 async fn test_object_deduplication() {
     let app = create_test_app();
@@ -416,7 +478,7 @@ async fn test_object_deduplication() {
     let hash = obj1.hash();
     
     // Store the first object
-    let serialized1 = bincode::serialize(&obj1).unwrap();
+    let serialized1 = obj1.encode();
     let store_req1 = Request::builder()
         .method("PUT")
         .uri(format!("/objects/{}", hash))
@@ -428,7 +490,7 @@ async fn test_object_deduplication() {
     assert_eq!(store_resp1.status(), StatusCode::OK);
     
     // Store the second identical object (should succeed)
-    let serialized2 = bincode::serialize(&obj2).unwrap();
+    let serialized2 = obj2.encode();
     let store_req2 = Request::builder()
         .method("PUT")
         .uri(format!("/objects/{}", hash))
@@ -2,13 +2,17 @@ use std::time::Duration;
 
 use futures_util::StreamExt;
 use reqwest_eventsource::{Event, EventSource};
-use serde_json;
 use std::collections::HashSet;
+use std::path::PathBuf;
 use tokio::net::TcpListener;
 use tokio::sync::broadcast;
 use tokio::time::sleep;
 
-use hit_with_gpt::object::{Blob, Hashable, Object};
+use axum::Extension;
+use hit_with_gpt::auth::Permissions;
+use hit_with_gpt::object::{Blob, FileMode, Hashable, Object};
+use hit_with_gpt::commit::CommitStore;
+use hit_with_gpt::object_id::ObjectId;
 use hit_with_gpt::server::{Change, ChangeEvent};
 use hit_with_gpt::storage::{OBJECT_DIR, read_object};
 use hit_with_gpt::streaming::{self, Broadcaster};
@@ -16,10 +20,33 @@ use hit_with_gpt::sync::apply_change;
 use httpmock::{Method::GET, MockServer};
 use serial_test::serial;
 
+/// Works in a new temporary directory until dropped, so the files sync
+/// writes stay out of the source tree.
+struct TempRepo {
+    _dir: tempfile::TempDir,
+    original: PathBuf,
+}
+
+impl TempRepo {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let original = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+        Self { _dir: dir, original }
+    }
+}
+
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        std::env::set_current_dir(&self.original).unwrap();
+    }
+}
+
 #[tokio::test]
 async fn parses_sse_event() {
     let (tx, _) = broadcast::channel(8);
-    let router = streaming::router(Broadcaster::new(tx.clone()));
+    let router = streaming::router(Broadcaster::new(tx.clone()))
+        .layer(Extension(Permissions::unrestricted()));
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
     tokio::spawn(async move {
@@ -31,9 +58,11 @@ async fn parses_sse_event() {
         sleep(Duration::from_millis(100)).await;
         tx.send(ChangeEvent {
             change: Change {
-                hash: "abcd".into(),
+                hash: ObjectId::from([0xab; 32]),
                 path: "foo.txt".into(),
                 timestamp: 1,
+                client: None,
+                mode: FileMode::Regular,
             },
             commit_id: 1,
         })
@@ -47,7 +76,7 @@ async fn parses_sse_event() {
     matches!(es.next().await.unwrap().unwrap(), Event::Open);
     if let Event::Message(msg) = es.next().await.unwrap().unwrap() {
         let ev: ChangeEvent = serde_json::from_str(&msg.data).unwrap();
-        assert_eq!(ev.change.hash, "abcd");
+        assert_eq!(ev.change.hash, ObjectId::from([0xab; 32]));
         assert_eq!(ev.change.path, "foo.txt");
         assert_eq!(ev.commit_id, 1);
     } else {
@@ -61,13 +90,13 @@ async fn applies_change_from_server() {
     use std::fs;
 
     let server = MockServer::start();
-    fs::remove_dir_all(".hit").ok();
+    let _repo = TempRepo::new();
     let path = "synced.txt";
     let blob = Blob {
         content: b"hello".to_vec(),
     };
     let obj = Object::Blob(blob.clone());
-    let bytes = bincode::serialize(&obj).unwrap();
+    let bytes = obj.encode();
     let hash = obj.hash();
 
     let mock = server.mock(|when, then| {
@@ -77,9 +106,11 @@ async fn applies_change_from_server() {
 
     let client = reqwest::Client::new();
     let change = Change {
-        hash: hash.clone(),
+        hash,
         path: path.into(),
         timestamp: 1,
+        client: None,
+        mode: FileMode::Regular,
     };
     apply_change(&client, &server.url(""), &change)
         .await
@@ -102,9 +133,11 @@ async fn applies_change_from_server() {
 async fn error_when_object_unreachable() {
     let client = reqwest::Client::new();
     let change = Change {
-        hash: "abcd".into(),
+        hash: ObjectId::from([0xab; 32]),
         path: "nope".into(),
         timestamp: 0,
+        client: None,
+        mode: FileMode::Regular,
     };
     let err = apply_change(&client, "http://127.0.0.1:59999", &change).await;
     assert!(err.is_err());
@@ -113,16 +146,15 @@ async fn error_when_object_unreachable() {
 #[tokio::test]
 #[serial]
 async fn rejects_path_traversal() {
-    use std::fs;
     use std::path::Path;
 
     let server = MockServer::start();
-    fs::remove_dir_all(".hit").ok();
+    let _repo = TempRepo::new();
     let blob = Blob {
         content: b"hi".to_vec(),
     };
     let obj = Object::Blob(blob.clone());
-    let bytes = bincode::serialize(&obj).unwrap();
+    let bytes = obj.encode();
     let hash = obj.hash();
 
     server.mock(|when, then| {
@@ -132,9 +164,11 @@ async fn rejects_path_traversal() {
 
     let client = reqwest::Client::new();
     let change = Change {
-        hash: hash.clone(),
+        hash,
         path: "../evil.txt".into(),
         timestamp: 1,
+        client: None,
+        mode: FileMode::Regular,
     };
     let res = apply_change(&client, &server.url(""), &change).await;
     assert!(res.is_err());
@@ -143,17 +177,110 @@ async fn rejects_path_traversal() {
 
 #[tokio::test]
 #[serial]
-async fn rejects_non_blob_objects() {
+async fn keeps_directories_of_synced_files() {
+    use std::fs;
+    use std::path::Path;
+
+    let server = MockServer::start();
+    let _repo = TempRepo::new();
+    let obj = Object::Blob(Blob { content: b"nested".to_vec() });
+    let bytes = obj.encode();
+    let hash = obj.hash();
+    server.mock(|when, then| {
+        when.method(GET).path(format!("/objects/{hash}"));
+        then.status(200).body(bytes.clone());
+    });
+
+    let client = reqwest::Client::new();
+    for path in ["/tmp/synced-abs.txt", "synced-dir/../../x.txt", ".hit/HEAD"] {
+        let change = Change { hash, path: path.into(), timestamp: 1, client: None, mode: FileMode::Regular };
+        assert!(apply_change(&client, &server.url(""), &change).await.is_err(), "{}", path);
+    }
+    assert!(!Path::new("synced-abs.txt").exists());
+
+    let change = Change {
+        hash,
+        path: "synced-dir/sub/file.txt".into(),
+        timestamp: 1,
+        client: None,
+        mode: FileMode::Regular,
+    };
+    apply_change(&client, &server.url(""), &change).await.unwrap();
+    let content = fs::read("synced-dir/sub/file.txt");
+    assert_eq!(content.unwrap(), b"nested");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+#[serial]
+async fn restores_modes_links_and_empty_directories() {
+    use hit_with_gpt::object::Tree;
     use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = MockServer::start();
+    let _repo = TempRepo::new();
+    let serve = |obj: Object| {
+        let (hash, bytes) = (obj.hash(), obj.encode());
+        server.mock(|when, then| {
+            when.method(GET).path(format!("/objects/{hash}"));
+            then.status(200).body(bytes);
+        });
+        hash
+    };
+    let script = serve(Object::Blob(Blob { content: b"#!/bin/sh".to_vec() }));
+    let target = serve(Object::Blob(Blob { content: b"run.sh".to_vec() }));
+    let empty = serve(Object::Tree(Tree { entries: vec![] }));
+    let change = |hash, path: &str, mode| Change {
+        hash,
+        path: path.into(),
+        timestamp: 1,
+        client: None,
+        mode,
+    };
+
+    let client = reqwest::Client::new();
+    let url = server.url("");
+    let result = async {
+        apply_change(&client, &url, &change(script, "synced-modes/run.sh", FileMode::Executable)).await?;
+        let executable = fs::metadata("synced-modes/run.sh")?.permissions().mode() & 0o111;
+        apply_change(&client, &url, &change(target, "synced-modes/link", FileMode::Symlink)).await?;
+        apply_change(&client, &url, &change(empty, "synced-modes/empty", FileMode::Regular)).await?;
+        // Only the mode changes
+        apply_change(&client, &url, &change(script, "synced-modes/run.sh", FileMode::Regular)).await?;
+        let regular = fs::metadata("synced-modes/run.sh")?.permissions().mode() & 0o111;
+        // Nothing is written through a synced link
+        fs::create_dir("synced-modes/dir")?;
+        std::os::unix::fs::symlink("dir", "synced-modes/dir-link")?;
+        let below_link = apply_change(&client, &url, &change(script, "synced-modes/dir-link/x", FileMode::Regular)).await;
+        Ok::<_, Box<dyn std::error::Error>>((executable, regular, below_link.is_err()))
+    }
+    .await;
+    let link = fs::read_link("synced-modes/link");
+    let empty_dir = fs::read_dir("synced-modes/empty").map(|d| d.count());
+    let written_below_link = fs::metadata("synced-modes/dir/x").is_ok();
+
+    let (executable, regular, refused) = result.unwrap();
+    assert_ne!(executable, 0);
+    assert_eq!(regular, 0);
+    assert_eq!(link.unwrap(), std::path::Path::new("run.sh"));
+    assert_eq!(empty_dir.unwrap(), 0);
+    assert!(refused);
+    assert!(!written_below_link);
+}
+
+#[tokio::test]
+#[serial]
+async fn rejects_non_blob_objects() {
     use std::path::Path;
 
     let server = MockServer::start();
-    fs::remove_dir_all(".hit").ok();
+    let _repo = TempRepo::new();
     let obj = Object::Commit(hit_with_gpt::object::Commit {
         tree: hit_with_gpt::object::Tree { entries: vec![] },
         message: "msg".into(),
     });
-    let bytes = bincode::serialize(&obj).unwrap();
+    let bytes = obj.encode();
     let hash = obj.hash();
 
     server.mock(|when, then| {
@@ -163,9 +290,11 @@ async fn rejects_non_blob_objects() {
 
     let client = reqwest::Client::new();
     let change = Change {
-        hash: hash.clone(),
+        hash,
         path: "commit.txt".into(),
         timestamp: 1,
+        client: None,
+        mode: FileMode::Regular,
     };
     let res = apply_change(&client, &server.url(""), &change).await;
     assert!(res.is_err());
@@ -178,13 +307,13 @@ async fn backs_up_existing_file() {
     use std::fs;
 
     let server = MockServer::start();
-    fs::remove_dir_all(".hit").ok();
+    let _repo = TempRepo::new();
     fs::write("backup.txt", b"old").unwrap();
     let blob = Blob {
         content: b"new".to_vec(),
     };
     let obj = Object::Blob(blob.clone());
-    let bytes = bincode::serialize(&obj).unwrap();
+    let bytes = obj.encode();
     let hash = obj.hash();
 
     server.mock(|when, then| {
@@ -197,6 +326,8 @@ async fn backs_up_existing_file() {
         hash,
         path: "backup.txt".into(),
         timestamp: 1,
+        client: None,
+        mode: FileMode::Regular,
     };
     apply_change(&client, &server.url(""), &change)
         .await
@@ -206,6 +337,49 @@ async fn backs_up_existing_file() {
     assert_eq!(fs::read("backup.bak").unwrap(), b"old");
 }
 
+#[tokio::test]
+#[serial]
+async fn applies_chunked_change() {
+    use hit_with_gpt::object::ChunkedBlob;
+    use std::fs;
+
+    let server = MockServer::start();
+    let _repo = TempRepo::new();
+    let parts: [&[u8]; 2] = [b"first half, ", b"second half"];
+    let mut chunks = Vec::new();
+    for part in parts {
+        let obj = Object::Blob(Blob { content: part.to_vec() });
+        let hash = obj.hash();
+        let bytes = obj.encode();
+        server.mock(|when, then| {
+            when.method(GET).path(format!("/objects/{hash}"));
+            then.status(200).body(bytes);
+        });
+        chunks.push(hash);
+    }
+    let manifest = Object::Chunked(ChunkedBlob { size: 23, chunks });
+    let hash = manifest.hash();
+    let bytes = manifest.encode();
+    server.mock(|when, then| {
+        when.method(GET).path(format!("/objects/{hash}"));
+        then.status(200).body(bytes);
+    });
+
+    let client = reqwest::Client::new();
+    let change = Change {
+        hash,
+        path: "chunked.txt".into(),
+        timestamp: 1,
+        client: None,
+        mode: FileMode::Regular,
+    };
+    apply_change(&client, &server.url(""), &change)
+        .await
+        .unwrap();
+
+    assert_eq!(fs::read("chunked.txt").unwrap(), b"first half, second half");
+}
+
 #[tokio::test]
 async fn skips_duplicate_commit_ids() {
     let mut processed = HashSet::new();
@@ -213,17 +387,21 @@ async fn skips_duplicate_commit_ids() {
     let events = vec![
         ChangeEvent {
             change: Change {
-                hash: "h1".into(),
+                hash: ObjectId::from([1; 32]),
                 path: "p".into(),
                 timestamp: 1,
+                client: None,
+                mode: FileMode::Regular,
             },
             commit_id: 1,
         },
         ChangeEvent {
             change: Change {
-                hash: "h1".into(),
+                hash: ObjectId::from([1; 32]),
                 path: "p".into(),
                 timestamp: 1,
+                client: None,
+                mode: FileMode::Regular,
             },
             commit_id: 1,
         },
@@ -241,3 +419,81 @@ async fn skips_duplicate_commit_ids() {
     assert_eq!(applied, 1);
     assert_eq!(last_commit, 1);
 }
+
+#[tokio::test]
+async fn replays_missed_commits_before_live_events() {
+    let change = |n: u8| Change {
+        hash: ObjectId::from([n; 32]),
+        path: format!("f{}", n),
+        timestamp: n as u64,
+        client: None,
+        mode: FileMode::Regular,
+    };
+    let history = CommitStore::default();
+    for n in 1..=3 {
+        history.add_commit(change(n)).await.unwrap();
+    }
+    let (tx, _) = broadcast::channel(8);
+    let router = streaming::router(Broadcaster::with_history(tx.clone(), history))
+        .layer(Extension(Permissions::unrestricted()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(200)).await;
+        // Commit 3 was already replayed and must not be sent twice.
+        for n in 3..=4 {
+            tx.send(ChangeEvent {
+                change: change(n),
+                commit_id: n as u64,
+            })
+            .unwrap();
+        }
+    });
+
+    let url = format!("http://{}/events?after=1", addr);
+    let mut es = EventSource::new(reqwest::Client::new().get(&url)).unwrap();
+    let mut ids = Vec::new();
+    while ids.len() < 3 {
+        match es.next().await.unwrap().unwrap() {
+            Event::Open => {}
+            Event::Message(msg) => {
+                let ev: ChangeEvent = serde_json::from_str(&msg.data).unwrap();
+                assert_eq!(msg.id, ev.commit_id.to_string());
+                assert_eq!(ev.change.path, format!("f{}", ev.commit_id));
+                ids.push(ev.commit_id);
+            }
+        }
+    }
+    assert_eq!(ids, vec![2, 3, 4]);
+}
+
+#[tokio::test]
+async fn ends_event_stream_when_client_falls_behind() {
+    let (tx, _) = broadcast::channel(1);
+    let router = streaming::router(Broadcaster::new(tx.clone()))
+        .layer(Extension(Permissions::unrestricted()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router).await.unwrap();
+    });
+
+    let resp = reqwest::get(format!("http://{}/events", addr)).await.unwrap();
+    // More than the channel holds before the stream is polled again
+    for n in 1..=3u8 {
+        let change = Change {
+            hash: ObjectId::from([n; 32]),
+            path: format!("f{}", n),
+            timestamp: n as u64,
+            client: None,
+            mode: FileMode::Regular,
+        };
+        tx.send(ChangeEvent { change, commit_id: n as u64 }).unwrap();
+    }
+    let body = tokio::time::timeout(Duration::from_secs(5), resp.text()).await;
+    assert!(body.expect("stream did not end").unwrap().is_empty());
+}
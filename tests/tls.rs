@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::Path;
+
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use tokio::sync::broadcast;
+
+use hit_with_gpt::commit::CommitStore;
+use hit_with_gpt::config::{RemoteConfig, TlsConfig};
+use hit_with_gpt::server::{self, AppState};
+use hit_with_gpt::tls::{cert_fingerprint, client_config};
+
+/// Write a CA certificate and a certificate for `localhost` issued by it.
+fn write_certs(dir: &Path) -> TlsConfig {
+    let mut ca_params = CertificateParams::new(Vec::new());
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = Certificate::from_params(ca_params).unwrap();
+    let leaf = Certificate::from_params(CertificateParams::new(vec!["localhost".into()])).unwrap();
+    fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+    fs::write(dir.join("cert.pem"), leaf.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+    fs::write(dir.join("key.pem"), leaf.serialize_private_key_pem()).unwrap();
+    TlsConfig {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+    }
+}
+
+async fn get_commits(remote: &RemoteConfig) -> Result<reqwest::StatusCode, Box<dyn std::error::Error>> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(tls) = client_config(remote)? {
+        builder = builder.use_preconfigured_tls(tls);
+    }
+    let res = builder.build()?.get(format!("{}/commits", remote.url)).send().await?;
+    Ok(res.status())
+}
+
+#[tokio::test]
+async fn serves_https_to_clients_trusting_the_ca_or_pin() {
+    let dir = tempfile::tempdir().unwrap();
+    let tls = write_certs(dir.path());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, _) = broadcast::channel(8);
+    let app = server::app(AppState::new(CommitStore::default(), tx));
+    let served = tls.clone();
+    tokio::spawn(async move { server::serve(listener, app, Some(&served)).await });
+
+    let remote = |host: &str, ca: bool, pin: Option<String>| RemoteConfig {
+        url: format!("https://{}:{}", host, port),
+        token: None,
+        ca: ca.then(|| dir.path().join("ca.pem")),
+        pin,
+    };
+    let pin = cert_fingerprint(&tls.cert).unwrap();
+    let wrong_pin = "00".repeat(32);
+
+    // Not trusted by the system roots.
+    assert!(get_commits(&remote("localhost", false, None)).await.is_err());
+    assert_eq!(get_commits(&remote("localhost", true, None)).await.unwrap(), 200);
+    // The certificate is for localhost only, so only a pin accepts the IP.
+    assert!(get_commits(&remote("127.0.0.1", true, None)).await.is_err());
+    assert_eq!(get_commits(&remote("127.0.0.1", false, Some(pin.clone()))).await.unwrap(), 200);
+    assert_eq!(get_commits(&remote("localhost", true, Some(pin))).await.unwrap(), 200);
+    assert!(get_commits(&remote("localhost", false, Some(wrong_pin.clone()))).await.is_err());
+    assert!(get_commits(&remote("localhost", true, Some(wrong_pin))).await.is_err());
+}
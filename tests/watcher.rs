@@ -1,35 +1,151 @@
 use std::path::Path;
 
-use hit_with_gpt::watcher::send_change_to_server;
+use hit_with_gpt::object::{Blob, FileMode, Object};
+use hit_with_gpt::object_id::ObjectId;
+use hit_with_gpt::outbox::Outbox;
+use hit_with_gpt::server::Change;
+use hit_with_gpt::session::Session;
+use hit_with_gpt::storage::write_object;
+use hit_with_gpt::watcher::{flush_outbox, send_change_to_server, send_missing_objects_to_server};
 use serial_test::serial;
-use httpmock::Method::POST;
+use httpmock::Method::{HEAD, POST, PUT};
 use httpmock::MockServer;
 
 
-#[test]
+#[tokio::test]
 #[serial]
-fn reports_change_to_server() {
-    let server = MockServer::start();
-    let partial = serde_json::json!({"hash": "abcd", "path": "foo.txt"}).to_string();
-    let mock = server.mock(move |when, then| {
+async fn reports_change_to_server() {
+    let server = MockServer::start_async().await;
+    let hash = ObjectId::from([0xab; 32]);
+    let partial = serde_json::json!({"hash": hash.to_hex(), "path": "foo.txt"}).to_string();
+    let mock = server.mock_async(move |when, then| {
         when.method(POST)
             .path("/changes")
             .header("content-type", "application/json")
             .json_body_partial(partial);
         then.status(200);
-    });
+    }).await;
 
     unsafe { std::env::set_var("HIT_SERVER_URL", server.url("")); }
 
-    send_change_to_server("abcd", Path::new("foo.txt")).unwrap();
-    mock.assert();
-    assert!(mock.hits() >= 1);
+    send_change_to_server(&hash, Path::new("foo.txt")).await.unwrap();
+    mock.assert_async().await;
+    assert!(mock.hits_async().await >= 1);
 }
 
-#[test]
+#[tokio::test]
 #[serial]
-fn error_when_unreachable() {
+async fn error_when_unreachable() {
     unsafe { std::env::set_var("HIT_SERVER_URL", "http://127.0.0.1:59999"); }
-    let err = send_change_to_server("abcd", Path::new("foo.txt"));
+    let err = send_change_to_server(&ObjectId::from([0xab; 32]), Path::new("foo.txt")).await;
     assert!(err.is_err());
 }
+
+#[tokio::test]
+#[serial]
+async fn uploads_only_missing_objects() {
+    let dir = tempfile::tempdir().unwrap();
+    let original_dir = std::env::current_dir().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+    let present = write_object(&Object::Blob(Blob { content: b"present".to_vec() })).unwrap();
+    let absent = write_object(&Object::Blob(Blob { content: b"absent".to_vec() })).unwrap();
+
+    let server = MockServer::start_async().await;
+    let query = server.mock_async(|when, then| {
+        when.method(POST)
+            .path("/objects/missing")
+            .json_body(serde_json::json!({"hashes": [present, absent]}));
+        then.status(200).json_body(serde_json::json!({"missing": [absent]}));
+    }).await;
+    let put_present = server.mock_async(|when, then| {
+        when.method(PUT).path(format!("/objects/{}", present));
+        then.status(200);
+    }).await;
+    let put_absent = server.mock_async(|when, then| {
+        when.method(PUT).path(format!("/objects/{}", absent));
+        then.status(200);
+    }).await;
+    // A single object is checked with HEAD.
+    let head_present = server.mock_async(|when, then| {
+        when.method(HEAD).path(format!("/objects/{}", present));
+        then.status(200);
+    }).await;
+    unsafe { std::env::set_var("HIT_SERVER_URL", server.url("")); }
+
+    let sent = send_missing_objects_to_server(&[present, absent]).await.map_err(|e| e.to_string());
+    let sent_single = send_missing_objects_to_server(&[present]).await.map_err(|e| e.to_string());
+    std::env::set_current_dir(original_dir).unwrap();
+    unsafe { std::env::remove_var("HIT_SERVER_URL"); }
+
+    assert_eq!(sent.unwrap(), vec![absent]);
+    assert_eq!(sent_single.unwrap(), Vec::new());
+    query.assert_async().await;
+    head_present.assert_async().await;
+    put_absent.assert_async().await;
+    assert_eq!(put_present.hits_async().await, 0);
+}
+
+#[tokio::test]
+#[serial]
+async fn sets_aside_changes_the_server_refuses() {
+    let dir = tempfile::tempdir().unwrap();
+    let original_dir = std::env::current_dir().unwrap();
+    std::env::set_current_dir(dir.path()).unwrap();
+    let hash = write_object(&Object::Blob(Blob { content: b"content".to_vec() })).unwrap();
+    let change = |path: &str, hash: ObjectId| Change {
+        hash,
+        path: path.into(),
+        timestamp: 0,
+        client: None,
+        mode: FileMode::Regular,
+    };
+    let outbox = Outbox::default();
+    for queued in [
+        change("secret.txt", hash),
+        change("lost.txt", ObjectId::from([0xab; 32])),
+        change("open.txt", hash),
+        change("busy.txt", hash),
+        change("later.txt", hash),
+    ] {
+        outbox.push(&queued).unwrap();
+    }
+
+    let server = MockServer::start_async().await;
+    server.mock_async(|when, then| {
+        when.method(HEAD).path(format!("/objects/{}", hash));
+        then.status(200);
+    }).await;
+    let post = |path: &'static str, status: u16| {
+        server.mock_async(move |when, then| {
+            when.method(POST)
+                .path("/changes")
+                .json_body_partial(serde_json::json!({"path": path}).to_string());
+            then.status(status);
+        })
+    };
+    let secret = post("secret.txt", 403).await;
+    let open = post("open.txt", 200).await;
+    let busy = post("busy.txt", 503).await;
+    let later = post("later.txt", 200).await;
+    unsafe { std::env::set_var("HIT_SERVER_URL", server.url("")); }
+
+    let session = Session::open().unwrap();
+    let result = flush_outbox(&session, &outbox).await.map_err(|e| e.to_string());
+    let pending: Vec<String> = outbox.pending().unwrap().into_iter().map(|c| c.path).collect();
+    let rejected = outbox.rejected().unwrap();
+    std::env::set_current_dir(original_dir).unwrap();
+    unsafe { std::env::remove_var("HIT_SERVER_URL"); }
+
+    // Refused changes are set aside, a server error stops the flush
+    assert!(result.unwrap_err().starts_with("1 of 5 changes sent"));
+    assert_eq!(pending, vec!["busy.txt", "later.txt"]);
+    let rejected: Vec<(&str, &str)> = rejected.iter().map(|r| (r.change.path.as_str(), r.reason.as_str())).collect();
+    assert_eq!(rejected[0].0, "secret.txt");
+    assert!(rejected[0].1.contains("403"));
+    assert_eq!(rejected[1].0, "lost.txt");
+    assert!(rejected[1].1.contains("missing locally"));
+    assert_eq!(secret.hits_async().await, 1);
+    assert_eq!(open.hits_async().await, 1);
+    assert_eq!(busy.hits_async().await, 1);
+    assert_eq!(later.hits_async().await, 0);
+}
@@ -1,8 +1,11 @@
 use hit_with_gpt::commit::CommitStore;
-use hit_with_gpt::object::{Blob, Hashable, Object};
+use hit_with_gpt::object::{Blob, Hashable, Object, Tree};
 use hit_with_gpt::server::AppState;
-use hit_with_gpt::storage::{read_object, write_object};
-use hit_with_gpt::watcher::{handle_event, send_change_to_server, send_object_to_server};
+use hit_with_gpt::storage::{ObjectStore, read_object, write_object};
+use hit_with_gpt::outbox::Outbox;
+use hit_with_gpt::session::Session;
+use hit_with_gpt::index::Index;
+use hit_with_gpt::watcher::{handle_event, retry_outbox, scan_changes, send_change_to_server, send_object_to_server};
 
 use notify::Event;
 use notify::event::{CreateKind, EventKind};
@@ -20,10 +23,7 @@ async fn test_send_object_to_server_success() {
     // Start a test server
     let commits = CommitStore::default();
     let (tx, _) = broadcast::channel(100);
-    let state = AppState {
-        commits,
-        broadcaster: tx,
-    };
+    let state = AppState::new(commits, tx);
     let app = hit_with_gpt::server::app(state);
 
     // Start the server on a test port
@@ -62,12 +62,7 @@ async fn test_send_object_to_server_success() {
         let hash = obj.hash();
 
         // Test sending the object to the server
-        let obj_clone = obj.clone();
-        let result = tokio::task::spawn_blocking(move || -> Result<(), String> {
-            send_object_to_server(&obj_clone).map_err(|e| e.to_string())
-        })
-        .await
-        .unwrap();
+        let result = send_object_to_server(&obj).await.map_err(|e| e.to_string());
 
         assert!(
             result.is_ok(),
@@ -83,7 +78,7 @@ async fn test_send_object_to_server_success() {
         assert_eq!(resp.status(), reqwest::StatusCode::OK);
 
         let body = resp.bytes().await.unwrap();
-        let retrieved_obj: Object = bincode::deserialize(&body).unwrap();
+        let retrieved_obj = Object::decode(&body).unwrap();
 
         match retrieved_obj {
             Object::Blob(retrieved_blob) => {
@@ -104,10 +99,11 @@ async fn test_send_change_to_server_success() {
     // Start a test server
     let commits = CommitStore::default();
     let (tx, _) = broadcast::channel(100);
-    let state = AppState {
-        commits,
-        broadcaster: tx,
-    };
+    let mut state = AppState::new(commits, tx);
+    // The server only accepts changes to objects it has
+    let objects_dir = tempfile::tempdir().unwrap();
+    state.objects = ObjectStore::in_dir(objects_dir.path());
+    state.objects.write_object(&Object::Blob(Blob { content: b"test".to_vec() })).unwrap();
     let app = hit_with_gpt::server::app(state);
 
     // Start the server on a test port
@@ -127,14 +123,10 @@ async fn test_send_change_to_server_success() {
     sleep(Duration::from_millis(100)).await;
 
     // Test sending change notification
-    let test_hash = "test_hash_12345";
+    let test_hash = Object::Blob(Blob { content: b"test".to_vec() }).hash();
     let test_path = Path::new("test_file.txt");
 
-    let result = tokio::task::spawn_blocking(move || -> Result<(), String> {
-        send_change_to_server(test_hash, test_path).map_err(|e| e.to_string())
-    })
-    .await
-    .unwrap();
+    let result = send_change_to_server(&test_hash, test_path).await.map_err(|e| e.to_string());
 
     assert!(
         result.is_ok(),
@@ -200,7 +192,7 @@ fn test_handle_event_creates_and_stores_object() {
     let expected_hash = expected_obj.hash();
 
     // Check that the object file exists
-    let object_path = Path::new(".hit/objects").join(&expected_hash);
+    let object_path = Path::new(".hit/objects").join(expected_hash.to_hex());
     assert!(object_path.exists(), "Object file was not created");
 
     // Verify the stored object content
@@ -386,14 +378,17 @@ fn test_handle_event_ignores_directories() {
     // Create .hit directory structure
     fs::create_dir_all(".hit/objects").unwrap();
 
-    // Create a subdirectory
+    // Create a subdirectory; its files are stored on their own events
     let sub_dir_path = temp_path.join("subdirectory");
     fs::create_dir(&sub_dir_path).unwrap();
+    fs::write(sub_dir_path.join("file.txt"), b"in a directory").unwrap();
+    let empty_dir_path = temp_path.join("empty");
+    fs::create_dir(&empty_dir_path).unwrap();
 
     // Create a mock event for directory creation
-    let event = Event {
+    let event = |path: PathBuf| Event {
         kind: EventKind::Create(CreateKind::Folder),
-        paths: vec![sub_dir_path],
+        paths: vec![path],
         attrs: Default::default(),
     };
 
@@ -401,16 +396,179 @@ fn test_handle_event_ignores_directories() {
     let objects_before = fs::read_dir(".hit/objects").unwrap().count();
 
     // Handle the event
-    let result = handle_event(event);
-    assert!(result.is_ok(), "handle_event failed: {:?}", result);
+    let result = handle_event(event(sub_dir_path));
+    let objects_after = fs::read_dir(".hit/objects").unwrap().count();
+
+    // An empty directory is queued, as an empty tree
+    let empty_result = handle_event(event(empty_dir_path));
+    let pending = Outbox::default().pending();
+
+    // Clean up
+    env::set_current_dir(original_dir).unwrap();
 
+    assert!(result.is_ok(), "handle_event failed: {:?}", result);
     // Verify no objects were created for directory
-    let objects_after = fs::read_dir(".hit/objects").unwrap().count();
     assert_eq!(
         objects_before, objects_after,
         "Objects were created for directory"
     );
+    assert!(empty_result.is_ok(), "handle_event failed: {:?}", empty_result);
+    let pending = pending.unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].path, "empty");
+    assert_eq!(pending[0].hash, Object::Tree(Tree { entries: vec![] }).hash());
+}
 
-    // Clean up
+#[test]
+#[serial]
+fn test_scan_queues_changes_made_while_stopped() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let original_dir = env::current_dir().unwrap();
+    env::set_current_dir(temp_dir.path()).unwrap();
+    fs::create_dir_all(".hit/objects").unwrap();
+
+    let paths = || -> Vec<String> {
+        let mut paths: Vec<String> = Outbox::default().pending().unwrap().into_iter().map(|c| c.path).collect();
+        paths.sort();
+        paths
+    };
+    let result = (|| -> std::io::Result<_> {
+        // The first run records the whole tree, except ignored files
+        fs::create_dir_all("src/empty")?;
+        fs::write("src/main.rs", b"fn main() {}")?;
+        fs::write("notes.txt", b"first")?;
+        fs::write("notes.txt.swp", b"swap")?;
+        let first = scan_changes()?;
+        let first_paths = paths();
+
+        // Nothing changed in between
+        let again = scan_changes()?;
+
+        // Changes made while the watcher was not running
+        Outbox::default().remove_first(first)?;
+        fs::write("notes.txt", b"second")?;
+        fs::write("src/lib.rs", b"pub mod x;")?;
+        fs::remove_file("src/main.rs")?;
+        let later = scan_changes()?;
+        let index = Index::load()?.paths();
+        Ok((first, first_paths, again, later, paths(), index))
+    })();
     env::set_current_dir(original_dir).unwrap();
+
+    let (first, first_paths, again, later, later_paths, index) = result.unwrap();
+    assert_eq!(first, 3);
+    assert_eq!(first_paths, vec!["notes.txt", "src/empty", "src/main.rs"]);
+    assert_eq!(again, 0);
+    assert_eq!(later, 2);
+    assert_eq!(later_paths, vec!["notes.txt", "src/lib.rs"]);
+    assert_eq!(index, vec!["notes.txt", "src/empty", "src/lib.rs"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_outbox_keeps_changes_until_server_accepts() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let original_dir = env::current_dir().unwrap();
+    env::set_current_dir(temp_dir.path()).unwrap();
+    fs::create_dir_all(".hit/objects").unwrap();
+
+    // Reserve an address, but nothing listens on it yet
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    unsafe { env::set_var("HIT_SERVER_URL", format!("http://{}", addr)) };
+
+    fs::write("offline.txt", b"written while the server was down").unwrap();
+    let event = Event {
+        kind: EventKind::Create(CreateKind::File),
+        paths: vec![PathBuf::from("offline.txt")],
+        attrs: Default::default(),
+    };
+    tokio::task::spawn_blocking(move || handle_event(event).unwrap()).await.unwrap();
+    let pending = Outbox::default().pending().unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].path, "offline.txt");
+
+    // A failed attempt puts off the next one
+    let session = Session::open().unwrap();
+    retry_outbox(&session, &Outbox::default()).await;
+    retry_outbox(&session, &Outbox::default()).await;
+    let retry = Outbox::default().retry_state().unwrap();
+    assert_eq!(retry.failures, 1);
+    assert!(retry.last_error.is_some());
+
+    // The server comes up
+    let server_dir = tempfile::tempdir().unwrap();
+    let commits = CommitStore::default();
+    let (tx, _) = broadcast::channel(100);
+    let mut state = AppState::new(commits.clone(), tx);
+    state.objects = ObjectStore::in_dir(server_dir.path());
+    let app = hit_with_gpt::server::app(state);
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    sleep(Duration::from_millis(1100)).await;
+    retry_outbox(&session, &Outbox::default()).await;
+    let left = Outbox::default().pending().unwrap();
+    let retry = Outbox::default().retry_state().unwrap();
+    env::set_current_dir(original_dir).unwrap();
+    server_handle.abort();
+    unsafe { env::remove_var("HIT_SERVER_URL") };
+
+    assert!(left.is_empty());
+    assert_eq!(retry.failures, 0);
+    let all = commits.all().unwrap();
+    assert_eq!(all.len(), 1);
+    assert_eq!(all[0].changes[0].hash, pending[0].hash);
+    assert!(server_dir.path().join("objects").join(pending[0].hash.to_hex()).exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[serial]
+async fn test_watcher_stores_and_sends_changes() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let original_dir = env::current_dir().unwrap();
+    env::set_current_dir(temp_dir.path()).unwrap();
+    fs::create_dir_all(".hit/objects").unwrap();
+
+    let server_dir = tempfile::tempdir().unwrap();
+    let commits = CommitStore::default();
+    let (tx, _) = broadcast::channel(100);
+    let mut state = AppState::new(commits.clone(), tx);
+    state.objects = ObjectStore::in_dir(server_dir.path());
+    let app = hit_with_gpt::server::app(state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    unsafe { env::set_var("HIT_SERVER_URL", format!("http://{}", listener.local_addr().unwrap())) };
+    let server_handle = tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let watcher = tokio::spawn(async {
+        hit_with_gpt::watcher::watch_and_store_changes().await.map_err(|e| e.to_string())
+    });
+    sleep(Duration::from_millis(300)).await;
+    // Several writes in quick succession are stored once
+    for i in 0..5 {
+        fs::write("watched.txt", format!("version {}", i)).unwrap();
+    }
+    let mut received = Vec::new();
+    for _ in 0..100 {
+        received = commits.all().unwrap();
+        if !received.is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    sleep(Duration::from_millis(500)).await;
+    let all = commits.all().unwrap();
+
+    watcher.abort();
+    server_handle.abort();
+    env::set_current_dir(original_dir).unwrap();
+    unsafe { env::remove_var("HIT_SERVER_URL") };
+
+    assert!(!received.is_empty(), "no change reached the server");
+    assert_eq!(all.len(), 1);
+    let expected = Object::Blob(Blob { content: b"version 4".to_vec() }).hash();
+    assert_eq!(all[0].changes[0].hash, expected);
 }